@@ -0,0 +1,315 @@
+//! Hash-chained audit trail of configuration and firmware changes
+//!
+//! [`audit_record`] appends one line per event to [`AUDIT_FILE`]:
+//!
+//! ```text
+//! 2025-03-01T12:00:00,ConfigChanged,interval_ms,1000,500,3f2a9c01
+//! ```
+//!
+//! The last field is the CRC-32, in hex, of the previous line's last field
+//! followed by everything before the final comma of this line; the first
+//! line chains from `00000000`. Editing or deleting a line breaks the chain
+//! at the next line, which [`verify_audit_chain`] (or a host script
+//! following the same rule) reports. CRC-32 detects accidental and casual
+//! edits, not a forger who recomputes the chain.
+
+use embedded_sdmmc::{BlockDevice, Directory, Error, File, Mode, TimeSource};
+
+use crate::checksum::{write_hex, Crc32};
+use crate::csv::escape_csv_field;
+use crate::time::{format_iso8601, ISO8601_LEN};
+
+/// Name of the audit trail file
+pub const AUDIT_FILE: &str = "AUDIT.LOG";
+
+/// Longest line [`audit_record`] writes, including the hash and newline
+pub const AUDIT_LINE_MAX: usize = 160;
+
+/// Hash the first line chains from
+const GENESIS_HASH: &[u8; 8] = b"00000000";
+
+/// Event recorded in the audit trail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEntry<'a> {
+    /// The firmware started
+    FirmwareBoot {
+        /// Firmware version, e.g. a git describe string
+        version: &'a str,
+    },
+    /// A logging parameter changed
+    ConfigChanged {
+        /// Name of the parameter
+        key: &'a str,
+        /// Value before the change
+        old: &'a str,
+        /// Value after the change
+        new: &'a str,
+    },
+    /// A different card was inserted
+    CardSwapped,
+    /// A file was exported by hand, e.g. with [`crate::export_over_serial`]
+    ManualExport {
+        /// Name of the exported file
+        file: &'a str,
+    },
+}
+
+impl AuditEntry<'_> {
+    /// Entry type as written in the second column
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AuditEntry::FirmwareBoot { .. } => "FirmwareBoot",
+            AuditEntry::ConfigChanged { .. } => "ConfigChanged",
+            AuditEntry::CardSwapped => "CardSwapped",
+            AuditEntry::ManualExport { .. } => "ManualExport",
+        }
+    }
+}
+
+/// Errors returned by [`audit_record`]
+#[derive(Debug, Clone)]
+pub enum AuditError<E: core::fmt::Debug> {
+    /// The filesystem failed
+    Fs(Error<E>),
+    /// The entry doesn't fit in [`AUDIT_LINE_MAX`] bytes
+    EntryTooLong,
+    /// A field contains a line break, which would split the entry
+    InvalidField,
+    /// The last line of the file doesn't end in a hash, so there is nothing
+    /// to chain from
+    Corrupt,
+}
+
+impl<E: core::fmt::Debug> From<Error<E>> for AuditError<E> {
+    fn from(e: Error<E>) -> Self {
+        AuditError::Fs(e)
+    }
+}
+
+/// Result of [`verify_audit_chain`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyResult {
+    /// Every line chains from the one before
+    Intact {
+        /// Number of lines checked
+        entries: u32,
+    },
+    /// The hash of `line` (from 1) doesn't match its content and the line
+    /// before, so that line or the one before it was edited or removed. A
+    /// line cut short by power loss also shows up here.
+    Broken {
+        /// First line that fails the check
+        line: u32,
+    },
+    /// Every complete line chains, but the file ends partway through a line
+    Truncated {
+        /// Number of complete lines checked
+        entries: u32,
+    },
+}
+
+/// Append `entry` to [`AUDIT_FILE`] in `dir`, timestamped from `time_source`
+/// and chained to the last line. The file is closed, and so written to the
+/// card, before this returns.
+///
+/// If the previous write was cut short by power loss, the partial line is
+/// ended and the new entry chains from the last complete line; the partial
+/// line then shows as [`VerifyResult::Broken`].
+pub fn audit_record<
+    D,
+    T,
+    S,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    entry: AuditEntry<'_>,
+    time_source: &S,
+) -> Result<(), AuditError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    S: TimeSource,
+{
+    let mut line = [0u8; AUDIT_LINE_MAX];
+    let mut len = format_iso8601(&mut line, &time_source.get_timestamp());
+    debug_assert_eq!(len, ISO8601_LEN);
+    len += push_field(&mut line[len..], entry.kind().as_bytes())?;
+    match entry {
+        AuditEntry::FirmwareBoot { version } => {
+            len += push_field(&mut line[len..], version.as_bytes())?;
+        }
+        AuditEntry::ConfigChanged { key, old, new } => {
+            for field in [key, old, new] {
+                len += push_field(&mut line[len..], field.as_bytes())?;
+            }
+        }
+        AuditEntry::CardSwapped => {}
+        AuditEntry::ManualExport { file } => {
+            len += push_field(&mut line[len..], file.as_bytes())?;
+        }
+    }
+    // ",xxxxxxxx\n"
+    if len + 10 > line.len() {
+        return Err(AuditError::EntryTooLong);
+    }
+
+    let file = dir.open_file_in_dir(AUDIT_FILE, Mode::ReadWriteCreateOrAppend)?;
+    let (previous, torn) = last_hash(&file)?;
+    let mut crc = Crc32::new();
+    crc.update(&previous);
+    crc.update(&line[..len]);
+    line[len] = b',';
+    write_hex(&mut line[len + 1..len + 9], crc.finish());
+    line[len + 9] = b'\n';
+    len += 10;
+
+    file.seek_from_end(0)?;
+    if torn {
+        file.write(b"\n")?;
+    }
+    file.write(&line[..len])?;
+    file.close()?;
+    Ok(())
+}
+
+/// Check every line of [`AUDIT_FILE`] in `dir` against the chain, reading it
+/// through `scratch`. Lines may be of any length.
+pub fn verify_audit_chain<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    scratch: &mut [u8],
+) -> Result<VerifyResult, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let file = dir.open_file_in_dir(AUDIT_FILE, Mode::ReadOnly)?;
+    let mut previous = *GENESIS_HASH;
+    let mut entries = 0u32;
+    // Checksum of the line up to its last comma so far
+    let mut crc = Crc32::new();
+    crc.update(&previous);
+    // Bytes since that comma, held back while they could still be the hash
+    let mut tail = [0u8; 8];
+    let mut pending: Option<usize> = None;
+    let mut partial = false;
+    loop {
+        let n = file.read(scratch)?;
+        if n == 0 {
+            break;
+        }
+        for &byte in &scratch[..n] {
+            partial = byte != b'\n';
+            match (byte, pending) {
+                (b'\n', held) => {
+                    let mut expected = [0u8; 8];
+                    write_hex(&mut expected, crc.finish());
+                    if held != Some(tail.len()) || expected != tail {
+                        file.close()?;
+                        return Ok(VerifyResult::Broken { line: entries + 1 });
+                    }
+                    entries += 1;
+                    previous = tail;
+                    crc = Crc32::new();
+                    crc.update(&previous);
+                    pending = None;
+                }
+                (b',', held) => {
+                    if let Some(len) = held {
+                        crc.update(b",");
+                        crc.update(&tail[..len]);
+                    }
+                    pending = Some(0);
+                }
+                (_, Some(len)) if len < tail.len() => {
+                    tail[len] = byte;
+                    pending = Some(len + 1);
+                }
+                (_, Some(len)) => {
+                    // Longer than a hash, so not the last field
+                    crc.update(b",");
+                    crc.update(&tail[..len]);
+                    crc.update(&[byte]);
+                    pending = None;
+                }
+                (_, None) => crc.update(&[byte]),
+            }
+        }
+    }
+    file.close()?;
+    Ok(if partial {
+        VerifyResult::Truncated { entries }
+    } else {
+        VerifyResult::Intact { entries }
+    })
+}
+
+/// The hash ending the last complete line of `file`, or [`GENESIS_HASH`] if
+/// there is none, and whether a partial line follows it
+fn last_hash<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    file: &File<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+) -> Result<([u8; 8], bool), AuditError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    // Lines are at most AUDIT_LINE_MAX, so this holds a partial line and
+    // the complete one before it
+    let mut window = [0u8; 2 * AUDIT_LINE_MAX];
+    let len = file.length();
+    if len == 0 {
+        return Ok((*GENESIS_HASH, false));
+    }
+    let start = len.saturating_sub(window.len() as u32);
+    file.seek_from_start(start)?;
+    let mut filled = 0;
+    while filled < window.len() {
+        let n = file.read(&mut window[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    let window = &window[..filled];
+
+    let torn = window.last() != Some(&b'\n');
+    let complete = match window.iter().rposition(|&b| b == b'\n') {
+        Some(end) => &window[..end],
+        None if start == 0 => return Ok((*GENESIS_HASH, torn)),
+        None => return Err(AuditError::Corrupt),
+    };
+    let Some(hash_start) = complete.len().checked_sub(8) else {
+        return Err(AuditError::Corrupt);
+    };
+    let hash = &complete[hash_start..];
+    if hash_start == 0
+        || complete[hash_start - 1] != b','
+        || !hash.iter().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    {
+        return Err(AuditError::Corrupt);
+    }
+    let mut previous = [0u8; 8];
+    previous.copy_from_slice(hash);
+    Ok((previous, torn))
+}
+
+/// Write `field` into `buffer` preceded by a comma, returning bytes written
+fn push_field<E: core::fmt::Debug>(
+    buffer: &mut [u8],
+    field: &[u8],
+) -> Result<usize, AuditError<E>> {
+    if field.iter().any(|&b| matches!(b, b'\r' | b'\n')) {
+        return Err(AuditError::InvalidField);
+    }
+    let (comma, rest) = buffer.split_first_mut().ok_or(AuditError::EntryTooLong)?;
+    *comma = b',';
+    let len = escape_csv_field(rest, field, false).ok_or(AuditError::EntryTooLong)?;
+    Ok(len + 1)
+}
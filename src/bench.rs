@@ -0,0 +1,67 @@
+//! Write throughput benchmarking
+
+use embassy_time::Instant;
+use embedded_io::Write;
+
+use crate::YieldBudget;
+
+/// Write `total_bytes` of `chunk` repeatedly to `out`, flush, and return the
+/// measured throughput in bytes per second.
+///
+/// This writes real data, so point it at a scratch file and delete it afterwards.
+/// If `budget` is cancelled the benchmark stops early and returns the
+/// throughput of what was written so far.
+pub async fn bench_write<W: Write>(
+    out: &mut W,
+    chunk: &[u8],
+    total_bytes: u32,
+    budget: &mut YieldBudget,
+) -> Result<u64, W::Error> {
+    let start = Instant::now();
+    let mut written = 0u32;
+    while written < total_bytes && !chunk.is_empty() {
+        let len = chunk.len().min((total_bytes - written) as usize);
+        out.write_all(&chunk[..len])?;
+        written += len as u32;
+        budget.spend_bytes(len as u32).await;
+        if budget.is_cancelled() {
+            break;
+        }
+    }
+    out.flush()?;
+
+    let elapsed_us = start.elapsed().as_micros().max(1);
+    Ok(u64::from(written) * 1_000_000 / elapsed_us)
+}
+
+/// Benchmark each candidate buffer size and return the one with the best throughput.
+///
+/// Each candidate writes `bytes_per_candidate` bytes from `scratch` to `out`,
+/// so this temporarily fills a scratch file with
+/// `candidates.len() * bytes_per_candidate` bytes; delete it afterwards.
+/// Candidates larger than `scratch` are skipped. Good candidates are
+/// multiples of the 512-byte block size; run this once at provisioning and
+/// store the result rather than on every boot. Returns 0 if no candidate fits.
+/// If `budget` is cancelled the remaining candidates are skipped.
+pub async fn auto_tune_buffer<W: Write>(
+    out: &mut W,
+    scratch: &[u8],
+    candidates: &[usize],
+    bytes_per_candidate: u32,
+    budget: &mut YieldBudget,
+) -> Result<usize, W::Error> {
+    let mut best = (0, 0);
+    for &size in candidates {
+        if budget.is_cancelled() {
+            break;
+        }
+        if size == 0 || size > scratch.len() {
+            continue;
+        }
+        let rate = bench_write(out, &scratch[..size], bytes_per_candidate, budget).await?;
+        if rate > best.1 {
+            best = (size, rate);
+        }
+    }
+    Ok(best.0)
+}
@@ -7,8 +7,9 @@
 )]
 
 use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
 use embedded_hal_bus::spi::RefCellDevice;
-use embedded_sdmmc::{Mode as FileMode, SdCard, VolumeIdx, VolumeManager};
+use embedded_sdmmc::{SdCard, VolumeIdx, VolumeManager};
 use esp_hal::gpio::{Level, Output, OutputConfig};
 use esp_hal::time::Rate;
 use esp_hal::timer::timg::TimerGroup;
@@ -16,19 +17,56 @@ use esp_hal::{
     clock::CpuClock,
     delay::Delay as EspHalDelay,
     rng::Rng,
+    rtc_cntl::Rtc,
     spi::master::{Config as SpiMasterConfig, Spi as SpiMaster},
     spi::Mode as SpiMode,
 };
 use esp_println::println;
 
 use embassy_executor::Spawner;
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 
 // Import our utility functions from the library
+use esp32_sdcard::esp::{generate_filename_for, generate_random_filename};
 use esp32_sdcard::{
-    format_csv_line, generate_random_filename, retry_with_backoff, DummyTimeSource,
+    bench_write, create_new_file, format_csv_row, is_volume_full, reset_card_spi,
+    retry_with_backoff, retry_with_hint, self_test, suffix_filename, try_retry_with_backoff,
+    volume_label, wait_for_card, AdaptiveFlushPolicy, Field, HumanBytes, HumanRate, LineEnding,
+    LoggerConfig, LoggerState, LoggerStateCell, PhaseTimer, RtcTimeSource, TimeSourceExt,
+    YieldBudget, VOLUME_LABEL_LEN,
 };
 
+/// Settings file on the card that can be edited without reflashing
+const CONFIG_FILENAME: &str = "LOGGER.CFG";
+
+/// Names to try before giving up when generated filenames are already taken
+const MAX_NAME_ATTEMPTS: u32 = 8;
+
+/// What the logger is doing, for a display task to render
+static LOGGER_STATE: LoggerStateCell = LoggerStateCell::new();
+
+/// How long to wait at boot for a card to be inserted
+const CARD_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Scratch file for measuring what yielding costs
+const YIELD_TEST_FILENAME: &str = "YIELDTST.TMP";
+
+/// Worst lateness of [`ticker`] in microseconds since last reset
+static TICK_LATENESS_US: AtomicU32 = AtomicU32::new(0);
+
+/// Stands in for a periodic task (a sensor poll, a display refresh) and
+/// records how late it runs while the card is busy
+#[embassy_executor::task]
+async fn ticker() {
+    let period = Duration::from_millis(10);
+    loop {
+        let due = Instant::now() + period;
+        Timer::at(due).await;
+        let late = Instant::now().saturating_duration_since(due);
+        TICK_LATENESS_US.fetch_max(late.as_micros() as u32, Ordering::Relaxed);
+    }
+}
+
 #[panic_handler]
 fn panic(_: &core::panic::PanicInfo) -> ! {
     loop {}
@@ -39,31 +77,40 @@ fn panic(_: &core::panic::PanicInfo) -> ! {
 esp_bootloader_esp_idf::esp_app_desc!();
 
 #[esp_hal_embassy::main]
-async fn main(_spawner: Spawner) -> ! {
+async fn main(spawner: Spawner) -> ! {
     esp_println::logger::init_logger_from_env();
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
 
     let timer0 = TimerGroup::new(peripherals.TIMG1);
     esp_hal_embassy::init(timer0.timer0);
+    spawner.must_spawn(ticker());
+    let mut boot_timer = PhaseTimer::<8>::new();
 
     println!("\n====================================");
     println!("ESP32 Micro SD Card Counter Example");
     println!("====================================\n");
 
-    // Generate random filename for CSV file
+    // File timestamps come from the RTC, which reads 1980-01-01 until it is
+    // set (e.g. clock.set_epoch() from NTP or GPS) and keeps time through
+    // deep sleep once it is
+    let rtc = Rtc::new(peripherals.LPWR);
+    let clock = RtcTimeSource::from_rtc(&rtc);
+
+    // Generate filename for CSV file (random until the RTC has been set)
     let mut rng = Rng::new(peripherals.RNG);
     let mut filename = [0u8; 12];
-    generate_random_filename(&mut rng, &mut filename);
-    let filename_str = core::str::from_utf8(&filename).unwrap();
-    println!("Generated filename: {}", filename_str);
+    println!(
+        "Generated filename: {}",
+        generate_filename_for(&clock, &mut rng, &mut filename)
+    );
 
     // === SPI Bus Setup ===
     println!("Setting up SPI bus for SD card...");
     let spi2 = peripherals.SPI2;
 
     // With this setup, you could add a second SPI device on this same bus with a second CS pin
-    let cs = Output::new(peripherals.GPIO18, Level::High, OutputConfig::default()); // CS pin
+    let mut cs = Output::new(peripherals.GPIO18, Level::High, OutputConfig::default()); // CS pin
     let sclk = peripherals.GPIO19; // Serial Clock
     let mosi = peripherals.GPIO23; // Master Out Slave In
     let miso = peripherals.GPIO21; // Master In Slave Out
@@ -73,12 +120,17 @@ async fn main(_spawner: Spawner) -> ! {
         .with_frequency(Rate::from_khz(400)) // 400kHz for initialization
         .with_mode(SpiMode::_0);
 
-    let spi_bus = SpiMaster::new(spi2, spi_bus_config)
+    let mut spi_bus = SpiMaster::new(spi2, spi_bus_config)
         .expect("Failed to initialize SPI bus")
         .with_miso(miso)
         .with_mosi(mosi)
         .with_sck(sclk);
 
+    // Clear any half-finished command left by a reset mid-transfer
+    if let Err(e) = reset_card_spi(&mut spi_bus, &mut cs) {
+        println!("    SPI reset failed: {:?}", e);
+    }
+
     let shared_spi_bus = RefCell::new(spi_bus);
     let spi_device = RefCellDevice::new(&shared_spi_bus, cs, EspHalDelay::new())
         .expect("Failed to create SPI device");
@@ -89,34 +141,50 @@ async fn main(_spawner: Spawner) -> ! {
     // Initialize SD card with retry logic
     let sdcard = SdCard::new(spi_device, EspHalDelay::new());
     println!("Initializing SD Card...");
-    let sd_size =
-        retry_with_backoff("SD Card initialization", || async { sdcard.num_bytes() }).await;
+    // Give the operator time to insert a card before falling back to retries
+    let sd_size = match wait_for_card(
+        Some(CARD_WAIT_TIMEOUT),
+        Duration::from_millis(500),
+        || async { sdcard.num_bytes() },
+    )
+    .await
+    {
+        Ok(num_bytes) => Some(num_bytes),
+        Err(_) => retry_with_hint("SD Card initialization", || async { sdcard.num_bytes() }).await,
+    };
     if let Some(num_bytes) = sd_size {
-        println!(
-            "    SD Card ready - size: {} GB",
-            num_bytes / 1024 / 1024 / 1024
-        );
+        println!("    SD Card ready - size: {}", HumanBytes(num_bytes));
     } else {
         println!("    SD Card initialization failed");
     }
+    boot_timer.mark("card init");
 
     // Open volume 0 (main partition)
-    let volume_mgr = VolumeManager::new(sdcard, DummyTimeSource);
+    let volume_mgr = VolumeManager::new(sdcard, &clock);
     let volume0 = if sd_size.is_some() {
-        retry_with_backoff("Opening volume 0", || async {
-            volume_mgr.open_volume(VolumeIdx(0))
+        retry_with_hint("Opening volume 0", || async {
+            volume_mgr.open_raw_volume(VolumeIdx(0))
         })
         .await
     } else {
         None
     };
-    if volume0.is_some() {
+    if let Some(raw_volume) = volume0 {
         println!("    Volume 0 opened");
+        // Shows which card is inserted without taking it to a PC
+        let mut label = [0u8; VOLUME_LABEL_LEN];
+        match volume_label(&volume_mgr, raw_volume, &mut label) {
+            Ok(Some(_)) => {}
+            Ok(None) => println!("    Volume has no label"),
+            Err(e) => println!("    Could not read volume label: {:?}", e),
+        }
     }
+    let volume0 = volume0.map(|raw_volume| raw_volume.to_volume(&volume_mgr));
+    boot_timer.mark("volume open");
 
     // Open root directory
     let root_dir = if let Some(ref volume) = volume0 {
-        retry_with_backoff("Opening root directory", || async {
+        retry_with_hint("Opening root directory", || async {
             volume.open_root_dir()
         })
         .await
@@ -126,6 +194,7 @@ async fn main(_spawner: Spawner) -> ! {
     if root_dir.is_some() {
         println!("    Root directory opened");
     }
+    boot_timer.mark("root dir open");
 
     // After initializing the SD card, increase the SPI frequency
     shared_spi_bus
@@ -137,20 +206,67 @@ async fn main(_spawner: Spawner) -> ! {
         )
         .expect("Failed to apply the second SPI configuration");
 
-    // Create CSV file
-    let filename_str = core::str::from_utf8(&filename).unwrap();
+    // Load logging settings from the card. Writing the commented template on
+    // first boot is deferred until after the first row so it doesn't delay it.
+    let mut store_default_config = false;
+    let logger_config = match root_dir {
+        Some(ref root_dir) => match LoggerConfig::load(root_dir, CONFIG_FILENAME) {
+            Ok(config) => {
+                println!("    Loaded {}", CONFIG_FILENAME);
+                config
+            }
+            Err(embedded_sdmmc::Error::NotFound) => {
+                store_default_config = true;
+                LoggerConfig::default()
+            }
+            Err(e) => {
+                println!(
+                    "    Could not read {}: {:?} - using defaults",
+                    CONFIG_FILENAME, e
+                );
+                LoggerConfig::default()
+            }
+        },
+        None => LoggerConfig::default(),
+    };
+    boot_timer.mark("config load");
+
+    // Create CSV file, picking a fresh name if the generated one is taken
     let mut file = if let Some(ref root_dir) = root_dir {
-        retry_with_backoff("Creating CSV file", || async {
-            root_dir.open_file_in_dir(filename_str, FileMode::ReadWriteCreateOrAppend)
+        match try_retry_with_backoff("Creating CSV file", || {
+            core::future::ready(create_new_file(
+                root_dir,
+                &mut filename,
+                MAX_NAME_ATTEMPTS,
+                |name| {
+                    // A dated name stays dated: 07141530.CSV, then 0714153A.CSV...
+                    if !(clock.is_real() && suffix_filename(name)) {
+                        generate_random_filename(&mut rng, name);
+                    }
+                },
+            ))
         })
         .await
+        {
+            Ok(file) => Some(file),
+            Err(e) => {
+                // Retrying won't help a full card; say so rather than just "failed"
+                if is_volume_full(&e) {
+                    println!("    Card is full - free some space to log");
+                }
+                None
+            }
+        }
     } else {
         None
     };
 
+    // Every name tried was ASCII
+    let filename_str = core::str::from_utf8(&filename).unwrap_or_default();
     if file.is_some() {
         println!("    CSV file '{}' created", filename_str);
     }
+    boot_timer.mark("file create");
 
     // Write CSV header
     if let Some(ref mut f) = file {
@@ -166,9 +282,16 @@ async fn main(_spawner: Spawner) -> ! {
             file = None;
         }
     }
+    boot_timer.mark("header write");
+    LOGGER_STATE.set(if file.is_some() {
+        LoggerState::logging(filename_str)
+    } else {
+        LoggerState::Stopped
+    });
 
     // Main counting loop
     let mut counter = 0u32;
+    let mut flush_policy = AdaptiveFlushPolicy::new(logger_config.flush_rows);
     println!("Starting counter loop...\n");
 
     loop {
@@ -180,22 +303,78 @@ async fn main(_spawner: Spawner) -> ! {
         // Write to SD card file if available
         if let Some(ref mut file) = file {
             let mut buffer = [0u8; 64];
-            let line_length = format_csv_line(&mut buffer, timestamp, counter);
+            let fields = [
+                Field::UInt(timestamp),
+                Field::Str("count"),
+                Field::from(counter),
+            ];
+            let line_length = format_csv_row(&mut buffer, &fields, LineEnding::Lf).unwrap_or(0);
 
             match file.write(&buffer[..line_length]) {
                 Ok(_) => {
+                    LOGGER_STATE.set_rows(counter);
                     // Don't forget to flush the file occasionally so that the directory entry is updated
-                    if counter % 10 == 0 {
+                    // Flushes are spaced out while the card is slow to take them
+                    if flush_policy.row_written() {
+                        let start = embassy_time::Instant::now();
                         let _ = file.flush();
+                        flush_policy.record_flush(start.elapsed());
                         println!("    Flushed data to SD card (count: {})", counter);
                     }
                 }
                 Err(e) => {
+                    if is_volume_full(&e) {
+                        LOGGER_STATE.set(LoggerState::CardFull);
+                    }
                     println!("    SD write error: {:?}", e);
                 }
             }
         }
 
+        // Show where boot time went, then do the init work that was deferred
+        if counter == 1 {
+            boot_timer.mark("first row");
+            println!("Boot time breakdown:");
+            boot_timer.print();
+
+            if let Some(ref root_dir) = root_dir {
+                if store_default_config && logger_config.store(root_dir, CONFIG_FILENAME).is_ok() {
+                    println!("    Wrote default {}", CONFIG_FILENAME);
+                }
+                match self_test(root_dir) {
+                    Ok(()) => println!("    Card self-test passed"),
+                    Err(e) => println!("    Card self-test failed: {:?}", e),
+                }
+
+                // What yield points buy the 10 ms ticker, and what they cost
+                // in throughput, over 64 KiB of writes
+                for (label, mut budget) in [
+                    ("default yielding", YieldBudget::default()),
+                    ("never yielding", YieldBudget::new(0)),
+                ] {
+                    let Ok(mut scratch) = root_dir.open_file_in_dir(
+                        YIELD_TEST_FILENAME,
+                        embedded_sdmmc::Mode::ReadWriteCreateOrTruncate,
+                    ) else {
+                        break;
+                    };
+                    TICK_LATENESS_US.store(0, Ordering::Relaxed);
+                    let rate =
+                        bench_write(&mut scratch, &[0x55; 512], 64 * 1024, &mut budget).await;
+                    let _ = scratch.close();
+                    let _ = root_dir.delete_file_in_dir(YIELD_TEST_FILENAME);
+                    if let Ok(rate) = rate {
+                        println!(
+                            "    {}: {}, 10 ms task up to {} us late",
+                            label,
+                            HumanRate(rate),
+                            TICK_LATENESS_US.load(Ordering::Relaxed)
+                        );
+                    }
+                }
+            }
+        }
+
         // Wait 1 second before next count
         Timer::after(Duration::from_secs(1)).await;
     }
@@ -0,0 +1,125 @@
+//! Boot counter persisted on the card
+//!
+//! The count is kept in two copies, `<base>.BCA` and `<base>.BCB`, each
+//! holding the count and its CRC-32 as little-endian `u32`s. An increment
+//! rewrites only the copy that doesn't hold the current count, so a write
+//! torn by power loss leaves the other copy intact and the count never goes
+//! backwards. Tag logs with it (see [`crate::CsvWriter::with_boot_count`]) to
+//! tell power cycles apart without a real-time clock.
+
+use embedded_sdmmc::{BlockDevice, Directory, Error, FilenameError, Mode, TimeSource};
+
+use crate::checksum::Crc32;
+use crate::fs::replace_extension;
+use crate::read_file_chunks;
+
+/// Default base name for the boot counter files
+pub const BOOT_COUNT_FILE: &str = "BOOTCNT";
+
+/// Extensions of the two copies
+const COPY_EXTENSIONS: [&str; 2] = ["BCA", "BCB"];
+
+/// Read the boot count kept under `base`: the higher of the two copies that
+/// pass their CRC, or 0 if neither does (e.g. on a fresh card).
+pub fn read_boot_count<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    base: &str,
+) -> Result<u32, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let [a, b] = read_copies(dir, base)?;
+    Ok(a.max(b).unwrap_or(0))
+}
+
+/// Add one to the boot count kept under `base` and return the new count.
+/// Call once per boot, after mounting the card.
+pub fn increment_boot_count<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    base: &str,
+) -> Result<u32, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let [a, b] = read_copies(dir, base)?;
+    let current = a.max(b).unwrap_or(0);
+    let count = current.saturating_add(1);
+    // Overwrite the copy that is stale or damaged, never the current one
+    let target = if a.is_some_and(|a| a == current) && b != a {
+        1
+    } else {
+        0
+    };
+
+    let mut record = [0u8; 8];
+    record[..4].copy_from_slice(&count.to_le_bytes());
+    record[4..].copy_from_slice(&crc(count).to_le_bytes());
+    let mut name_buf = [0u8; 12];
+    let name = copy_name(base, target, &mut name_buf)?;
+    let file = dir.open_file_in_dir(name, Mode::ReadWriteCreateOrTruncate)?;
+    file.write(&record)?;
+    file.close()?;
+    Ok(count)
+}
+
+/// The count in each copy, None if missing or damaged
+fn read_copies<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    base: &str,
+) -> Result<[Option<u32>; 2], Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut counts = [None; 2];
+    for (index, count) in counts.iter_mut().enumerate() {
+        let mut name_buf = [0u8; 12];
+        let name = copy_name(base, index, &mut name_buf)?;
+        let mut record = [0u8; 8];
+        let mut len = 0;
+        let mut buffer = [0u8; 16];
+        match read_file_chunks(dir, name, &mut buffer, |chunk| {
+            let take = chunk.len().min(record.len() - len);
+            record[len..len + take].copy_from_slice(&chunk[..take]);
+            len += take;
+        }) {
+            Ok(8) => {}
+            Ok(_) | Err(Error::NotFound) => continue,
+            Err(e) => return Err(e),
+        }
+        let value = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+        let stored = u32::from_le_bytes([record[4], record[5], record[6], record[7]]);
+        if crc(value) == stored {
+            *count = Some(value);
+        }
+    }
+    Ok(counts)
+}
+
+fn crc(count: u32) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(&count.to_le_bytes());
+    crc.finish()
+}
+
+fn copy_name<'a, E>(base: &str, index: usize, buffer: &'a mut [u8; 12]) -> Result<&'a str, Error<E>>
+where
+    E: core::fmt::Debug,
+{
+    replace_extension(base, COPY_EXTENSIONS[index], buffer)
+        .ok_or(Error::FilenameError(FilenameError::NameTooLong))
+}
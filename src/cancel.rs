@@ -0,0 +1,73 @@
+//! Stopping long-running operations early, e.g. before powering down
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embedded_sdmmc::Error;
+
+/// Cheap handle to a shared cancellation flag
+///
+/// The flag lives in a `static` so the handle can be copied into any task:
+///
+/// ```text
+/// static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+/// let mut budget = YieldBudget::default().with_cancel(CancelToken::new(&SHUTDOWN));
+/// ```
+///
+/// Operations that take a [`crate::YieldBudget`] check it at every yield
+/// point and return early, reporting how far they got: file operations
+/// with [`CancellableError::Cancelled`], the others through their own
+/// error or report types.
+#[derive(Debug, Clone, Copy)]
+pub struct CancelToken {
+    flag: &'static AtomicBool,
+}
+
+impl CancelToken {
+    /// A handle to `flag`
+    pub const fn new(flag: &'static AtomicBool) -> Self {
+        Self { flag }
+    }
+
+    /// Ask every operation holding this token to stop
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::Release);
+    }
+
+    /// True once [`CancelToken::cancel`] has been called
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Acquire)
+    }
+
+    /// Clear the flag so the token can be used again
+    pub fn reset(&self) {
+        self.flag.store(false, Ordering::Release);
+    }
+}
+
+impl PartialEq for CancelToken {
+    fn eq(&self, other: &Self) -> bool {
+        core::ptr::eq(self.flag, other.flag)
+    }
+}
+
+impl Eq for CancelToken {}
+
+/// Error from a file operation that stops when its [`crate::YieldBudget`]'s
+/// [`CancelToken`] is cancelled
+#[derive(Debug, Clone)]
+pub enum CancellableError<E: core::fmt::Debug> {
+    /// The filesystem operation failed
+    Fs(Error<E>),
+    /// Cancelled after `bytes` bytes were processed; what that leaves
+    /// behind is documented with each operation
+    Cancelled {
+        /// Bytes read, written or copied before stopping
+        bytes: u32,
+    },
+}
+
+impl<E: core::fmt::Debug> From<Error<E>> for CancellableError<E> {
+    fn from(e: Error<E>) -> Self {
+        CancellableError::Fs(e)
+    }
+}
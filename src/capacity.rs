@@ -0,0 +1,108 @@
+//! Detecting counterfeit cards that claim more capacity than they have
+//!
+//! Fake cards report a large size but only store a fraction of it: writes
+//! past the real capacity either vanish or wrap around and overwrite the
+//! start of the card. Both show up when a marker written at one address
+//! reads back differently.
+
+use embedded_sdmmc::{Block, BlockDevice, BlockIdx};
+
+/// Block used to catch writes that wrap around; block 0 holds the MBR
+const ANCHOR: u32 = 1;
+
+/// Smallest power of two probed for wraparound, in blocks (1 MiB)
+const FIRST_WRAP_PROBE: u32 = 1 << 11;
+
+/// Marker prefix written by [`verify_capacity`]
+const MARKER_TAG: &[u8; 8] = b"SDCAPCHK";
+
+/// Errors returned by [`verify_capacity`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapacityError<E> {
+    /// A block could not be read or written
+    Device(E),
+    /// Data written at `block` didn't survive, or landed on another block,
+    /// so the card holds less than it reports
+    CounterfeitSuspected {
+        /// Block whose marker failed to read back
+        block: u32,
+        /// Blocks the card reports
+        reported: u32,
+    },
+}
+
+impl<E> From<E> for CapacityError<E> {
+    fn from(e: E) -> Self {
+        CapacityError::Device(e)
+    }
+}
+
+/// Check that `device` really stores data across the capacity it reports.
+///
+/// Markers are written in the middle and at the end of the claimed
+/// capacity, and just past every power of two from 1 MiB up, each paired
+/// with a marker at block 1 so a card that wraps at its real size
+/// overwrites the pair. Every probed block is restored afterwards, but the
+/// card is written to, so power loss during the check can corrupt it: run
+/// it at provisioning time, not on a card holding data that matters.
+pub fn verify_capacity<D: BlockDevice>(device: &D) -> Result<(), CapacityError<D::Error>> {
+    let reported = device.num_blocks()?.0;
+    if reported <= ANCHOR {
+        return Ok(());
+    }
+    let wrap_probes = (FIRST_WRAP_PROBE.trailing_zeros()..32)
+        .map(|shift| (1u32 << shift) + ANCHOR)
+        .take_while(|&block| block < reported);
+    for block in [reported / 2, reported - 1].into_iter().chain(wrap_probes) {
+        if block > ANCHOR {
+            probe(device, block, reported)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write markers to the anchor and `block`, read both back and restore them
+fn probe<D: BlockDevice>(
+    device: &D,
+    block: u32,
+    reported: u32,
+) -> Result<(), CapacityError<D::Error>> {
+    let mut saved = [Block::new(), Block::new()];
+    device.read(&mut saved[..1], BlockIdx(ANCHOR))?;
+    device.read(&mut saved[1..], BlockIdx(block))?;
+
+    let anchor_marker = marker(ANCHOR, block);
+    let block_marker = marker(block, block);
+    device.write(core::slice::from_ref(&anchor_marker), BlockIdx(ANCHOR))?;
+    device.write(core::slice::from_ref(&block_marker), BlockIdx(block))?;
+
+    let mut readback = [Block::new()];
+    device.read(&mut readback, BlockIdx(block))?;
+    let block_ok = readback[0].contents == block_marker.contents;
+    device.read(&mut readback, BlockIdx(ANCHOR))?;
+    let anchor_ok = readback[0].contents == anchor_marker.contents;
+
+    // Anchor last, so if the two alias its original contents win
+    device.write(&saved[1..], BlockIdx(block))?;
+    device.write(&saved[..1], BlockIdx(ANCHOR))?;
+
+    if block_ok && anchor_ok {
+        Ok(())
+    } else {
+        Err(CapacityError::CounterfeitSuspected { block, reported })
+    }
+}
+
+/// Marker for block `at` during the probe of `round`, unique to both
+fn marker(at: u32, round: u32) -> Block {
+    let mut block = Block::new();
+    let contents = &mut block.contents;
+    contents[..8].copy_from_slice(MARKER_TAG);
+    contents[8..12].copy_from_slice(&at.to_le_bytes());
+    contents[12..16].copy_from_slice(&round.to_le_bytes());
+    let seed = at.wrapping_mul(0x9E37_79B9) ^ round;
+    for (i, byte) in contents[16..].iter_mut().enumerate() {
+        *byte = (seed.rotate_left(i as u32 % 32) as u8) ^ i as u8;
+    }
+    block
+}
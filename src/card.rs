@@ -0,0 +1,151 @@
+//! Recovering a card whose SPI interface has wedged, and checking that the
+//! bus runs in a mode the card understands
+
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::{Operation, SpiBus, SpiDevice};
+
+/// Bytes of 0xFF clocked out by [`reset_card_spi`]: 80 clocks, above the 74
+/// the SD spec requires after power-up
+const WAKEUP_BYTES: usize = 10;
+
+/// Errors returned by [`reset_card_spi`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetError<S, P> {
+    /// The SPI bus failed
+    Spi(S),
+    /// The chip-select pin couldn't be driven
+    Cs(P),
+}
+
+/// Return the card's SPI interface to a known state: pulse CS low, then
+/// send 80 clocks with CS high.
+///
+/// Recovers a card left mid-command (e.g. by a reset during a transfer, or
+/// a chip-select stuck low) without a power cycle; call it before
+/// re-initialising. It also provides the wakeup clocks embedded-sdmmc leaves
+/// to the caller before the first `SdCard` access. Run the bus at 400 kHz
+/// or below, and since it needs the raw bus and pin, call it before they
+/// are wrapped in an `SpiDevice`, or on a shared bus while no other device
+/// is selected.
+pub fn reset_card_spi<B, P>(bus: &mut B, cs: &mut P) -> Result<(), ResetError<B::Error, P::Error>>
+where
+    B: SpiBus<u8>,
+    P: OutputPin,
+{
+    cs.set_low().map_err(ResetError::Cs)?;
+    bus.write(&[0xFF]).map_err(ResetError::Spi)?;
+    bus.flush().map_err(ResetError::Spi)?;
+    cs.set_high().map_err(ResetError::Cs)?;
+    bus.write(&[0xFF; WAKEUP_BYTES]).map_err(ResetError::Spi)?;
+    bus.flush().map_err(ResetError::Spi)
+}
+
+/// CMD0, GO_IDLE_STATE, with its fixed CRC
+const CMD0: [u8; 6] = [0x40, 0x00, 0x00, 0x00, 0x00, 0x95];
+
+/// CMD8, SEND_IF_COND, for 2.7-3.6 V with check pattern 0xAA, and its CRC
+const CMD8: [u8; 6] = [0x48, 0x00, 0x00, 0x01, 0xAA, 0x87];
+
+/// Bytes clocked in after a command while waiting for the answer; the
+/// card answers within 8
+const RESPONSE_BYTES: usize = 16;
+
+/// What [`diagnose_spi_mode`] made of the card's answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpiModeDiagnosis {
+    /// CMD0 and CMD8 were answered as expected, so the mode is right
+    Ok {
+        /// The card answered CMD8, so it is an SD v2 card (SDHC/SDXC or a
+        /// v2 SDSC); v1 cards reject it
+        sd_v2: bool,
+    },
+    /// Nothing came back but 0xFF: check power, wiring and chip select
+    /// before the mode
+    NoResponse,
+    /// The answers are off by one bit, the sign of sampling on the wrong
+    /// clock edge: try another SPI mode, usually mode 0
+    ModeMismatch {
+        /// First byte of the answer to CMD0, which should be 0x01
+        r1: u8,
+    },
+    /// The card answered, but not like an SD card in SPI mode, e.g. noise
+    /// from a long cable or a clock too fast for initialisation
+    Unexpected {
+        /// First byte of the answer that didn't match
+        r1: u8,
+    },
+}
+
+/// Send the SD handshake, CMD0 then CMD8, and check the answers match the
+/// bus's SPI mode, printing a suggestion unless [`crate::Verbosity::Quiet`].
+///
+/// Debugs adapters that fail to initialise: a wrong clock polarity or phase
+/// shows up as answers shifted by a bit rather than no answer at all. Run
+/// it at 400 kHz after [`reset_card_spi`], before handing the device to
+/// `SdCard`; CMD0 resets the card, so an `SdCard` that already initialised
+/// it needs `mark_card_uninit` afterwards.
+pub fn diagnose_spi_mode<S: SpiDevice<u8>>(spi: &mut S) -> Result<SpiModeDiagnosis, S::Error> {
+    let diagnosis = match command(spi, &CMD0)? {
+        (None, _) => SpiModeDiagnosis::NoResponse,
+        (Some(0x01), _) => match command(spi, &CMD8)? {
+            // Illegal command: a v1 card, which still answered correctly
+            (Some(0x05), _) => SpiModeDiagnosis::Ok { sd_v2: false },
+            (Some(0x01), [.., 0x01, 0xAA]) => SpiModeDiagnosis::Ok { sd_v2: true },
+            (Some(0x01), [.., echo]) if off_by_one_bit(echo, 0xAA) => {
+                SpiModeDiagnosis::ModeMismatch { r1: 0x01 }
+            }
+            (None, _) => SpiModeDiagnosis::NoResponse,
+            (Some(r1), _) => SpiModeDiagnosis::Unexpected { r1 },
+        },
+        (Some(r1), _) if off_by_one_bit(r1, 0x01) => SpiModeDiagnosis::ModeMismatch { r1 },
+        (Some(r1), _) => SpiModeDiagnosis::Unexpected { r1 },
+    };
+
+    if crate::verbosity() != crate::Verbosity::Quiet {
+        match diagnosis {
+            SpiModeDiagnosis::Ok { sd_v2 } => println!(
+                "SPI mode OK: card answered CMD0 and CMD8 ({})",
+                if sd_v2 { "SD v2" } else { "SD v1" }
+            ),
+            SpiModeDiagnosis::NoResponse => {
+                println!("No answer to CMD0 - check power, wiring and CS before the SPI mode")
+            }
+            SpiModeDiagnosis::ModeMismatch { r1 } => println!(
+                "Card answers are shifted by a bit (R1 {:#04x}) - wrong SPI mode, try mode 0",
+                r1
+            ),
+            SpiModeDiagnosis::Unexpected { r1 } => println!(
+                "Unexpected card answer (R1 {:#04x}) - lower the clock or shorten the wires",
+                r1
+            ),
+        }
+    }
+    Ok(diagnosis)
+}
+
+/// Send `cmd` with CS held low and return the first byte of the answer,
+/// if any, with the 4 bytes that follow it
+fn command<S: SpiDevice<u8>>(
+    spi: &mut S,
+    cmd: &[u8; 6],
+) -> Result<(Option<u8>, [u8; 4]), S::Error> {
+    let mut response = [0xFF; RESPONSE_BYTES];
+    spi.transaction(&mut [
+        Operation::Write(cmd),
+        Operation::TransferInPlace(&mut response),
+    ])?;
+    let Some(start) = response.iter().position(|&b| b != 0xFF) else {
+        return Ok((None, [0xFF; 4]));
+    };
+    let mut rest = [0xFF; 4];
+    for (slot, &byte) in rest.iter_mut().zip(&response[start + 1..]) {
+        *slot = byte;
+    }
+    Ok((Some(response[start]), rest))
+}
+
+/// True if `got` is `want` read one bit early or late, with the missing bit
+/// taken from the idle-high line either side
+fn off_by_one_bit(got: u8, want: u8) -> bool {
+    [want << 1, want << 1 | 1, want >> 1, want >> 1 | 0x80].contains(&got)
+}
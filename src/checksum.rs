@@ -0,0 +1,254 @@
+//! Whole-file CRC-32 checksums stored in sidecar files
+//!
+//! A sidecar for `12345678.CSV` is `12345678.CRC` in the same directory and
+//! holds one line in the `cksum`-style format `xxxxxxxx  12345678.CSV`, so
+//! archived logs can be checked on the host.
+
+use embedded_io::{ErrorType, Write};
+use embedded_sdmmc::{BlockDevice, Directory, Error, FilenameError, Mode, TimeSource};
+
+use crate::fs::replace_extension;
+use crate::{read_file_chunks, CancellableError, YieldBudget};
+
+/// Extension used for checksum sidecar files
+pub const SIDECAR_EXTENSION: &str = "CRC";
+
+/// Length of a sidecar line for an 8.3 data file name: 8 hex digits, two
+/// spaces, up to 12 name bytes and a newline
+const SIDECAR_MAX_LEN: usize = 8 + 2 + 12 + 1;
+
+/// Incremental CRC-32 (IEEE 802.3, as used by zip and `crc32`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    /// Start a new checksum
+    pub const fn new() -> Self {
+        Self { state: 0xFFFF_FFFF }
+    }
+
+    /// Feed `data` into the checksum
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state ^= u32::from(byte);
+            for _ in 0..8 {
+                let mask = (self.state & 1).wrapping_neg();
+                self.state = (self.state >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    /// The checksum of everything fed in so far
+    pub const fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Write `value` as 8 lowercase hex digits, the form checksums and IDs are
+/// written in on the card
+pub(crate) fn write_hex(out: &mut [u8], value: u32) {
+    for (i, slot) in out[..8].iter_mut().enumerate() {
+        let nibble = (value >> (28 - 4 * i)) & 0xF;
+        *slot = b"0123456789abcdef"[nibble as usize];
+    }
+}
+
+/// Wraps a writer and checksums every byte written through it, so the
+/// sidecar can be written on close without re-reading the file.
+pub struct ChecksumWriter<W> {
+    inner: W,
+    crc: Crc32,
+}
+
+impl<W> ChecksumWriter<W> {
+    /// Wrap `inner` with a fresh checksum
+    pub const fn new(inner: W) -> Self {
+        Self {
+            inner,
+            crc: Crc32::new(),
+        }
+    }
+
+    /// The checksum of everything written so far
+    pub const fn checksum(&self) -> u32 {
+        self.crc.finish()
+    }
+
+    /// Get a reference to the wrapped writer
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Return the wrapped writer and the final checksum
+    pub fn into_inner(self) -> (W, u32) {
+        (self.inner, self.crc.finish())
+    }
+}
+
+impl<W: ErrorType> ErrorType for ChecksumWriter<W> {
+    type Error = W::Error;
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let n = self.inner.write(buf)?;
+        self.crc.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush()
+    }
+}
+
+/// Result of [`verify_checksum_sidecar`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumStatus {
+    /// The data file matches its sidecar
+    Match,
+    /// The data file changed since the sidecar was written
+    Mismatch {
+        /// Checksum recorded in the sidecar
+        stored: u32,
+        /// Checksum of the data file now
+        actual: u32,
+    },
+    /// The sidecar exists but doesn't start with 8 hex digits
+    BadSidecar,
+}
+
+/// Build the sidecar name for `data_name` into `buffer`, replacing any
+/// extension with [`SIDECAR_EXTENSION`]. Returns None if `data_name` isn't a
+/// valid 8.3 name.
+pub fn sidecar_name<'a>(data_name: &str, buffer: &'a mut [u8; 12]) -> Option<&'a str> {
+    replace_extension(data_name, SIDECAR_EXTENSION, buffer)
+}
+
+/// Compute the CRC-32 of `name` by streaming it through `buffer`, spending
+/// `budget` on every chunk so other tasks keep running. If `budget` is
+/// cancelled it stops with [`CancellableError::Cancelled`].
+pub async fn checksum_file<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    name: &str,
+    buffer: &mut [u8],
+    budget: &mut YieldBudget,
+) -> Result<u32, CancellableError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let file = dir.open_file_in_dir(name, Mode::ReadOnly)?;
+    let mut crc = Crc32::new();
+    let mut bytes = 0u32;
+    loop {
+        let n = file.read(buffer)?;
+        if n == 0 {
+            break;
+        }
+        crc.update(&buffer[..n]);
+        bytes += n as u32;
+        budget.spend_bytes(n as u32).await;
+        if budget.is_cancelled() {
+            file.close()?;
+            return Err(CancellableError::Cancelled { bytes });
+        }
+    }
+    file.close()?;
+    Ok(crc.finish())
+}
+
+/// Write (or overwrite) the sidecar for `data_name` recording `checksum`
+pub fn write_checksum_sidecar<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    data_name: &str,
+    checksum: u32,
+) -> Result<(), Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    if data_name.len() > 12 {
+        return Err(Error::FilenameError(FilenameError::NameTooLong));
+    }
+    let mut name_buf = [0u8; 12];
+    let name = sidecar_name(data_name, &mut name_buf)
+        .ok_or(Error::FilenameError(FilenameError::NameTooLong))?;
+
+    let mut line = [0u8; SIDECAR_MAX_LEN];
+    write_hex(&mut line[..8], checksum);
+    line[8..10].copy_from_slice(b"  ");
+    let end = 10 + data_name.len();
+    line[10..end].copy_from_slice(data_name.as_bytes());
+    line[end] = b'\n';
+
+    let file = dir.open_file_in_dir(name, Mode::ReadWriteCreateOrTruncate)?;
+    file.write(&line[..=end])?;
+    file.close()
+}
+
+/// Recompute the checksum of `data_name` and compare it with its sidecar.
+/// Returns `Error::NotFound` if either file is missing. See
+/// [`checksum_file`] for `budget`.
+pub async fn verify_checksum_sidecar<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    data_name: &str,
+    buffer: &mut [u8],
+    budget: &mut YieldBudget,
+) -> Result<ChecksumStatus, CancellableError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut name_buf = [0u8; 12];
+    let name = sidecar_name(data_name, &mut name_buf)
+        .ok_or(Error::FilenameError(FilenameError::NameTooLong))?;
+
+    let mut hex = [0u8; 8];
+    let mut hex_len = 0;
+    read_file_chunks(dir, name, buffer, |chunk| {
+        let take = chunk.len().min(hex.len() - hex_len);
+        hex[hex_len..hex_len + take].copy_from_slice(&chunk[..take]);
+        hex_len += take;
+    })?;
+    let stored = match core::str::from_utf8(&hex[..hex_len])
+        .ok()
+        .filter(|s| s.len() == 8 && s.bytes().all(|b| b.is_ascii_hexdigit()))
+        .and_then(|s| u32::from_str_radix(s, 16).ok())
+    {
+        Some(stored) => stored,
+        None => return Ok(ChecksumStatus::BadSidecar),
+    };
+
+    let actual = checksum_file(dir, data_name, buffer, budget).await?;
+    if actual == stored {
+        Ok(ChecksumStatus::Match)
+    } else {
+        Ok(ChecksumStatus::Mismatch { stored, actual })
+    }
+}
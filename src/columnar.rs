@@ -0,0 +1,197 @@
+//! Column-major chunked logging
+//!
+//! Buffers a batch of rows in RAM and writes them transposed (all values of
+//! column 0, then all values of column 1, ...) so that similar values sit next
+//! to each other in the file, which compresses much better on the host.
+
+use embedded_io::{Read, Write};
+
+/// Magic bytes at the start of every chunk
+pub const COLUMNAR_MAGIC: [u8; 4] = *b"COLC";
+
+/// Chunk format version written by [`ColumnarWriter`]
+pub const COLUMNAR_VERSION: u8 = 1;
+
+/// Size of the chunk header in bytes
+pub const COLUMNAR_HEADER_LEN: usize = 8;
+
+/// Errors returned when reading columnar chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnarError<E> {
+    /// The underlying reader failed
+    Io(E),
+    /// The chunk did not start with [`COLUMNAR_MAGIC`]
+    BadMagic,
+    /// The chunk was written by an unknown format version
+    UnsupportedVersion(u8),
+    /// The chunk has a different number of columns than the caller expects
+    ColumnMismatch { expected: usize, found: usize },
+    /// The chunk holds more rows than the caller's buffer
+    TooManyRows { rows: usize, capacity: usize },
+    /// The file ended in the middle of a chunk
+    Truncated,
+}
+
+/// Writes rows of `COLS` values in column-major chunks of up to `BATCH` rows.
+///
+/// `BATCH` is the number of rows held in RAM before a chunk is written; it
+/// costs `COLS * BATCH * 4` bytes. Larger batches compress better but lose
+/// more data on power failure, so call [`ColumnarWriter::flush`] before
+/// closing the file to write a final partial chunk.
+///
+/// Chunk format (all integers little-endian):
+///
+/// | Offset | Size          | Field                                  |
+/// | ------ | ------------- | -------------------------------------- |
+/// | 0      | 4             | Magic `"COLC"`                         |
+/// | 4      | 1             | Format version (`1`)                   |
+/// | 5      | 1             | Column count `C`                       |
+/// | 6      | 2             | Row count `R` (`u16`)                  |
+/// | 8      | `4 * C * R`   | `i32` values, column 0 rows 0..R, then column 1, ... |
+///
+/// Chunks are written back to back, so a file is simply a sequence of chunks.
+pub struct ColumnarWriter<const COLS: usize, const BATCH: usize> {
+    rows: [[i32; COLS]; BATCH],
+    len: usize,
+}
+
+impl<const COLS: usize, const BATCH: usize> ColumnarWriter<COLS, BATCH> {
+    /// Create an empty writer
+    pub const fn new() -> Self {
+        const {
+            assert!(COLS > 0 && COLS <= u8::MAX as usize, "COLS must be 1..=255");
+            assert!(
+                BATCH > 0 && BATCH <= u16::MAX as usize,
+                "BATCH must be 1..=65535"
+            );
+        }
+        Self {
+            rows: [[0; COLS]; BATCH],
+            len: 0,
+        }
+    }
+
+    /// Number of rows buffered and not yet written
+    pub fn pending_rows(&self) -> usize {
+        self.len
+    }
+
+    /// Buffer a row, first writing the buffered rows to `out` as a chunk if
+    /// all `BATCH` slots are full. Returns true if a chunk was written.
+    ///
+    /// A full batch is only written when the next row arrives (or on
+    /// [`ColumnarWriter::flush`]). If that write fails the batch stays
+    /// buffered and `row` is not added, so the call can simply be retried.
+    pub fn push_row<W: Write>(&mut self, out: &mut W, row: [i32; COLS]) -> Result<bool, W::Error> {
+        let flushed = self.len == BATCH;
+        if flushed {
+            self.flush(out)?;
+        }
+        self.rows[self.len] = row;
+        self.len += 1;
+        Ok(flushed)
+    }
+
+    /// Write any buffered rows as a (possibly partial) chunk
+    pub fn flush<W: Write>(&mut self, out: &mut W) -> Result<(), W::Error> {
+        if self.len == 0 {
+            return Ok(());
+        }
+
+        let mut header = [0u8; COLUMNAR_HEADER_LEN];
+        header[..4].copy_from_slice(&COLUMNAR_MAGIC);
+        header[4] = COLUMNAR_VERSION;
+        header[5] = COLS as u8;
+        header[6..8].copy_from_slice(&(self.len as u16).to_le_bytes());
+        out.write_all(&header)?;
+
+        // Stage values so the file sees a few larger writes instead of many 4-byte ones
+        let mut staging = [0u8; 64];
+        let mut staged = 0;
+        for col in 0..COLS {
+            for row in &self.rows[..self.len] {
+                staging[staged..staged + 4].copy_from_slice(&row[col].to_le_bytes());
+                staged += 4;
+                if staged == staging.len() {
+                    out.write_all(&staging)?;
+                    staged = 0;
+                }
+            }
+        }
+        out.write_all(&staging[..staged])?;
+
+        self.len = 0;
+        Ok(())
+    }
+}
+
+impl<const COLS: usize, const BATCH: usize> Default for ColumnarWriter<COLS, BATCH> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Read the next chunk written by [`ColumnarWriter`] back into row-major `rows`.
+///
+/// Returns the number of rows read, or 0 at a clean end of file.
+pub fn read_columnar_chunk<R: Read, const COLS: usize>(
+    input: &mut R,
+    rows: &mut [[i32; COLS]],
+) -> Result<usize, ColumnarError<R::Error>> {
+    let mut header = [0u8; COLUMNAR_HEADER_LEN];
+    match read_full(input, &mut header).map_err(ColumnarError::Io)? {
+        0 => return Ok(0),
+        COLUMNAR_HEADER_LEN => {}
+        _ => return Err(ColumnarError::Truncated),
+    }
+
+    if header[..4] != COLUMNAR_MAGIC {
+        return Err(ColumnarError::BadMagic);
+    }
+    if header[4] != COLUMNAR_VERSION {
+        return Err(ColumnarError::UnsupportedVersion(header[4]));
+    }
+    let cols = header[5] as usize;
+    if cols != COLS {
+        return Err(ColumnarError::ColumnMismatch {
+            expected: COLS,
+            found: cols,
+        });
+    }
+    let row_count = u16::from_le_bytes([header[6], header[7]]) as usize;
+    if row_count > rows.len() {
+        return Err(ColumnarError::TooManyRows {
+            rows: row_count,
+            capacity: rows.len(),
+        });
+    }
+
+    let total = row_count * COLS;
+    let mut staging = [0u8; 64];
+    let mut index = 0;
+    while index < total {
+        let want = ((total - index) * 4).min(staging.len());
+        if read_full(input, &mut staging[..want]).map_err(ColumnarError::Io)? != want {
+            return Err(ColumnarError::Truncated);
+        }
+        for value in staging[..want].chunks_exact(4) {
+            let (col, row) = (index / row_count, index % row_count);
+            rows[row][col] = i32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+            index += 1;
+        }
+    }
+
+    Ok(row_count)
+}
+
+/// Read until `buffer` is full or the reader hits end of file
+fn read_full<R: Read>(input: &mut R, buffer: &mut [u8]) -> Result<usize, R::Error> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match input.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
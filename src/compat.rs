@@ -0,0 +1,72 @@
+//! The crate-root paths of the [`crate::esp`] helpers, kept for one release
+//!
+//! Each forwards to the function of the same name in [`crate::esp`].
+
+use embedded_sdmmc::FilenameError;
+use esp_hal::rng::Rng;
+
+use crate::{esp, RetryConfig, TimeSourceExt};
+
+/// Generate random 8.3 filename (e.g., "ABC12345.CSV")
+#[deprecated(note = "moved to `esp32_sdcard::esp::generate_random_filename`")]
+pub fn generate_random_filename(rng: &mut Rng, filename: &mut [u8; 12]) {
+    esp::generate_random_filename(rng, filename)
+}
+
+/// Generate an 8.3 filename of `prefix`, random characters and `extension`
+#[deprecated(note = "moved to `esp32_sdcard::esp::generate_filename`")]
+pub fn generate_filename<'a>(
+    rng: &mut Rng,
+    prefix: &str,
+    extension: &str,
+    filename: &'a mut [u8; 12],
+) -> Result<&'a str, FilenameError> {
+    esp::generate_filename(rng, prefix, extension, filename)
+}
+
+/// Generate an 8.3 filename with a base-36 stem of `token_len` characters
+#[deprecated(note = "moved to `esp32_sdcard::esp::generate_token_filename`")]
+pub fn generate_token_filename(rng: &mut Rng, filename: &mut [u8; 12], token_len: usize) -> usize {
+    esp::generate_token_filename(rng, filename, token_len)
+}
+
+/// Generate a dated filename if `time_source` has real time, otherwise a random one
+#[deprecated(note = "moved to `esp32_sdcard::esp::generate_filename_for`")]
+pub fn generate_filename_for<'a, S: TimeSourceExt>(
+    time_source: &S,
+    rng: &mut Rng,
+    filename: &'a mut [u8; 12],
+) -> &'a str {
+    esp::generate_filename_for(time_source, rng, filename)
+}
+
+/// Like [`crate::retry_with_backoff`], with jittered delays
+#[deprecated(note = "moved to `esp32_sdcard::esp::retry_with_jitter`")]
+pub async fn retry_with_jitter<T, E, F, Fut>(
+    rng: &mut Rng,
+    operation_name: &str,
+    operation: F,
+) -> Option<T>
+where
+    F: FnMut() -> Fut,
+    Fut: core::future::Future<Output = Result<T, E>>,
+    E: core::fmt::Debug,
+{
+    esp::retry_with_jitter(rng, operation_name, operation).await
+}
+
+/// Like [`crate::try_retry_with_backoff_cfg`], with jittered delays
+#[deprecated(note = "moved to `esp32_sdcard::esp::try_retry_with_jitter`")]
+pub async fn try_retry_with_jitter<T, E, F, Fut>(
+    config: RetryConfig,
+    rng: &mut Rng,
+    operation_name: &str,
+    operation: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: core::future::Future<Output = Result<T, E>>,
+    E: core::fmt::Debug,
+{
+    esp::try_retry_with_jitter(config, rng, operation_name, operation).await
+}
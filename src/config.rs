@@ -0,0 +1,287 @@
+//! Field-editable `key=value` configuration files
+//!
+//! Lines have the form `key=value`. Blank lines and lines starting with `#`
+//! are ignored, and whitespace around keys and values is trimmed.
+
+use embedded_io::{Read, Write};
+use embedded_sdmmc::{BlockDevice, Directory, Mode, TimeSource};
+
+/// Longest config line accepted, in bytes
+pub const CONFIG_MAX_LINE: usize = 128;
+
+/// Problems with a single config line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigLineError {
+    /// The line has no `=` separator
+    MissingEquals,
+    /// The line is longer than [`CONFIG_MAX_LINE`]
+    LineTooLong,
+    /// The line is not valid UTF-8
+    NotUtf8,
+}
+
+/// Stream `key=value` entries from `input`, calling `on_entry` with the
+/// 1-based line number and the parsed pair (or why the line was rejected).
+pub fn read_config<R, F>(input: &mut R, mut on_entry: F) -> Result<(), R::Error>
+where
+    R: Read,
+    F: FnMut(u32, Result<(&str, &str), ConfigLineError>),
+{
+    let mut chunk = [0u8; 64];
+    let mut line = [0u8; CONFIG_MAX_LINE];
+    let mut len = 0;
+    let mut overflow = false;
+    let mut line_no = 1;
+
+    loop {
+        let n = input.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        for &byte in &chunk[..n] {
+            if byte == b'\n' {
+                parse_line(line_no, &line[..len], overflow, &mut on_entry);
+                len = 0;
+                overflow = false;
+                line_no += 1;
+            } else if len < line.len() {
+                line[len] = byte;
+                len += 1;
+            } else {
+                overflow = true;
+            }
+        }
+    }
+    if len > 0 || overflow {
+        parse_line(line_no, &line[..len], overflow, &mut on_entry);
+    }
+
+    Ok(())
+}
+
+fn parse_line<F>(line_no: u32, line: &[u8], overflow: bool, on_entry: &mut F)
+where
+    F: FnMut(u32, Result<(&str, &str), ConfigLineError>),
+{
+    if overflow {
+        return on_entry(line_no, Err(ConfigLineError::LineTooLong));
+    }
+    let Ok(text) = core::str::from_utf8(line) else {
+        return on_entry(line_no, Err(ConfigLineError::NotUtf8));
+    };
+    let text = text.trim();
+    if text.is_empty() || text.starts_with('#') {
+        return;
+    }
+    match text.split_once('=') {
+        Some((key, value)) => on_entry(line_no, Ok((key.trim(), value.trim()))),
+        None => on_entry(line_no, Err(ConfigLineError::MissingEquals)),
+    }
+}
+
+/// Why a [`LoggerConfig`] entry was ignored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigIssueKind {
+    /// The line could not be parsed
+    Line(ConfigLineError),
+    /// The key is not part of the schema
+    UnknownKey,
+    /// The value is not an unsigned integer
+    NotANumber,
+    /// The value is outside the allowed range
+    OutOfRange { min: u32, max: u32 },
+}
+
+/// A config entry that was ignored in favor of the compiled default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigIssue<'a> {
+    /// 1-based line number in the config file
+    pub line: u32,
+    /// The offending key (empty if the line could not be parsed)
+    pub key: &'a str,
+    /// What was wrong with it
+    pub kind: ConfigIssueKind,
+}
+
+/// Logging parameters that can be changed by editing `LOGGER.CFG` on the card
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoggerConfig {
+    /// Rows written between flushes (1-10000)
+    pub flush_rows: u32,
+    /// Milliseconds between time-based flushes, 0 disables (0-3600000)
+    pub flush_ms: u32,
+    /// Rotate to a new file after this many bytes, 0 disables (0-4294967295)
+    pub rotate_bytes: u32,
+    /// Delete old files to keep this percentage of the card free, 0 disables (0-90)
+    pub retention_min_free_pct: u32,
+    /// Maximum rows per second, 0 is unlimited (0-1000)
+    pub rate_limit_hz: u32,
+    /// Keep one sample out of every N (1-1000)
+    pub sample_decimation: u32,
+}
+
+impl Default for LoggerConfig {
+    fn default() -> Self {
+        Self {
+            flush_rows: 10,
+            flush_ms: 10_000,
+            rotate_bytes: 0,
+            retention_min_free_pct: 0,
+            rate_limit_hz: 0,
+            sample_decimation: 1,
+        }
+    }
+}
+
+impl LoggerConfig {
+    /// Load a config file from `dir`, starting from the compiled defaults.
+    ///
+    /// Entries that are unknown or invalid keep their default value and are
+    /// reported on the console (unless [`crate::Verbosity::Quiet`]) rather
+    /// than aborting the load. Returns an error
+    /// only if the file itself can't be read (e.g. `NotFound` on first boot).
+    pub fn load<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+        dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+        name: &str,
+    ) -> Result<Self, embedded_sdmmc::Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        Self::load_with(dir, name, |issue| {
+            if crate::verbosity() == crate::Verbosity::Quiet {
+                return;
+            }
+            println!(
+                "{} line {}: ignoring '{}' ({:?}), using default",
+                name, issue.line, issue.key, issue.kind
+            );
+        })
+    }
+
+    /// Like [`LoggerConfig::load`], but hands each ignored entry to `on_issue`
+    pub fn load_with<
+        D,
+        T,
+        F,
+        const MAX_DIRS: usize,
+        const MAX_FILES: usize,
+        const MAX_VOLUMES: usize,
+    >(
+        dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+        name: &str,
+        mut on_issue: F,
+    ) -> Result<Self, embedded_sdmmc::Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+        F: FnMut(ConfigIssue<'_>),
+    {
+        let mut config = Self::default();
+        let mut file = dir.open_file_in_dir(name, Mode::ReadOnly)?;
+        read_config(&mut file, |line, entry| {
+            let (key, kind) = match entry {
+                Ok((key, value)) => match config.apply(key, value) {
+                    Ok(()) => return,
+                    Err(kind) => (key, kind),
+                },
+                Err(e) => ("", ConfigIssueKind::Line(e)),
+            };
+            on_issue(ConfigIssue { line, key, kind });
+        })?;
+        file.close()?;
+        Ok(config)
+    }
+
+    /// Set a single key from its text value, leaving the config unchanged on error
+    pub fn apply(&mut self, key: &str, value: &str) -> Result<(), ConfigIssueKind> {
+        match key {
+            "flush_rows" => self.flush_rows = parse_in_range(value, 1, 10_000)?,
+            "flush_ms" => self.flush_ms = parse_in_range(value, 0, 3_600_000)?,
+            "rotate_bytes" => self.rotate_bytes = parse_in_range(value, 0, u32::MAX)?,
+            "retention_min_free_pct" => self.retention_min_free_pct = parse_in_range(value, 0, 90)?,
+            "rate_limit_hz" => self.rate_limit_hz = parse_in_range(value, 0, 1_000)?,
+            "sample_decimation" => self.sample_decimation = parse_in_range(value, 1, 1_000)?,
+            _ => return Err(ConfigIssueKind::UnknownKey),
+        }
+        Ok(())
+    }
+
+    /// Write the config with explanatory comments, in a form [`LoggerConfig::load`] reads back
+    pub fn write_to<W: Write>(&self, out: &mut W) -> Result<(), W::Error> {
+        out.write_all(
+            b"# Logger configuration, read at boot.\n\
+              # One key=value per line. Invalid values fall back to the firmware defaults.\n",
+        )?;
+        let entries = [
+            (
+                "Rows written between flushes (1-10000)",
+                "flush_rows",
+                self.flush_rows,
+            ),
+            (
+                "Milliseconds between flushes, 0 disables (0-3600000)",
+                "flush_ms",
+                self.flush_ms,
+            ),
+            (
+                "Rotate after this many bytes, 0 disables",
+                "rotate_bytes",
+                self.rotate_bytes,
+            ),
+            (
+                "Keep this percentage of the card free, 0 disables (0-90)",
+                "retention_min_free_pct",
+                self.retention_min_free_pct,
+            ),
+            (
+                "Maximum rows per second, 0 is unlimited (0-1000)",
+                "rate_limit_hz",
+                self.rate_limit_hz,
+            ),
+            (
+                "Keep one sample out of every N (1-1000)",
+                "sample_decimation",
+                self.sample_decimation,
+            ),
+        ];
+        for (comment, key, value) in entries {
+            let mut value_buf = itoa::Buffer::new();
+            for part in [
+                b"# ".as_slice(),
+                comment.as_bytes(),
+                b"\n",
+                key.as_bytes(),
+                b"=",
+                value_buf.format(value).as_bytes(),
+                b"\n",
+            ] {
+                out.write_all(part)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write (or overwrite) a commented config file in `dir` with the current values
+    pub fn store<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+        &self,
+        dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+        name: &str,
+    ) -> Result<(), embedded_sdmmc::Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let mut file = dir.open_file_in_dir(name, Mode::ReadWriteCreateOrTruncate)?;
+        self.write_to(&mut file)?;
+        file.close()
+    }
+}
+
+fn parse_in_range(value: &str, min: u32, max: u32) -> Result<u32, ConfigIssueKind> {
+    let value: u32 = value.parse().map_err(|_| ConfigIssueKind::NotANumber)?;
+    if value < min || value > max {
+        return Err(ConfigIssueKind::OutOfRange { min, max });
+    }
+    Ok(value)
+}
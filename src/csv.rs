@@ -0,0 +1,363 @@
+//! RFC 4180 CSV field escaping and row formatting into caller buffers, plus
+//! continuation lines for fields too long for one line
+
+use crate::Field;
+
+/// True if `field` must be quoted: it contains a comma, double quote, CR or LF
+pub fn needs_quoting(field: &[u8]) -> bool {
+    field
+        .iter()
+        .any(|&b| matches!(b, b',' | b'"' | b'\r' | b'\n'))
+}
+
+/// True if `field` must be quoted as the first field of a row even though
+/// [`needs_quoting`] says it needn't: it starts with `#`, so unquoted the row
+/// would be read as a comment or a continuation line
+pub(crate) fn quote_as_first_field(field: &[u8]) -> bool {
+    field.first() == Some(&b'#')
+}
+
+/// Write `field` into `buffer` as a CSV field, returns bytes written or None
+/// if it doesn't fit.
+///
+/// The field is wrapped in double quotes (with embedded quotes doubled) when
+/// [`needs_quoting`] says so, or always when `quote_all` is set.
+pub fn escape_csv_field(buffer: &mut [u8], field: &[u8], quote_all: bool) -> Option<usize> {
+    if !quote_all && !needs_quoting(field) {
+        buffer.get_mut(..field.len())?.copy_from_slice(field);
+        return Some(field.len());
+    }
+
+    let mut cursor = 0;
+    let mut push = |byte: u8| -> Option<()> {
+        *buffer.get_mut(cursor)? = byte;
+        cursor += 1;
+        Some(())
+    };
+    push(b'"')?;
+    for &byte in field {
+        if byte == b'"' {
+            push(b'"')?;
+        }
+        push(byte)?;
+    }
+    push(b'"')?;
+    Some(cursor)
+}
+
+/// Errors returned by [`format_csv_row`] and [`CsvHeader`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsvError {
+    /// The row doesn't fit in the buffer; nothing usable was written
+    BufferTooSmall,
+}
+
+/// Line ending written after each row
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    /// `\n`
+    #[default]
+    Lf,
+    /// `\r\n`, as RFC 4180 and some Windows tools expect
+    CrLf,
+}
+
+impl LineEnding {
+    /// The ending's bytes, also usable with
+    /// [`crate::CsvWriter::with_record_separator`]
+    pub const fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::CrLf => b"\r\n",
+        }
+    }
+}
+
+/// Write `fields` into `buffer` as one comma-separated row ending in
+/// `line_ending`, returns bytes written.
+///
+/// Fields are escaped with [`escape_csv_field`] and [`Field::Fixed`] values
+/// written with their decimal point. A first field starting with `#` is
+/// quoted so the row isn't read as a comment. A row that doesn't fit gives
+/// [`CsvError::BufferTooSmall`] rather than a truncated row; the buffer
+/// contents are then unspecified.
+///
+/// ```text
+/// [UInt(1200), Str("a,b"), Fixed { value: -215, frac_digits: 1 }] -> 1200,"a,b",-21.5\n
+/// ```
+pub fn format_csv_row(
+    buffer: &mut [u8],
+    fields: &[Field<'_>],
+    line_ending: LineEnding,
+) -> Result<usize, CsvError> {
+    format_fields(buffer, fields.iter().copied(), line_ending)
+}
+
+fn format_fields<'f>(
+    buffer: &mut [u8],
+    fields: impl Iterator<Item = Field<'f>>,
+    line_ending: LineEnding,
+) -> Result<usize, CsvError> {
+    let mut cursor = 0;
+    for (i, field) in fields.enumerate() {
+        if i > 0 {
+            cursor += push(buffer, cursor, b",")?;
+        }
+        cursor += field
+            .with_bytes(|bytes| {
+                let quote = i == 0 && quote_as_first_field(bytes);
+                escape_csv_field(buffer.get_mut(cursor..)?, bytes, quote)
+            })
+            .ok_or(CsvError::BufferTooSmall)?;
+    }
+    cursor += push(buffer, cursor, line_ending.as_bytes())?;
+    Ok(cursor)
+}
+
+/// Copy `bytes` into `buffer` at `cursor`, returns bytes written
+fn push(buffer: &mut [u8], cursor: usize, bytes: &[u8]) -> Result<usize, CsvError> {
+    buffer
+        .get_mut(cursor..cursor + bytes.len())
+        .ok_or(CsvError::BufferTooSmall)?
+        .copy_from_slice(bytes);
+    Ok(bytes.len())
+}
+
+/// Column names for a file written with [`format_csv_row`], formatted only
+/// the first time they are asked for so the header row is written once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsvHeader<'a> {
+    columns: &'a [&'a str],
+    written: bool,
+}
+
+impl<'a> CsvHeader<'a> {
+    /// A header that hasn't been written yet
+    pub const fn new(columns: &'a [&'a str]) -> Self {
+        Self {
+            columns,
+            written: false,
+        }
+    }
+
+    /// The column names
+    pub fn columns(&self) -> &'a [&'a str] {
+        self.columns
+    }
+
+    /// True once the header has been formatted, or marked as written
+    pub fn is_written(&self) -> bool {
+        self.written
+    }
+
+    /// Write the header row into `buffer` if it hasn't been yet, returns
+    /// bytes written, 0 if it already was. It only counts as written once
+    /// it fits.
+    pub fn format_once(
+        &mut self,
+        buffer: &mut [u8],
+        line_ending: LineEnding,
+    ) -> Result<usize, CsvError> {
+        if self.written {
+            return Ok(0);
+        }
+        let fields = self.columns.iter().map(|&column| Field::Str(column));
+        let len = format_fields(buffer, fields, line_ending)?;
+        self.written = true;
+        Ok(len)
+    }
+
+    /// Treat the header as already written, e.g. when appending to a file
+    /// that has one
+    pub fn mark_written(&mut self) {
+        self.written = true;
+    }
+
+    /// Write the header again, e.g. at the start of the next file after
+    /// rotating
+    pub fn reset(&mut self) {
+        self.written = false;
+    }
+}
+
+/// Start of every continuation line
+pub const CONTINUATION_PREFIX: &[u8] = b"#+";
+
+/// Field value standing in for the continued field in its row. Like any
+/// first field starting with `#`, it is quoted in column 0 (`"#+",1200`) so
+/// the row isn't taken for a continuation line.
+pub const CONTINUED_FIELD: &str = "#+";
+
+/// Escape `byte` for a continuation line into `out`, returns bytes written
+pub(crate) fn escape_continuation_byte(byte: u8, out: &mut [u8; 2]) -> usize {
+    let escaped = match byte {
+        b'\\' => b'\\',
+        b'\n' => b'n',
+        b'\r' => b'r',
+        _ => {
+            out[0] = byte;
+            return 1;
+        }
+    };
+    *out = [b'\\', escaped];
+    2
+}
+
+/// Rejoins a field written across continuation lines, holding up to `N`
+/// bytes of the value.
+///
+/// A field longer than the writer's line buffer is written by
+/// [`crate::CsvWriter::write_continued`] as one or more continuation lines
+/// just before its row, and the row holds [`CONTINUED_FIELD`] in its place:
+///
+/// ```text
+/// #+first part of a very long note that\nspans two lines in the orig
+/// #+inal
+/// 1200,#+,21.5
+/// ```
+///
+/// Each continuation line is [`CONTINUATION_PREFIX`] followed by a piece of
+/// the value with `\` written as `\\`, LF as `\n` and CR as `\r`, so a
+/// piece never contains a line break. To rejoin, feed every line to the
+/// joiner before parsing it; continuation lines are consumed and
+/// the next row's `#+` field is replaced by [`FieldJoiner::value`]. Only
+/// lines starting with the unquoted prefix are continuation lines; the
+/// writers quote a first field starting with `#`, so a row with the marker
+/// in column 0 reads `"#+",1200,21.5` and is parsed as a row. A row holds
+/// at most one continued field, and a real field whose value is exactly
+/// `#+` can't be told apart from the marker. Readers that don't know the
+/// scheme skip continuation lines as comments.
+pub struct FieldJoiner<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    overflowed: bool,
+    escape: bool,
+}
+
+impl<const N: usize> FieldJoiner<N> {
+    /// Create an empty joiner
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            overflowed: false,
+            escape: false,
+        }
+    }
+
+    /// Take in `line` (with or without its line ending). Returns true if it
+    /// was a continuation line and has been consumed; otherwise parse it
+    /// as usual and call [`FieldJoiner::clear`] once the row is handled.
+    pub fn push_line(&mut self, line: &[u8]) -> bool {
+        let Some(piece) = line.strip_prefix(CONTINUATION_PREFIX) else {
+            return false;
+        };
+        let piece = piece.strip_suffix(b"\n").unwrap_or(piece);
+        let piece = piece.strip_suffix(b"\r").unwrap_or(piece);
+        for &byte in piece {
+            let byte = match (self.escape, byte) {
+                (false, b'\\') => {
+                    self.escape = true;
+                    continue;
+                }
+                (false, byte) => byte,
+                (true, b'n') => b'\n',
+                (true, b'r') => b'\r',
+                (true, byte) => byte,
+            };
+            self.escape = false;
+            match self.buf.get_mut(self.len) {
+                Some(slot) => {
+                    *slot = byte;
+                    self.len += 1;
+                }
+                None => self.overflowed = true,
+            }
+        }
+        true
+    }
+
+    /// The rejoined value, or None if it didn't fit in `N` bytes
+    pub fn value(&self) -> Option<&[u8]> {
+        (!self.overflowed).then_some(&self.buf[..self.len])
+    }
+
+    /// Forget the value, ready for the next row
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.overflowed = false;
+        self.escape = false;
+    }
+}
+
+impl<const N: usize> Default for FieldJoiner<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row<'b>(buffer: &'b mut [u8], fields: &[Field<'_>]) -> Result<&'b str, CsvError> {
+        let len = format_csv_row(buffer, fields, LineEnding::Lf)?;
+        Ok(core::str::from_utf8(&buffer[..len]).unwrap())
+    }
+
+    #[test]
+    fn quotes_fields_that_need_it() {
+        let mut buffer = [0u8; 64];
+        let fields = [
+            Field::UInt(1200),
+            Field::Str("a,b"),
+            Field::Str("say \"hi\""),
+            Field::Str("two\nlines"),
+            Field::Fixed {
+                value: -215,
+                frac_digits: 1,
+            },
+        ];
+        assert_eq!(
+            row(&mut buffer, &fields),
+            Ok("1200,\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\",-21.5\n")
+        );
+    }
+
+    #[test]
+    fn quotes_a_leading_hash() {
+        let mut buffer = [0u8; 32];
+        let fields = [Field::Str("#1"), Field::Str("#2")];
+        assert_eq!(row(&mut buffer, &fields), Ok("\"#1\",#2\n"));
+    }
+
+    #[test]
+    fn row_that_does_not_fit_is_an_error_not_truncated() {
+        let fields = [Field::UInt(1234), Field::Str("a\"b")];
+        // "1234,\"a\"\"b\"\n" is 12 bytes
+        let mut exact = [0u8; 12];
+        assert_eq!(row(&mut exact, &fields), Ok("1234,\"a\"\"b\"\n"));
+        for len in 0..12 {
+            let mut short = [0u8; 12];
+            assert_eq!(
+                format_csv_row(&mut short[..len], &fields, LineEnding::Lf),
+                Err(CsvError::BufferTooSmall),
+                "buffer of {len}"
+            );
+        }
+    }
+
+    #[test]
+    fn crlf_counts_towards_the_length() {
+        let mut buffer = [0u8; 3];
+        let fields = [Field::UInt(7)];
+        assert_eq!(
+            format_csv_row(&mut buffer, &fields, LineEnding::CrLf),
+            Ok(3)
+        );
+        assert_eq!(
+            format_csv_row(&mut buffer[..2], &fields, LineEnding::CrLf),
+            Err(CsvError::BufferTooSmall)
+        );
+    }
+}
@@ -0,0 +1,396 @@
+//! Reading on-card filesystem structures directly, for support dumps and
+//! values embedded-sdmmc keeps private
+
+use core::fmt::Write;
+
+use embassy_time::Duration;
+use embedded_sdmmc::{Block, BlockDevice, BlockIdx, Error, VolumeIdx};
+
+use crate::YieldBudget;
+
+/// Offset of the first partition entry in the MBR
+const PARTITION_TABLE: usize = 446;
+/// FSInfo lead, struct and trail signatures
+pub(crate) const FSINFO_SIGNATURES: [(usize, u32); 3] =
+    [(0, 0x4161_5252), (484, 0x6141_7272), (508, 0xAA55_0000)];
+
+/// Errors returned by [`dump_filesystem_headers`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpError<E> {
+    /// A block could not be read
+    Device(E),
+    /// The sink refused the output
+    Format,
+}
+
+impl<E> From<core::fmt::Error> for DumpError<E> {
+    fn from(_: core::fmt::Error) -> Self {
+        DumpError::Format
+    }
+}
+
+/// Write an annotated decode and hex dump of the MBR, the first partition's
+/// boot sector and (on FAT32) its FSInfo sector to `sink`.
+///
+/// Values that no working card would have are flagged with a leading `!!`,
+/// e.g. `!! bytes_per_sector=0`. Cards formatted without a partition table
+/// are detected and their boot sector is decoded from LBA 0.
+pub fn dump_filesystem_headers<D: BlockDevice>(
+    device: &D,
+    sink: &mut dyn Write,
+) -> Result<(), DumpError<D::Error>> {
+    let mut block = [Block::new()];
+    device
+        .read(&mut block, BlockIdx(0))
+        .map_err(DumpError::Device)?;
+    let mbr = &block[0].contents;
+
+    writeln!(sink, "== LBA 0 (MBR)")?;
+    flag(
+        sink,
+        "signature",
+        format_args!("0x{:04x}", le16(mbr, 510)),
+        le16(mbr, 510) == 0xAA55,
+    )?;
+    let mut boot_lba = 0;
+    for index in 0..4 {
+        let entry = &mbr[PARTITION_TABLE + index * 16..PARTITION_TABLE + (index + 1) * 16];
+        let (status, kind) = (entry[0], entry[4]);
+        let (start, sectors) = (le32(entry, 8), le32(entry, 12));
+        if kind == 0 {
+            writeln!(sink, "   partition{}: empty", index)?;
+            continue;
+        }
+        writeln!(
+            sink,
+            "{} partition{}: status=0x{:02x} type=0x{:02x} ({}) start={} sectors={}",
+            if status & 0x7F == 0 { "  " } else { "!!" },
+            index,
+            status,
+            kind,
+            partition_type_name(kind),
+            start,
+            sectors,
+        )?;
+        if boot_lba == 0 {
+            boot_lba = start;
+        }
+    }
+    let superfloppy = boot_lba == 0 && matches!(mbr[0], 0xEB | 0xE9);
+    if superfloppy {
+        writeln!(sink, "   no partition table, boot sector at LBA 0")?;
+    }
+    hex_dump(sink, mbr)?;
+
+    if boot_lba == 0 && !superfloppy {
+        return Ok(());
+    }
+    if !superfloppy {
+        device
+            .read(&mut block, BlockIdx(boot_lba))
+            .map_err(DumpError::Device)?;
+    }
+    let boot = &block[0].contents;
+    writeln!(sink, "== LBA {} (boot sector)", boot_lba)?;
+    let bytes_per_sector = le16(boot, 11);
+    let sectors_per_cluster = boot[13];
+    let fat_size16 = le16(boot, 22);
+    let fat32 = fat_size16 == 0;
+    let fat_size = if fat32 {
+        le32(boot, 36)
+    } else {
+        u32::from(fat_size16)
+    };
+    let total_sectors = match le16(boot, 19) {
+        0 => le32(boot, 32),
+        n => u32::from(n),
+    };
+    flag(
+        sink,
+        "signature",
+        format_args!("0x{:04x}", le16(boot, 510)),
+        le16(boot, 510) == 0xAA55,
+    )?;
+    flag(
+        sink,
+        "bytes_per_sector",
+        bytes_per_sector,
+        bytes_per_sector == 512,
+    )?;
+    flag(
+        sink,
+        "sectors_per_cluster",
+        sectors_per_cluster,
+        sectors_per_cluster.is_power_of_two(),
+    )?;
+    flag(sink, "reserved_sectors", le16(boot, 14), le16(boot, 14) > 0)?;
+    flag(sink, "fat_count", boot[16], matches!(boot[16], 1 | 2))?;
+    flag(sink, "fat_size", fat_size, fat_size > 0)?;
+    flag(sink, "total_sectors", total_sectors, total_sectors > 0)?;
+    writeln!(
+        sink,
+        "   fat_type={}",
+        if fat32 { "FAT32" } else { "FAT12/16" }
+    )?;
+    let fsinfo_sector = le16(boot, 48);
+    if fat32 {
+        flag(sink, "root_cluster", le32(boot, 44), le32(boot, 44) >= 2)?;
+        flag(sink, "fsinfo_sector", fsinfo_sector, fsinfo_sector > 0)?;
+    } else {
+        flag(sink, "root_entries", le16(boot, 17), le16(boot, 17) > 0)?;
+    }
+    hex_dump(sink, boot)?;
+
+    if !fat32 || fsinfo_sector == 0 || fsinfo_sector == 0xFFFF {
+        return Ok(());
+    }
+    let fsinfo_lba = boot_lba + u32::from(fsinfo_sector);
+    device
+        .read(&mut block, BlockIdx(fsinfo_lba))
+        .map_err(DumpError::Device)?;
+    let fsinfo = &block[0].contents;
+    writeln!(sink, "== LBA {} (FSInfo)", fsinfo_lba)?;
+    let signatures_ok = FSINFO_SIGNATURES
+        .iter()
+        .all(|&(offset, expected)| le32(fsinfo, offset) == expected);
+    flag(
+        sink,
+        "signatures",
+        if signatures_ok { "ok" } else { "bad" },
+        signatures_ok,
+    )?;
+    // 0xFFFFFFFF means "unknown", which is allowed
+    let free = le32(fsinfo, 488);
+    if free == u32::MAX {
+        writeln!(sink, "   free_clusters=unknown")?;
+    } else {
+        writeln!(sink, "   free_clusters={}", free)?;
+    }
+    let next_free = le32(fsinfo, 492);
+    if next_free == u32::MAX {
+        writeln!(sink, "   next_free_hint=unknown")?;
+    } else {
+        flag(sink, "next_free_hint", next_free, next_free >= 2)?;
+    }
+    hex_dump(sink, fsinfo)?;
+    Ok(())
+}
+
+/// Cluster size in bytes of `volume`, read from its boot sector, or None if
+/// the partition doesn't exist or its boot sector is implausible.
+///
+/// embedded-sdmmc keeps this private; get at the device with
+/// `volume_mgr.device(|device| bytes_per_cluster(device, VolumeIdx(0)))`.
+pub fn bytes_per_cluster<D: BlockDevice>(
+    device: &D,
+    volume: VolumeIdx,
+) -> Result<Option<u32>, D::Error> {
+    let mut block = [Block::new()];
+    Ok(read_boot_sector(device, volume, &mut block)?.map(|(_, cluster_size)| cluster_size))
+}
+
+/// Free space on FAT32 `volume` in bytes, from the free cluster count in
+/// its FSInfo sector, or None if that is unavailable (FAT16, or the count
+/// was never recorded).
+///
+/// The count is a hint kept by the filesystem driver and may lag behind
+/// writes made since the volume was last closed. Some cheap cards also
+/// return a bogus zero under load, so prefer [`free_space_sampled`] before
+/// acting on a zero. `budget` is spent on each block read.
+pub async fn free_space<D: BlockDevice>(
+    device: &D,
+    volume: VolumeIdx,
+    budget: &mut YieldBudget,
+) -> Result<Option<u64>, D::Error> {
+    let mut block = [Block::new()];
+    let boot_sector = read_boot_sector(device, volume, &mut block)?;
+    // The MBR and the boot sector
+    budget.spend_blocks(2).await;
+    let Some((boot_lba, cluster_size)) = boot_sector else {
+        return Ok(None);
+    };
+    let boot = &block[0].contents;
+    let fsinfo_sector = le16(boot, 48);
+    if le16(boot, 22) != 0 || fsinfo_sector == 0 || fsinfo_sector == 0xFFFF {
+        return Ok(None);
+    }
+    device.read(&mut block, BlockIdx(boot_lba + u32::from(fsinfo_sector)))?;
+    budget.spend_blocks(1).await;
+    let fsinfo = &block[0].contents;
+    let signatures_ok = FSINFO_SIGNATURES
+        .iter()
+        .all(|&(offset, expected)| le32(fsinfo, offset) == expected);
+    match le32(fsinfo, 488) {
+        _ if !signatures_ok => Ok(None),
+        u32::MAX => Ok(None),
+        free => Ok(Some(u64::from(free) * u64::from(cluster_size))),
+    }
+}
+
+/// Like [`free_space`], but only believes a zero that every one of
+/// `samples` reads agrees on.
+///
+/// Reading stops at the first non-zero answer, so a healthy card costs one
+/// read. A read error is only returned if every sample fails, so a single
+/// glitch doesn't look like a full card either.
+pub async fn free_space_sampled<D: BlockDevice>(
+    device: &D,
+    volume: VolumeIdx,
+    samples: u8,
+    budget: &mut YieldBudget,
+) -> Result<Option<u64>, D::Error> {
+    let mut result = free_space(device, volume, budget).await;
+    for _ in 1..samples {
+        match result {
+            Ok(Some(0)) | Err(_) => {}
+            _ => break,
+        }
+        result = match (free_space(device, volume, budget).await, result) {
+            // Keep an earlier answer over a later error
+            (Err(_), Ok(earlier)) => Ok(earlier),
+            (sample, _) => sample,
+        };
+    }
+    result
+}
+
+/// Check that `volume` has room for a recording of `duration` at
+/// `rows_per_sec` rows of `bytes_per_row` bytes, before starting it.
+/// Returns the bytes the recording needs, rounded up to whole clusters.
+///
+/// Free space comes from [`free_space_sampled`], so it fails with
+/// `Error::DiskFull` if the recording won't fit and `Error::Unsupported` if
+/// the volume keeps no free cluster count (FAT16, or never recorded), since
+/// then nothing can be promised. Nothing is allocated: embedded-sdmmc can't
+/// extend a file without writing it, so other writes made before the
+/// recording ends still count against the space checked here. Recordings
+/// over [`crate::FAT32_MAX_FILE_SIZE`] also need rotating across files.
+pub async fn reserve_for<D: BlockDevice>(
+    device: &D,
+    volume: VolumeIdx,
+    bytes_per_row: u32,
+    rows_per_sec: u32,
+    duration: Duration,
+    budget: &mut YieldBudget,
+) -> Result<u64, Error<D::Error>> {
+    let bytes = u64::from(bytes_per_row)
+        .saturating_mul(u64::from(rows_per_sec))
+        .saturating_mul(duration.as_millis())
+        .div_ceil(1000);
+    let cluster_size = bytes_per_cluster(device, volume)
+        .map_err(Error::DeviceError)?
+        .ok_or(Error::Unsupported)?;
+    let needed = bytes
+        .div_ceil(u64::from(cluster_size))
+        .saturating_mul(u64::from(cluster_size));
+    match free_space_sampled(device, volume, 3, budget)
+        .await
+        .map_err(Error::DeviceError)?
+    {
+        Some(free) if free >= needed => Ok(needed),
+        Some(_) => Err(Error::DiskFull),
+        None => Err(Error::Unsupported),
+    }
+}
+
+/// Read `volume`'s boot sector into `block`, returning its LBA and cluster
+/// size, or None if it doesn't exist or is implausible
+pub(crate) fn read_boot_sector<D: BlockDevice>(
+    device: &D,
+    volume: VolumeIdx,
+    block: &mut [Block; 1],
+) -> Result<Option<(u32, u32)>, D::Error> {
+    device.read(block, BlockIdx(0))?;
+    let mbr = &block[0].contents;
+    let boot_lba = if volume.0 < 4 {
+        let entry = PARTITION_TABLE + volume.0 * 16;
+        (mbr[entry + 4] != 0).then(|| le32(mbr, entry + 8))
+    } else {
+        None
+    };
+    let boot_lba = match boot_lba {
+        Some(lba) => {
+            device.read(block, BlockIdx(lba))?;
+            lba
+        }
+        // A card without a partition table has its only volume at LBA 0
+        None if volume.0 == 0 && matches!(block[0].contents[0], 0xEB | 0xE9) => 0,
+        None => return Ok(None),
+    };
+    let boot = &block[0].contents;
+    let (bytes_per_sector, sectors_per_cluster) = (le16(boot, 11), boot[13]);
+    if le16(boot, 510) != 0xAA55 || bytes_per_sector == 0 || !sectors_per_cluster.is_power_of_two()
+    {
+        return Ok(None);
+    }
+    let cluster_size = u32::from(bytes_per_sector) * u32::from(sectors_per_cluster);
+    Ok(Some((boot_lba, cluster_size)))
+}
+
+/// Write `name=value`, prefixed with `!!` if the value is implausible
+fn flag(
+    sink: &mut dyn Write,
+    name: &str,
+    value: impl core::fmt::Display,
+    plausible: bool,
+) -> core::fmt::Result {
+    let marker = if plausible { "  " } else { "!!" };
+    writeln!(sink, "{} {}={}", marker, name, value)
+}
+
+/// Classic 16-bytes-per-line hex dump; runs of identical lines collapse to `*`
+fn hex_dump(sink: &mut dyn Write, data: &[u8]) -> core::fmt::Result {
+    let mut previous: Option<&[u8]> = None;
+    let mut collapsed = false;
+    for (index, line) in data.chunks(16).enumerate() {
+        if previous == Some(line) {
+            if !collapsed {
+                writeln!(sink, "*")?;
+                collapsed = true;
+            }
+            continue;
+        }
+        previous = Some(line);
+        collapsed = false;
+
+        write!(sink, "{:04x}:", index * 16)?;
+        for byte in line {
+            write!(sink, " {:02x}", byte)?;
+        }
+        write!(sink, "  |")?;
+        for &byte in line {
+            let shown = if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            };
+            sink.write_char(shown)?;
+        }
+        writeln!(sink, "|")?;
+    }
+    Ok(())
+}
+
+fn partition_type_name(kind: u8) -> &'static str {
+    match kind {
+        0x01 => "FAT12",
+        0x04 | 0x06 | 0x0E => "FAT16",
+        0x0B | 0x0C => "FAT32",
+        0x07 => "exFAT/NTFS",
+        0xEE => "GPT protective",
+        _ => "unknown",
+    }
+}
+
+pub(crate) fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub(crate) fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
@@ -0,0 +1,80 @@
+//! Per-row delivery confirmation for producers that must know a record is on the card
+//!
+//! Rows written with [`crate::CsvWriter::write_row_seq`] carry a sequence ID.
+//! After a flush succeeds, the writer posts the highest sequence ID written
+//! so far to a [`DurableSeq`], which a producer awaits before dropping the
+//! record from its own queue. Only the watermark is stored, not per-row state.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::watch::Watch;
+use embassy_time::{Duration, Timer};
+
+/// Highest sequence ID known to be durable on the card
+///
+/// Meant to live in a `static` shared between the writer task and producers.
+/// Backed by an `embassy_sync` [`Watch`], so waiters are woken by each post.
+pub struct DurableSeq {
+    watch: Watch<CriticalSectionRawMutex, u64, { DurableSeq::MAX_WAITERS }>,
+}
+
+impl DurableSeq {
+    /// How many tasks can be woken by a post while in
+    /// [`DurableSeq::wait_durable`] at once
+    pub const MAX_WAITERS: usize = 4;
+
+    /// How often [`DurableSeq::wait_durable`] rechecks the watermark when
+    /// more than [`DurableSeq::MAX_WAITERS`] tasks are waiting
+    pub const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    /// Nothing is durable yet
+    pub const fn new() -> Self {
+        Self {
+            watch: Watch::new(),
+        }
+    }
+
+    /// Highest durable sequence ID, or None if nothing has been made durable
+    pub fn get(&self) -> Option<u64> {
+        self.watch.try_get()
+    }
+
+    /// True if the row with sequence ID `seq` is durable
+    pub fn is_durable(&self, seq: u64) -> bool {
+        self.get().is_some_and(|durable| durable >= seq)
+    }
+
+    /// Wait until the row with sequence ID `seq` is durable
+    pub async fn wait_durable(&self, seq: u64) {
+        if self.is_durable(seq) {
+            return;
+        }
+        match self.watch.receiver() {
+            Some(mut receiver) => {
+                receiver.get_and(|&durable| durable >= seq).await;
+            }
+            // Every receiver is taken by other waiters
+            None => {
+                while !self.is_durable(seq) {
+                    Timer::after(Self::POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Record that every row up to `seq` is durable; never moves backwards
+    pub(crate) fn post(&self, seq: u64) {
+        self.watch.sender().send_if_modified(|durable| {
+            if durable.is_some_and(|current| seq <= current) {
+                return false;
+            }
+            *durable = Some(seq);
+            true
+        });
+    }
+}
+
+impl Default for DurableSeq {
+    fn default() -> Self {
+        Self::new()
+    }
+}
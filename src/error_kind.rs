@@ -0,0 +1,79 @@
+//! A stable classification of embedded-sdmmc errors
+//!
+//! `embedded_sdmmc::Error` gains and renames variants between releases.
+//! Matching on [`FsErrorKind`] instead keeps application code compiling
+//! across embedded-sdmmc upgrades; only the mapping here has to follow.
+
+use embedded_sdmmc::Error;
+
+/// What kind of failure an `embedded_sdmmc::Error` is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum FsErrorKind {
+    /// The block device (card or SPI bus) failed
+    Device,
+    /// The file or directory doesn't exist
+    NotFound,
+    /// A file or directory with that name already exists
+    AlreadyExists,
+    /// The file, directory or volume is already open, or still in use
+    InUse,
+    /// The volume has no free space left
+    DiskFull,
+    /// The filesystem structures are damaged: a bad cluster, broken FAT
+    /// chain or unreadable boot sector
+    Corrupt,
+    /// No usable FAT volume, or a feature the filesystem driver lacks
+    Unsupported,
+    /// The name isn't a valid 8.3 file name
+    InvalidName,
+    /// Too many volumes, directories or files are open at once
+    TooManyOpen,
+    /// A file was used as a directory or the other way round
+    WrongType,
+    /// Reading past the end of a file, or seeking outside it
+    OutOfRange,
+    /// The file was opened read-only
+    ReadOnly,
+    /// Anything else, such as a stale handle
+    Other,
+}
+
+impl FsErrorKind {
+    /// The kind of `error`
+    pub fn of<E: core::fmt::Debug>(error: &Error<E>) -> Self {
+        match error {
+            Error::DeviceError(_) | Error::BadBlockSize(_) => FsErrorKind::Device,
+            Error::NotFound => FsErrorKind::NotFound,
+            Error::FileAlreadyExists | Error::DirAlreadyExists => FsErrorKind::AlreadyExists,
+            Error::FileAlreadyOpen
+            | Error::DirAlreadyOpen
+            | Error::VolumeAlreadyOpen
+            | Error::VolumeStillInUse
+            | Error::LockError => FsErrorKind::InUse,
+            Error::DiskFull | Error::NotEnoughSpace => FsErrorKind::DiskFull,
+            Error::BadCluster
+            | Error::UnterminatedFatChain
+            | Error::AllocationError
+            | Error::FormatError(_)
+            | Error::ConversionError => FsErrorKind::Corrupt,
+            Error::NoSuchVolume | Error::Unsupported => FsErrorKind::Unsupported,
+            Error::FilenameError(_) => FsErrorKind::InvalidName,
+            Error::TooManyOpenVolumes | Error::TooManyOpenDirs | Error::TooManyOpenFiles => {
+                FsErrorKind::TooManyOpen
+            }
+            Error::OpenedDirAsFile | Error::OpenedFileAsDir | Error::DeleteDirAsFile => {
+                FsErrorKind::WrongType
+            }
+            Error::EndOfFile | Error::InvalidOffset => FsErrorKind::OutOfRange,
+            Error::ReadOnly => FsErrorKind::ReadOnly,
+            Error::BadHandle => FsErrorKind::Other,
+        }
+    }
+}
+
+impl<E: core::fmt::Debug> From<&Error<E>> for FsErrorKind {
+    fn from(error: &Error<E>) -> Self {
+        FsErrorKind::of(error)
+    }
+}
@@ -0,0 +1,106 @@
+//! Glue between the portable helpers and esp-hal peripherals
+//!
+//! Only built with the `esp` feature. These used to be at the crate root,
+//! where deprecated forwarding functions remain for one release.
+
+use embedded_sdmmc::FilenameError;
+use esp_hal::{rng::Rng, rtc_cntl::Rtc};
+
+use crate::{
+    generate_dated_filename, generate_filename_with, generate_random_filename_with,
+    generate_token_filename_with, rtc_time::SettableClock, try_retry_with_jitter_with, RetryConfig,
+    RtcTimeSource, TimeSourceExt, DEFAULT_JITTER_PERCENT,
+};
+
+/// Generate random 8.3 filename (e.g., "ABC12345.CSV")
+/// Note: This is the max length for a filename in this filesystem.
+pub fn generate_random_filename(rng: &mut Rng, filename: &mut [u8; 12]) {
+    generate_random_filename_with(|| rng.random(), filename);
+}
+
+/// Generate an 8.3 filename of `prefix`, random characters and `extension`,
+/// e.g. "LOGK3Z9Q.BIN"; see [`generate_filename_with`]
+pub fn generate_filename<'a>(
+    rng: &mut Rng,
+    prefix: &str,
+    extension: &str,
+    filename: &'a mut [u8; 12],
+) -> Result<&'a str, FilenameError> {
+    generate_filename_with(|| rng.random(), prefix, extension, filename)
+}
+
+/// Generate an 8.3 filename with a base-36 stem of `token_len` characters,
+/// returns bytes written; see [`generate_token_filename_with`]
+pub fn generate_token_filename(rng: &mut Rng, filename: &mut [u8; 12], token_len: usize) -> usize {
+    generate_token_filename_with(|| rng.random(), filename, token_len)
+}
+
+/// Generate a dated filename if `time_source` has real time, otherwise a
+/// random one from [`generate_filename`], and return it
+pub fn generate_filename_for<'a, S: TimeSourceExt>(
+    time_source: &S,
+    rng: &mut Rng,
+    filename: &'a mut [u8; 12],
+) -> &'a str {
+    if time_source.is_real() {
+        generate_dated_filename(&time_source.get_timestamp(), filename);
+        // Only digits and ".CSV" were written
+        return core::str::from_utf8(filename).unwrap_or_default();
+    }
+    // An empty prefix and "CSV" are always valid
+    generate_filename(rng, "", "CSV", filename).unwrap_or_default()
+}
+
+/// Like [`crate::retry_with_backoff`], with each 500 ms delay spread by up to
+/// ±[`DEFAULT_JITTER_PERCENT`] percent using `rng`, so boards sharing a
+/// power rail don't retry in lockstep
+pub async fn retry_with_jitter<T, E, F, Fut>(
+    rng: &mut Rng,
+    operation_name: &str,
+    operation: F,
+) -> Option<T>
+where
+    F: FnMut() -> Fut,
+    Fut: core::future::Future<Output = Result<T, E>>,
+    E: core::fmt::Debug,
+{
+    let config = RetryConfig::DEFAULT.with_jitter(DEFAULT_JITTER_PERCENT);
+    try_retry_with_jitter(config, rng, operation_name, operation)
+        .await
+        .ok()
+}
+
+/// Like [`crate::try_retry_with_backoff_cfg`], jittering each delay by
+/// `config.jitter_percent` using `rng`; see [`try_retry_with_jitter_with`]
+pub async fn try_retry_with_jitter<T, E, F, Fut>(
+    config: RetryConfig,
+    rng: &mut Rng,
+    operation_name: &str,
+    operation: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: core::future::Future<Output = Result<T, E>>,
+    E: core::fmt::Debug,
+{
+    try_retry_with_jitter_with(config, || rng.random(), operation_name, operation).await
+}
+
+impl SettableClock for Rtc<'_> {
+    fn now_us(&self) -> u64 {
+        self.current_time_us()
+    }
+
+    fn set_us(&self, us: u64) {
+        self.set_current_time_us(us);
+    }
+}
+
+impl<'a> RtcTimeSource<'a> {
+    /// Read the RTC, which [`RtcTimeSource::set_epoch`] sets. The RTC keeps
+    /// counting through deep sleep and software resets, so a time set once
+    /// survives them.
+    pub const fn from_rtc(rtc: &'a Rtc<'a>) -> Self {
+        Self::from_settable(rtc)
+    }
+}
@@ -0,0 +1,199 @@
+//! Pulling a file off the card over the serial console
+//!
+//! For field technicians without a card reader. CSV files are printed as
+//! they are. Binary files are base64 encoded in fixed-width lines between
+//! `-----BEGIN <name>-----` and `-----END <name>-----`, followed (inside the
+//! markers) by a `CRC32 xxxxxxxx` line over the decoded bytes, so the output
+//! can be copied from a terminal and checked without a transfer protocol.
+
+use core::fmt::Write;
+
+use embedded_sdmmc::{BlockDevice, Directory, Error, Mode, TimeSource};
+
+use crate::checksum::Crc32;
+use crate::YieldBudget;
+
+/// Input bytes per base64 line; 57 bytes make the usual 76-character lines
+const BASE64_LINE_BYTES: usize = 57;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// How [`export_over_serial`] writes the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportEncoding {
+    /// The contents as text, for CSV and other text files; bytes that aren't
+    /// valid UTF-8, including a character cut off by the end of the file,
+    /// are replaced with U+FFFD
+    #[default]
+    PlainText,
+    /// Base64 lines between BEGIN/END markers with a CRC-32 line, for
+    /// binary files
+    Base64Framed,
+}
+
+/// Errors returned by [`export_over_serial`]
+#[derive(Debug, Clone)]
+pub enum ExportError<E: core::fmt::Debug> {
+    /// The file could not be read
+    Fs(Error<E>),
+    /// The writer refused the output
+    Format,
+    /// The budget's [`crate::CancelToken`] was cancelled after `bytes` bytes
+    /// of the file were written out; framed output lacks its CRC and END lines
+    Cancelled {
+        /// Bytes of the file exported before stopping
+        bytes: u32,
+    },
+}
+
+impl<E: core::fmt::Debug> From<Error<E>> for ExportError<E> {
+    fn from(e: Error<E>) -> Self {
+        ExportError::Fs(e)
+    }
+}
+
+impl<E: core::fmt::Debug> From<core::fmt::Error> for ExportError<E> {
+    fn from(_: core::fmt::Error) -> Self {
+        ExportError::Format
+    }
+}
+
+/// Write the contents of `name` to `writer` (e.g. the serial console) using
+/// `encoding`, returning the number of bytes read from the file.
+///
+/// The file is read through `scratch`, yielding to the executor as `budget`
+/// is spent so other tasks keep running during long exports, and stopping
+/// with [`ExportError::Cancelled`] if the budget is cancelled. `scratch`
+/// needs at least 4 bytes so a character split between two reads is kept
+/// whole.
+///
+/// Wired to a command typed on the console, e.g. `EXPORT LOG00017.CSV`:
+///
+/// ```text
+/// if let Some(name) = command.trim().strip_prefix("EXPORT ") {
+///     let encoding = if name.ends_with(".CSV") {
+///         ExportEncoding::PlainText
+///     } else {
+///         ExportEncoding::Base64Framed
+///     };
+///     let mut budget = YieldBudget::new(4096);
+///     let name = name.trim();
+///     if let Err(e) =
+///         export_over_serial(&root_dir, name, &mut console, encoding, &mut scratch, &mut budget)
+///             .await
+///     {
+///         println!("EXPORT failed: {:?}", e);
+///     }
+/// }
+/// ```
+pub async fn export_over_serial<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    name: &str,
+    writer: &mut dyn Write,
+    encoding: ExportEncoding,
+    scratch: &mut [u8],
+    budget: &mut YieldBudget,
+) -> Result<u32, ExportError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let file = dir.open_file_in_dir(name, Mode::ReadOnly)?;
+    let framed = encoding == ExportEncoding::Base64Framed;
+    if framed {
+        writeln!(writer, "-----BEGIN {}-----", name)?;
+    }
+    let mut line = [0u8; BASE64_LINE_BYTES];
+    let mut line_len = 0;
+    let mut crc = Crc32::new();
+    let mut total = 0u32;
+    // Start of a character split by the previous read, kept at the front
+    let mut carry = 0;
+    loop {
+        let n = file.read(&mut scratch[carry..])?;
+        if n == 0 {
+            break;
+        }
+        total += n as u32;
+        if framed {
+            crc.update(&scratch[..n]);
+            for &byte in &scratch[..n] {
+                line[line_len] = byte;
+                line_len += 1;
+                if line_len == line.len() {
+                    write_base64_line(writer, &line)?;
+                    line_len = 0;
+                }
+            }
+        } else {
+            carry = write_text(writer, &mut scratch[..carry + n])?;
+        }
+        budget.spend_bytes(n as u32).await;
+        if budget.is_cancelled() {
+            file.close()?;
+            return Err(ExportError::Cancelled { bytes: total });
+        }
+    }
+    file.close()?;
+    if carry > 0 {
+        writer.write_char(char::REPLACEMENT_CHARACTER)?;
+    }
+    if framed {
+        if line_len > 0 {
+            write_base64_line(writer, &line[..line_len])?;
+        }
+        writeln!(writer, "CRC32 {:08x}", crc.finish())?;
+        writeln!(writer, "-----END {}-----", name)?;
+    }
+    Ok(total)
+}
+
+/// Write `data` as text, replacing invalid UTF-8 with U+FFFD, except for an
+/// incomplete character at the end, which is moved to the front of `data`
+/// to be finished by the next read. Returns the length of that tail.
+fn write_text(writer: &mut dyn Write, data: &mut [u8]) -> Result<usize, core::fmt::Error> {
+    let mut tail = 0;
+    let mut chunks = data.utf8_chunks().peekable();
+    while let Some(chunk) = chunks.next() {
+        writer.write_str(chunk.valid())?;
+        let invalid = chunk.invalid();
+        if invalid.is_empty() {
+            continue;
+        }
+        // The last chunk's invalid bytes may just be a character cut short
+        let incomplete = core::str::from_utf8(invalid).is_err_and(|e| e.error_len().is_none());
+        if chunks.peek().is_none() && incomplete {
+            tail = invalid.len();
+        } else {
+            writer.write_char(char::REPLACEMENT_CHARACTER)?;
+        }
+    }
+    let len = data.len();
+    data.copy_within(len - tail.., 0);
+    Ok(tail)
+}
+
+/// Write `data` base64 encoded, with padding, followed by a newline
+fn write_base64_line(writer: &mut dyn Write, data: &[u8]) -> core::fmt::Result {
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+            bits | u32::from(byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= group.len() {
+                let index = (bits >> (18 - 6 * i)) & 0x3F;
+                writer.write_char(char::from(BASE64_ALPHABET[index as usize]))?;
+            } else {
+                writer.write_char('=')?;
+            }
+        }
+    }
+    writer.write_char('\n')
+}
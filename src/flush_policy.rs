@@ -0,0 +1,124 @@
+//! Flushing less often while the card is slow
+//!
+//! A card that is busy with internal housekeeping takes longer for every
+//! flush, and flushing at the usual rate only queues more work behind it.
+//! [`AdaptiveFlushPolicy`] stretches the interval between flushes while
+//! they are slow and shrinks it back once they are fast again.
+//! [`AdaptiveFlushPolicy::for_max_loss`] instead fixes it at the longest
+//! interval a durability requirement allows, to save energy on battery or
+//! solar nodes.
+
+use embassy_time::Duration;
+
+/// Most data that may be lost if power fails between flushes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaxDataLoss {
+    /// At most this many rows
+    Rows(u32),
+    /// At most the rows logged within this long
+    Time(Duration),
+}
+
+impl MaxDataLoss {
+    /// Rows to write between flushes, logging one row every `row_period`:
+    /// the longest interval that stays within the limit, at least 1.
+    /// Everything written since the last flush is lost on power failure, so
+    /// flushing every N rows risks up to N rows.
+    pub fn flush_interval(&self, row_period: Duration) -> u32 {
+        let rows = match *self {
+            MaxDataLoss::Rows(rows) => u64::from(rows),
+            MaxDataLoss::Time(time) => time
+                .as_ticks()
+                .checked_div(row_period.as_ticks())
+                .unwrap_or(1),
+        };
+        rows.clamp(1, u64::from(u32::MAX)) as u32
+    }
+}
+
+/// Decides when to flush from the row count and how long recent flushes took
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveFlushPolicy {
+    base_rows: u32,
+    max_rows: u32,
+    slow_threshold: Duration,
+    interval: u32,
+    rows_since_flush: u32,
+}
+
+impl AdaptiveFlushPolicy {
+    /// Flush every `base_rows` rows (at least 1) while flushes are fast,
+    /// backing off to at most 8 times that
+    pub const fn new(base_rows: u32) -> Self {
+        let base_rows = if base_rows == 0 { 1 } else { base_rows };
+        Self {
+            base_rows,
+            max_rows: base_rows.saturating_mul(8),
+            slow_threshold: Duration::from_millis(250),
+            interval: base_rows,
+            rows_since_flush: 0,
+        }
+    }
+
+    /// Flush as rarely as `max_loss` allows, logging one row every
+    /// `row_period`; see [`MaxDataLoss::flush_interval`]. The interval never
+    /// backs off past that, however slow flushes get, since that would risk
+    /// more data.
+    ///
+    /// ```text
+    /// // One row every 10 s, losing at most 5 minutes: flush every 30 rows
+    /// let max_loss = MaxDataLoss::Time(Duration::from_secs(300));
+    /// AdaptiveFlushPolicy::for_max_loss(max_loss, Duration::from_secs(10))
+    /// ```
+    pub fn for_max_loss(max_loss: MaxDataLoss, row_period: Duration) -> Self {
+        let rows = max_loss.flush_interval(row_period);
+        Self::new(rows).with_max_rows(rows)
+    }
+
+    /// Never let the interval grow past `max_rows` rows
+    pub fn with_max_rows(mut self, max_rows: u32) -> Self {
+        self.max_rows = max_rows.max(self.base_rows);
+        self.interval = self.interval.min(self.max_rows);
+        self
+    }
+
+    /// A flush taking longer than `slow_threshold` doubles the interval; one
+    /// taking under half of it halves the interval, down to the base
+    pub fn with_slow_threshold(mut self, slow_threshold: Duration) -> Self {
+        self.slow_threshold = slow_threshold;
+        self
+    }
+
+    /// Rows currently written between flushes
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    /// Count a written row, returning true if it is time to flush
+    pub fn row_written(&mut self) -> bool {
+        self.rows_since_flush += 1;
+        if self.rows_since_flush < self.interval {
+            return false;
+        }
+        self.rows_since_flush = 0;
+        true
+    }
+
+    /// Adjust the interval for a flush that took `took`, e.g.
+    /// [`crate::FlushOutcome::took`]
+    pub fn record_flush(&mut self, took: Duration) {
+        let previous = self.interval;
+        if took > self.slow_threshold {
+            self.interval = self.interval.saturating_mul(2).min(self.max_rows);
+        } else if took * 2 < self.slow_threshold {
+            self.interval = (self.interval / 2).max(self.base_rows);
+        }
+        if self.interval != previous && crate::verbosity() == crate::Verbosity::Verbose {
+            println!(
+                "Flush took {} ms, flushing every {} rows",
+                took.as_millis(),
+                self.interval
+            );
+        }
+    }
+}
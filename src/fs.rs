@@ -0,0 +1,563 @@
+//! File helpers built on embedded-sdmmc directories
+
+use embedded_sdmmc::filesystem::ToShortFileName;
+use embedded_sdmmc::{BlockDevice, Directory, Error, File, Mode, TimeSource};
+
+use crate::{CancellableError, YieldBudget};
+
+/// Read a whole file through `buffer`, handing each chunk to `on_chunk`.
+/// Returns the number of bytes read.
+pub fn read_file_chunks<
+    D,
+    T,
+    F,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    name: &str,
+    buffer: &mut [u8],
+    on_chunk: F,
+) -> Result<u32, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    F: FnMut(&[u8]),
+{
+    read_file_chunks_with_progress(dir, name, buffer, on_chunk, |_, _| {})
+}
+
+/// Like [`read_file_chunks`], but also calls `on_progress(bytes_read, total_bytes)`
+/// after each chunk so callers can drive a progress bar or throttle a transfer.
+/// `total_bytes` is the file length when it was opened.
+pub fn read_file_chunks_with_progress<
+    D,
+    T,
+    F,
+    P,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    name: &str,
+    buffer: &mut [u8],
+    mut on_chunk: F,
+    mut on_progress: P,
+) -> Result<u32, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    F: FnMut(&[u8]),
+    P: FnMut(u32, u32),
+{
+    let file = dir.open_file_in_dir(name, Mode::ReadOnly)?;
+    let total = file.length();
+    let mut bytes_read = 0u32;
+    loop {
+        let n = file.read(buffer)?;
+        if n == 0 {
+            break;
+        }
+        bytes_read += n as u32;
+        on_chunk(&buffer[..n]);
+        on_progress(bytes_read, total);
+    }
+    file.close()?;
+    Ok(bytes_read)
+}
+
+/// What [`copy_file`] does when the destination already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CopyMode {
+    /// Fail with `Error::FileAlreadyExists`
+    #[default]
+    FailIfExists,
+    /// Replace the existing file's contents
+    Overwrite,
+}
+
+/// Copy `src` to `dst` in the same directory, streaming through `buffer`
+/// and spending `budget` on every chunk so other tasks keep running.
+/// Returns the number of bytes copied.
+///
+/// If `budget` is cancelled the copy stops with
+/// [`CancellableError::Cancelled`], leaving `dst` holding the bytes copied
+/// so far; copying again with [`CopyMode::Overwrite`] starts it over.
+///
+/// Use a multiple of 512 bytes for `buffer` so each read and write covers
+/// whole blocks.
+pub async fn copy_file<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    src: &str,
+    dst: &str,
+    buffer: &mut [u8],
+    mode: CopyMode,
+    budget: &mut YieldBudget,
+) -> Result<u32, CancellableError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    copy_file_between(dir, src, dir, dst, buffer, mode, budget).await
+}
+
+/// Like [`copy_file`], but `dst` is created in `dst_dir`, which may differ
+/// from `src_dir`. The copy gets a fresh modification time.
+pub async fn copy_file_between<
+    D,
+    T,
+    S,
+    N,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    src_dir: &Directory<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    src: S,
+    dst_dir: &Directory<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    dst: N,
+    buffer: &mut [u8],
+    mode: CopyMode,
+    budget: &mut YieldBudget,
+) -> Result<u32, CancellableError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    S: ToShortFileName,
+    N: ToShortFileName,
+{
+    let input = src_dir.open_file_in_dir(src, Mode::ReadOnly)?;
+    let output = dst_dir.open_file_in_dir(
+        dst,
+        match mode {
+            CopyMode::FailIfExists => Mode::ReadWriteCreate,
+            CopyMode::Overwrite => Mode::ReadWriteCreateOrTruncate,
+        },
+    )?;
+    let mut copied = 0u32;
+    loop {
+        let n = input.read(buffer)?;
+        if n == 0 {
+            break;
+        }
+        output.write(&buffer[..n])?;
+        copied += n as u32;
+        budget.spend_bytes(n as u32).await;
+        if budget.is_cancelled() {
+            break;
+        }
+    }
+    output.close()?;
+    input.close()?;
+    if budget.is_cancelled() {
+        return Err(CancellableError::Cancelled { bytes: copied });
+    }
+    Ok(copied)
+}
+
+/// Build `name` with its extension replaced by `extension` into `buffer`,
+/// e.g. "LOG00001.CSV" with "CRC" gives "LOG00001.CRC". Returns None if the
+/// base name is empty or longer than 8 characters, or `extension` is longer
+/// than 3.
+pub fn replace_extension<'a>(
+    name: &str,
+    extension: &str,
+    buffer: &'a mut [u8; 12],
+) -> Option<&'a str> {
+    let base = name.split('.').next().unwrap_or(name);
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+    let len = base.len() + 1 + extension.len();
+    buffer[..base.len()].copy_from_slice(base.as_bytes());
+    buffer[base.len()] = b'.';
+    buffer[base.len() + 1..len].copy_from_slice(extension.as_bytes());
+    core::str::from_utf8(&buffer[..len]).ok()
+}
+
+/// Flush every file, even if some fail, and return the first error.
+///
+/// Works with embedded-sdmmc files or anything else implementing
+/// [`embedded_io::Write`], such as a [`crate::CsvWriter`]'s underlying file.
+pub fn flush_all<W: embedded_io::Write>(files: &mut [&mut W]) -> Result<(), W::Error> {
+    let mut first_error = None;
+    for file in files.iter_mut() {
+        if let Err(e) = file.flush() {
+            first_error.get_or_insert(e);
+        }
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+/// Create `name` if it is missing, otherwise update its modification time
+/// without changing its contents.
+///
+/// The timestamp comes from the `TimeSource` the `VolumeManager` was built
+/// with, so with [`crate::DummyTimeSource`] every touch records 1970-01-01.
+/// Non-empty files get a zero-byte write, which marks the entry dirty and
+/// restamps it on close. Empty files are recreated instead, because a write
+/// to a file with no clusters would allocate one.
+pub fn touch_file<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    name: &str,
+) -> Result<(), Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    match dir.find_directory_entry(name) {
+        Ok(entry) if entry.size > 0 => {
+            let file = dir.open_file_in_dir(name, Mode::ReadWriteAppend)?;
+            file.write(&[])?;
+            file.close()
+        }
+        Ok(_) => {
+            dir.delete_file_in_dir(name)?;
+            dir.open_file_in_dir(name, Mode::ReadWriteCreate)?.close()
+        }
+        Err(Error::NotFound) => dir.open_file_in_dir(name, Mode::ReadWriteCreate)?.close(),
+        Err(e) => Err(e),
+    }
+}
+
+/// Root directory entries on a typical FAT16 volume. FAT32 roots grow like
+/// any other directory, but FAT16 roots are fixed-size and creating a file
+/// in a full one fails.
+pub const FAT16_ROOT_ENTRIES: usize = 512;
+
+/// Which directory [`use_subdir_when_full`] left active
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActiveDir {
+    /// The directory passed in still has room
+    Original,
+    /// The directory was full, so the handle now points at the subdirectory
+    Subdir,
+}
+
+/// Count the entries (files, directories and the volume label) in `dir`
+pub fn count_dir_entries<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+) -> Result<usize, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut count = 0;
+    dir.iterate_dir(|_| count += 1)?;
+    Ok(count)
+}
+
+/// If `dir` holds `max_entries` or more entries, create `subdir` (if needed)
+/// and switch `dir` to it so further files go there.
+///
+/// Use a `max_entries` a little below the real cap (e.g. 500 for a FAT16 root
+/// of [`FAT16_ROOT_ENTRIES`]) to leave room for config and marker files.
+pub fn use_subdir_when_full<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &mut Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    max_entries: usize,
+    subdir: &str,
+) -> Result<ActiveDir, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    if count_dir_entries(dir)? < max_entries {
+        return Ok(ActiveDir::Original);
+    }
+    match dir.make_dir_in_dir(subdir) {
+        Ok(()) | Err(Error::DirAlreadyExists) => {}
+        Err(e) => return Err(e),
+    }
+    dir.change_dir(subdir)?;
+    Ok(ActiveDir::Subdir)
+}
+
+/// Create a file that must not already exist, trying a new name on collision.
+///
+/// `filename` holds the first candidate and, on success, the name actually
+/// created. If it is taken, `next_name` rewrites it (e.g. with
+/// [`crate::esp::generate_random_filename`], [`bump_filename`] or
+/// [`suffix_filename`]) and creation is retried, up to `max_attempts` names in total. Files are only ever created
+/// with [`Mode::ReadWriteCreate`], so an existing log is never appended to by
+/// accident; open with [`Mode::ReadWriteCreateOrAppend`] yourself to resume one.
+/// Returns `Error::FileAlreadyExists` if every candidate was taken.
+pub fn create_new_file<
+    'a,
+    D,
+    T,
+    F,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &'a Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    filename: &mut [u8; 12],
+    max_attempts: u32,
+    mut next_name: F,
+) -> Result<File<'a, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    F: FnMut(&mut [u8; 12]),
+{
+    for attempt in 1..=max_attempts {
+        let name = core::str::from_utf8(filename)
+            .map_err(|_| Error::FilenameError(embedded_sdmmc::FilenameError::InvalidCharacter))?
+            .trim_end_matches('\0');
+        match dir.open_file_in_dir(name, Mode::ReadWriteCreate) {
+            Err(Error::FileAlreadyExists) if attempt < max_attempts => next_name(filename),
+            result => return result,
+        }
+    }
+    Err(Error::FileAlreadyExists)
+}
+
+/// What [`trim_partial_row`] did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrimOutcome {
+    /// The file now ends after its last complete row, `bytes` having been
+    /// cut off (0 if it already did)
+    Trimmed {
+        /// Bytes cut off
+        bytes: u32,
+    },
+    /// The file ends in a partial row but trimming it would copy more than
+    /// the limit, so it was left alone; start a new file instead
+    TooLarge {
+        /// Bytes of complete rows that trimming would have copied
+        keep: u32,
+    },
+}
+
+/// Drop a half-written last row from `name` before appending to it after a
+/// crash.
+///
+/// The file is scanned backwards from the end through `scratch` for the last
+/// newline and anything after it is cut off with [`crate::truncate_file`]
+/// (which also uses `scratch`). A file with no newline at all is emptied.
+/// Open it with [`Mode::ReadWriteCreateOrAppend`] afterwards and the cursor
+/// sits just past the last complete row.
+///
+/// embedded-sdmmc can't shrink a file in place, so however few bytes are cut
+/// off, truncating copies the whole kept part out to a temporary file and
+/// back: twice the file's size in I/O, which for a large log can take
+/// minutes, and free space for the copy, without which it fails on a nearly
+/// full card. If the kept part is longer than `max_copy` bytes the file is
+/// left untouched and [`TrimOutcome::TooLarge`] returned, so the caller can
+/// start a new file instead; pass `u32::MAX` to always trim. The copy spends
+/// `budget` as it goes; if it is cancelled, see [`crate::truncate_file`]
+/// for how to finish.
+pub async fn trim_partial_row<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    name: &str,
+    scratch: &mut [u8],
+    max_copy: u32,
+    budget: &mut YieldBudget,
+) -> Result<TrimOutcome, CancellableError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    if scratch.is_empty() {
+        return Err(Error::NotEnoughSpace.into());
+    }
+    let file = dir.open_file_in_dir(name, Mode::ReadOnly)?;
+    let length = file.length();
+    let mut end = length;
+    let keep = loop {
+        if end == 0 {
+            break 0;
+        }
+        let start = end.saturating_sub(scratch.len() as u32);
+        let window = &mut scratch[..(end - start) as usize];
+        file.seek_from_start(start)?;
+        let mut filled = 0;
+        while filled < window.len() {
+            let n = file.read(&mut window[filled..])?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        if let Some(i) = window[..filled].iter().rposition(|&b| b == b'\n') {
+            break start + i as u32 + 1;
+        }
+        end = start;
+    };
+    file.close()?;
+
+    if keep < length {
+        if keep > max_copy {
+            return Ok(TrimOutcome::TooLarge { keep });
+        }
+        crate::truncate_file(dir, name, keep, scratch, |_, _| {}, budget).await?;
+    }
+    Ok(TrimOutcome::Trimmed {
+        bytes: length - keep,
+    })
+}
+
+/// Increment the number at the end of an 8.3 base name, e.g. "LOG00017.CSV"
+/// becomes "LOG00018.CSV". Returns false (leaving `filename` unchanged) if the
+/// base name doesn't end in a digit or the number would overflow its width.
+pub fn bump_filename(filename: &mut [u8; 12]) -> bool {
+    let base_len = filename.iter().position(|&b| b == b'.').unwrap_or(8).min(8);
+    let mut next = *filename;
+    for digit in next[..base_len].iter_mut().rev() {
+        match *digit {
+            b'0'..=b'8' => {
+                *digit += 1;
+                *filename = next;
+                return true;
+            }
+            b'9' => *digit = b'0',
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// Step the letter at the end of an 8.3 base name, as [`unique_suffixed_name`]
+/// does: a final digit becomes 'A' and a letter the next one, so
+/// "07141530.CSV" becomes "0714153A.CSV", then "0714153B.CSV". Unlike
+/// [`crate::esp::generate_random_filename`] this keeps a dated name
+/// recognisable. Returns false (leaving `filename` unchanged) after 'Z', or
+/// if the base name ends in anything else.
+pub fn suffix_filename(filename: &mut [u8; 12]) -> bool {
+    let base_len = filename.iter().position(|&b| b == b'.').unwrap_or(8).min(8);
+    let Some(last) = base_len.checked_sub(1).map(|i| &mut filename[i]) else {
+        return false;
+    };
+    match *last {
+        b'0'..=b'9' => *last = b'A',
+        b'A'..=b'Y' => *last += 1,
+        _ => return false,
+    }
+    true
+}
+
+/// Find a free name in `dir` for `base` + `extension`, adding a rolling
+/// letter suffix (A, B, ... Z) if the plain name is taken, and write it to
+/// `buffer`. E.g. "0714153" + "CSV" gives "0714153.CSV", then "0714153A.CSV".
+///
+/// Suffixes are appended while `base` is shorter than 8 characters;
+/// otherwise they replace its last character. Returns
+/// `Error::FileAlreadyExists` if all 27 names are taken. Create the file with
+/// [`create_new_file`] so a name taken between this check and the create is
+/// still caught.
+pub fn unique_suffixed_name<
+    'a,
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    base: &str,
+    extension: &str,
+    buffer: &'a mut [u8; 12],
+) -> Result<&'a str, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return Err(Error::FilenameError(
+            embedded_sdmmc::FilenameError::NameTooLong,
+        ));
+    }
+    let suffix_at = base.len().min(7);
+    let mut candidate = [0u8; 12];
+    for suffix in core::iter::once(None).chain((b'A'..=b'Z').map(Some)) {
+        let base_len = match suffix {
+            None => {
+                candidate[..base.len()].copy_from_slice(base.as_bytes());
+                base.len()
+            }
+            Some(letter) => {
+                candidate[..suffix_at].copy_from_slice(&base.as_bytes()[..suffix_at]);
+                candidate[suffix_at] = letter;
+                suffix_at + 1
+            }
+        };
+        let len = base_len + 1 + extension.len();
+        candidate[base_len] = b'.';
+        candidate[base_len + 1..len].copy_from_slice(extension.as_bytes());
+        let name = core::str::from_utf8(&candidate[..len])
+            .map_err(|_| Error::FilenameError(embedded_sdmmc::FilenameError::InvalidCharacter))?;
+        match dir.find_directory_entry(name) {
+            Err(Error::NotFound) => {
+                buffer[..len].copy_from_slice(&candidate[..len]);
+                return core::str::from_utf8(&buffer[..len]).map_err(|_| {
+                    Error::FilenameError(embedded_sdmmc::FilenameError::InvalidCharacter)
+                });
+            }
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Err(Error::FileAlreadyExists)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suffix_steps_through_the_alphabet() {
+        let mut filename = *b"07141530.CSV";
+        assert!(suffix_filename(&mut filename));
+        assert_eq!(&filename, b"0714153A.CSV");
+        assert!(suffix_filename(&mut filename));
+        assert_eq!(&filename, b"0714153B.CSV");
+        filename[7] = b'Z';
+        assert!(!suffix_filename(&mut filename));
+        assert_eq!(&filename, b"0714153Z.CSV");
+    }
+
+    #[test]
+    fn suffix_of_a_short_base_name() {
+        let mut filename = *b"LOG1.CSV\0\0\0\0";
+        assert!(suffix_filename(&mut filename));
+        assert_eq!(&filename, b"LOGA.CSV\0\0\0\0");
+        let mut filename = *b".CSV\0\0\0\0\0\0\0\0";
+        assert!(!suffix_filename(&mut filename));
+    }
+
+    #[test]
+    fn bump_carries_into_the_next_digit() {
+        let mut filename = *b"LOG00019.CSV";
+        assert!(bump_filename(&mut filename));
+        assert_eq!(&filename, b"LOG00020.CSV");
+        let mut filename = *b"LOG99999.CSV";
+        assert!(!bump_filename(&mut filename));
+        assert_eq!(&filename, b"LOG99999.CSV");
+    }
+}
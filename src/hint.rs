@@ -0,0 +1,57 @@
+//! Human-readable advice for common card errors
+
+use embedded_sdmmc::{Error, SdCardError};
+
+/// A short, actionable hint for an error, printed by [`crate::retry_with_hint`]
+/// once every attempt has failed
+pub trait ErrorHint {
+    /// Advice on what to check, or None if there is nothing specific to say
+    fn hint(&self) -> Option<&'static str>;
+}
+
+impl ErrorHint for SdCardError {
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            SdCardError::Transport | SdCardError::GpioError => {
+                Some("check the SPI wiring and that the pins match your board")
+            }
+            SdCardError::CardNotFound
+            | SdCardError::TimeoutCommand(_)
+            | SdCardError::TimeoutACommand(_)
+            | SdCardError::TimeoutReadBuffer
+            | SdCardError::TimeoutWaitNotBusy => Some(
+                "no response from the card: check it is inserted, the CS wiring and 3.3V power",
+            ),
+            SdCardError::CantEnableCRC | SdCardError::CrcError(_, _) => {
+                Some("data is corrupted on the bus: shorten the wires or lower the SPI frequency")
+            }
+            SdCardError::Cmd58Error | SdCardError::RegisterReadError => {
+                Some("the card answered unexpectedly: try another card")
+            }
+            SdCardError::ReadError | SdCardError::WriteError => {
+                Some("the card reported a read/write failure: it may be worn out or locked")
+            }
+            SdCardError::BadState => Some("the card is in an unexpected state: power-cycle it"),
+        }
+    }
+}
+
+impl<E: ErrorHint + core::fmt::Debug> ErrorHint for Error<E> {
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            Error::DeviceError(e) => e.hint(),
+            Error::FormatError(_) | Error::Unsupported => {
+                Some("the card is not FAT16/FAT32: reformat it as FAT32")
+            }
+            Error::NoSuchVolume => {
+                Some("no partition found: reformat the card as FAT32 with an MBR partition table")
+            }
+            Error::TooManyOpenVolumes | Error::TooManyOpenDirs | Error::TooManyOpenFiles => {
+                Some("too many open handles: close files and directories you no longer need")
+            }
+            Error::NotEnoughSpace | Error::DiskFull => Some("the card is full: delete old logs"),
+            Error::FilenameError(_) => Some("use an 8.3 name such as LOG00001.CSV"),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Human-readable byte counts and transfer rates without floating point
+
+use core::fmt;
+
+/// Which prefixes [`format_bytes`] and [`format_rate`] use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Units {
+    /// Powers of 1024: KiB, MiB, GiB, ...
+    #[default]
+    Binary,
+    /// Powers of 1000: KB, MB, GB, ...
+    Decimal,
+}
+
+impl Units {
+    fn base(self) -> u128 {
+        match self {
+            Units::Binary => 1024,
+            Units::Decimal => 1000,
+        }
+    }
+
+    fn prefixes(self) -> [&'static str; 7] {
+        match self {
+            Units::Binary => ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"],
+            Units::Decimal => ["B", "KB", "MB", "GB", "TB", "PB", "EB"],
+        }
+    }
+}
+
+/// Displays a byte count like "29.7 GiB"; use `{:#}` for decimal units ("31.9 GB")
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HumanBytes(pub u64);
+
+impl fmt::Display for HumanBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units = units_for(f);
+        write_human(f, self.0, units, "")
+    }
+}
+
+/// Displays a rate in bytes per second like "120.6 KiB/s"; use `{:#}` for decimal units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HumanRate(pub u64);
+
+impl fmt::Display for HumanRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units = units_for(f);
+        write_human(f, self.0, units, "/s")
+    }
+}
+
+/// Format `bytes` with one decimal and the largest fitting prefix, e.g.
+/// "29.7 GiB" or "512 B". Returns bytes written (0 if `buffer` is too small).
+pub fn format_bytes(buffer: &mut [u8], bytes: u64, units: Units) -> usize {
+    format_into(buffer, bytes, units, "")
+}
+
+/// Like [`format_bytes`] for a rate in bytes per second, e.g. "120.6 KiB/s"
+pub fn format_rate(buffer: &mut [u8], bytes_per_sec: u64, units: Units) -> usize {
+    format_into(buffer, bytes_per_sec, units, "/s")
+}
+
+fn units_for(f: &fmt::Formatter<'_>) -> Units {
+    if f.alternate() {
+        Units::Decimal
+    } else {
+        Units::Binary
+    }
+}
+
+fn format_into(buffer: &mut [u8], value: u64, units: Units, suffix: &str) -> usize {
+    let mut out = SliceWriter { buffer, len: 0 };
+    match write_human(&mut out, value, units, suffix) {
+        Ok(()) => out.len,
+        Err(_) => 0,
+    }
+}
+
+fn write_human(out: &mut dyn fmt::Write, value: u64, units: Units, suffix: &str) -> fmt::Result {
+    let base = units.base();
+    let prefixes = units.prefixes();
+    let value = u128::from(value);
+    if value < base {
+        return write!(out, "{} B{}", value, suffix);
+    }
+
+    let mut exponent = 1;
+    let mut divisor = base;
+    while exponent + 1 < prefixes.len() && value >= divisor * base {
+        exponent += 1;
+        divisor *= base;
+    }
+    // Round to the nearest tenth; 1023.96 KiB rounds to 1024.0, so show 1.0 MiB instead
+    let mut tenths = (value * 10 + divisor / 2) / divisor;
+    if tenths >= base * 10 && exponent + 1 < prefixes.len() {
+        exponent += 1;
+        divisor *= base;
+        tenths = (value * 10 + divisor / 2) / divisor;
+    }
+    write!(
+        out,
+        "{}.{} {}{}",
+        tenths / 10,
+        tenths % 10,
+        prefixes[exponent],
+        suffix
+    )
+}
+
+/// `fmt::Write` into a fixed buffer, failing once it is full
+struct SliceWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl fmt::Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.buffer
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+//! Noticing a card swapped for another while the device was off
+//!
+//! Resuming a log or reading state kept on the card assumes it is the same
+//! card as last time. [`IdentityPin`] brands each card with a marker file,
+//! [`IDENTITY_FILE`], naming the device and a random card ID, and on later
+//! boots compares it with the identity the caller saved off the card (e.g.
+//! in flash or NVS). The marker is one line of hex:
+//!
+//! ```text
+//! device=0000002a card=9f31c4e0
+//! ```
+
+use embedded_sdmmc::{BlockDevice, Directory, Error, Mode, TimeSource};
+
+use crate::checksum::write_hex;
+use crate::read_file_chunks;
+
+/// Name of the marker file in the root directory
+pub const IDENTITY_FILE: &str = "CARDID.TXT";
+
+/// Directory to log into when [`OnMismatch::Quarantine`] applies
+pub const QUARANTINE_DIR: &str = "FOREIGN";
+
+/// Length of a marker line: two 8-digit hex values, their keys and a newline
+const MARKER_LEN: usize = 30;
+
+/// Who branded a card, and which card it is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CardIdentity {
+    /// ID of the device that branded the card, chosen by the application
+    pub device_id: u32,
+    /// Random ID given to the card when it was branded
+    pub card_id: u32,
+}
+
+/// How the inserted card compares with the expected one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentityStatus {
+    /// The card is the one expected, or carries this device's marker when
+    /// no identity was expected
+    Match,
+    /// The card had no readable marker and has been branded
+    NewCard,
+    /// The card carries a marker from another device, or is another card
+    /// branded by this one
+    ForeignCard {
+        /// Identity found on the card
+        found: CardIdentity,
+    },
+}
+
+/// What to do with a [`IdentityStatus::ForeignCard`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnMismatch {
+    /// Don't log to it
+    #[default]
+    Refuse,
+    /// Log to [`QUARANTINE_DIR`], keeping the card's own data apart
+    Quarantine,
+    /// Brand it as this device's card and log as usual
+    Rebrand,
+}
+
+/// Where logging should go after [`IdentityPin::check`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardDecision {
+    /// Log as usual
+    Log,
+    /// Log to [`QUARANTINE_DIR`]
+    Quarantine,
+    /// Don't log to this card
+    Refuse,
+}
+
+/// Outcome of [`IdentityPin::check`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdentityReport {
+    /// How the card compared
+    pub status: IdentityStatus,
+    /// Identity on the card now, to save as the expected one next boot
+    pub identity: CardIdentity,
+    /// What to do, per the [`OnMismatch`] policy
+    pub decision: CardDecision,
+}
+
+/// Checks at startup that the card is the one this device used last
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdentityPin {
+    device_id: u32,
+    expected: Option<CardIdentity>,
+    on_mismatch: OnMismatch,
+}
+
+impl IdentityPin {
+    /// Check cards for this device, accepting any card it branded until an
+    /// expected identity is given
+    pub fn new(device_id: u32) -> Self {
+        Self {
+            device_id,
+            expected: None,
+            on_mismatch: OnMismatch::default(),
+        }
+    }
+
+    /// Expect the card identified by `identity`, as reported by an earlier
+    /// [`IdentityPin::check`]
+    pub fn with_expected(mut self, identity: CardIdentity) -> Self {
+        self.expected = Some(identity);
+        self
+    }
+
+    /// What to do with a foreign card (default [`OnMismatch::Refuse`])
+    pub fn with_on_mismatch(mut self, on_mismatch: OnMismatch) -> Self {
+        self.on_mismatch = on_mismatch;
+        self
+    }
+
+    /// Compare the card's marker in `root` with the expected identity,
+    /// branding the card with a card ID from `next_u32` if it has no marker
+    /// or is rebranded. Prints a line for the outcome unless
+    /// [`crate::Verbosity::Quiet`].
+    ///
+    /// A blank card is branded and logged to even when a different card was
+    /// expected; save [`IdentityReport::identity`] whenever it changes.
+    pub fn check<D, T, R, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+        &self,
+        root: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+        mut next_u32: R,
+    ) -> Result<IdentityReport, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+        R: FnMut() -> u32,
+    {
+        let mut brand = || -> Result<CardIdentity, Error<D::Error>> {
+            let identity = CardIdentity {
+                device_id: self.device_id,
+                card_id: next_u32(),
+            };
+            write_marker(root, &identity)?;
+            Ok(identity)
+        };
+
+        let report = match read_marker(root)? {
+            None => IdentityReport {
+                status: IdentityStatus::NewCard,
+                identity: brand()?,
+                decision: CardDecision::Log,
+            },
+            Some(found)
+                if found.device_id == self.device_id
+                    && self.expected.is_none_or(|expected| expected == found) =>
+            {
+                IdentityReport {
+                    status: IdentityStatus::Match,
+                    identity: found,
+                    decision: CardDecision::Log,
+                }
+            }
+            Some(found) => {
+                let status = IdentityStatus::ForeignCard { found };
+                match self.on_mismatch {
+                    OnMismatch::Refuse => IdentityReport {
+                        status,
+                        identity: found,
+                        decision: CardDecision::Refuse,
+                    },
+                    OnMismatch::Quarantine => IdentityReport {
+                        status,
+                        identity: found,
+                        decision: CardDecision::Quarantine,
+                    },
+                    OnMismatch::Rebrand => IdentityReport {
+                        status,
+                        identity: brand()?,
+                        decision: CardDecision::Log,
+                    },
+                }
+            }
+        };
+
+        if crate::verbosity() != crate::Verbosity::Quiet {
+            let identity = report.identity;
+            match report.status {
+                IdentityStatus::Match => {
+                    println!("Card {:08x} is this device's card", identity.card_id)
+                }
+                IdentityStatus::NewCard => println!(
+                    "New card - branded as card {:08x} of device {:08x}",
+                    identity.card_id, identity.device_id
+                ),
+                IdentityStatus::ForeignCard { found } => println!(
+                    "Foreign card {:08x} from device {:08x} - {}",
+                    found.card_id,
+                    found.device_id,
+                    match report.decision {
+                        CardDecision::Log => "rebranded",
+                        CardDecision::Quarantine => "logging to quarantine",
+                        CardDecision::Refuse => "not logging",
+                    }
+                ),
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// The identity in the card's marker, None if it is missing or unreadable
+fn read_marker<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    root: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+) -> Result<Option<CardIdentity>, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut marker = [0u8; MARKER_LEN];
+    let mut len = 0;
+    let mut buffer = [0u8; MARKER_LEN];
+    match read_file_chunks(root, IDENTITY_FILE, &mut buffer, |chunk| {
+        let take = chunk.len().min(marker.len() - len);
+        marker[len..len + take].copy_from_slice(&chunk[..take]);
+        len += take;
+    }) {
+        Ok(_) => {}
+        Err(Error::NotFound) => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let Ok(line) = core::str::from_utf8(&marker[..len]) else {
+        return Ok(None);
+    };
+    let mut fields = line.trim_end().split(' ');
+    let mut value = |key: &str| {
+        let hex = fields.next()?.strip_prefix(key)?;
+        u32::from_str_radix(hex, 16).ok()
+    };
+    Ok(value("device=")
+        .zip(value("card="))
+        .map(|(device_id, card_id)| CardIdentity { device_id, card_id }))
+}
+
+fn write_marker<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    root: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    identity: &CardIdentity,
+) -> Result<(), Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut marker = *b"device=00000000 card=00000000\n";
+    write_hex(&mut marker[7..15], identity.device_id);
+    write_hex(&mut marker[21..29], identity.card_id);
+    let file = root.open_file_in_dir(IDENTITY_FILE, Mode::ReadWriteCreateOrTruncate)?;
+    file.write(&marker)?;
+    file.close()
+}
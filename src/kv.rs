@@ -0,0 +1,393 @@
+//! Tiny key-value store kept as an append-only log in one file
+//!
+//! Each [`kv_put`] appends a record and [`kv_get`] returns the value of the
+//! last one for the key, so an update never rewrites earlier data. Records
+//! are `key_len: u8, value_len: u16 LE, key, value, CRC-32 LE`; a record cut
+//! short by power loss fails its CRC and ends the log, and the next put
+//! compacts it away. Lookups scan the whole file, so this suits a few dozen
+//! calibration values and state flags, not bulk data.
+//!
+//! Compaction writes the live records to a `.KVC` file ending in a marker
+//! record (`key_len` 0), deletes the log and copies the compacted file back.
+//! A `.KVC` file ending in the marker is complete, so if one is found on the
+//! next access it replaces the log; an incomplete one is discarded.
+
+use embedded_sdmmc::{BlockDevice, Directory, Error, File, Mode, TimeSource};
+
+use crate::checksum::Crc32;
+use crate::fs::replace_extension;
+
+/// Longest key [`kv_put`] accepts, in bytes
+pub const KV_MAX_KEY: usize = 32;
+
+/// Log size past which [`kv_put`] compacts, unless it is still less than
+/// twice the size after the last compaction
+pub const KV_COMPACT_THRESHOLD: u32 = 8 * 1024;
+
+/// Extension of the file [`kv_compact`] writes before replacing the log
+pub const KV_COMPACT_EXTENSION: &str = "KVC";
+
+/// `key_len` and `value_len`
+const HEADER_LEN: u32 = 3;
+/// Trailing CRC-32
+const CRC_LEN: u32 = 4;
+
+/// Errors returned by the key-value store
+#[derive(Debug, Clone)]
+pub enum KvError<E: core::fmt::Debug> {
+    /// The filesystem failed
+    Fs(Error<E>),
+    /// The key is empty or longer than [`KV_MAX_KEY`]
+    KeyLength,
+    /// The value is longer than 65535 bytes
+    ValueTooLong,
+    /// The stored value doesn't fit in the buffer passed to [`kv_get`]
+    BufferTooSmall {
+        /// Length of the stored value
+        needed: usize,
+    },
+}
+
+impl<E: core::fmt::Debug> From<Error<E>> for KvError<E> {
+    fn from(e: Error<E>) -> Self {
+        KvError::Fs(e)
+    }
+}
+
+/// Read the latest value stored for `key` in the log `file` into `buffer`,
+/// or None if the key (or the file) doesn't exist
+pub fn kv_get<'b, D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    file: &str,
+    key: &[u8],
+    buffer: &'b mut [u8],
+) -> Result<Option<&'b [u8]>, KvError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    check_key(key)?;
+    recover(dir, file)?;
+    let log = match dir.open_file_in_dir(file, Mode::ReadOnly) {
+        Ok(log) => log,
+        Err(Error::NotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let Some((offset, len)) = scan(&log, 0, Some(key))?.latest else {
+        log.close()?;
+        return Ok(None);
+    };
+    let len = usize::from(len);
+    if len > buffer.len() {
+        log.close()?;
+        return Err(KvError::BufferTooSmall { needed: len });
+    }
+    log.seek_from_start(offset)?;
+    let complete = read_exact(&log, &mut buffer[..len])?;
+    log.close()?;
+    Ok(complete.then_some(&buffer[..len]))
+}
+
+/// Store `value` for `key` in the log `file`, creating it if needed.
+///
+/// Compacts first if the log has grown past [`KV_COMPACT_THRESHOLD`] (and
+/// doubled since the last compaction), or ends in a damaged record.
+pub fn kv_put<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    file: &str,
+    key: &[u8],
+    value: &[u8],
+) -> Result<(), KvError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    check_key(key)?;
+    let value_len = u16::try_from(value.len()).map_err(|_| KvError::ValueTooLong)?;
+    recover(dir, file)?;
+
+    let mut log = dir.open_file_in_dir(file, Mode::ReadWriteCreateOrAppend)?;
+    let found = scan(&log, 0, None)?;
+    let len = log.length();
+    if found.valid_end < len
+        || len > KV_COMPACT_THRESHOLD.max(found.compacted_len.saturating_mul(2))
+    {
+        log.close()?;
+        compact(dir, file)?;
+        log = dir.open_file_in_dir(file, Mode::ReadWriteCreateOrAppend)?;
+    }
+    log.seek_from_end(0)?;
+    let mut crc = Crc32::new();
+    let [low, high] = value_len.to_le_bytes();
+    for part in [&[key.len() as u8, low, high][..], key, value] {
+        crc.update(part);
+        log.write(part)?;
+    }
+    log.write(&crc.finish().to_le_bytes())?;
+    log.close()?;
+    Ok(())
+}
+
+/// Rewrite the log `file` keeping only the latest value of each key.
+///
+/// [`kv_put`] does this automatically; call it directly to reclaim space
+/// at a convenient time.
+pub fn kv_compact<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    file: &str,
+) -> Result<(), KvError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    recover(dir, file)?;
+    compact(dir, file)
+}
+
+fn check_key<E: core::fmt::Debug>(key: &[u8]) -> Result<(), KvError<E>> {
+    if key.is_empty() || key.len() > KV_MAX_KEY {
+        return Err(KvError::KeyLength);
+    }
+    Ok(())
+}
+
+fn compact_name<'a, E: core::fmt::Debug>(
+    file: &str,
+    buffer: &'a mut [u8; 12],
+) -> Result<&'a str, KvError<E>> {
+    replace_extension(file, KV_COMPACT_EXTENSION, buffer).ok_or(KvError::Fs(Error::FilenameError(
+        embedded_sdmmc::FilenameError::FilenameEmpty,
+    )))
+}
+
+/// Finish or discard a compaction interrupted by power loss
+fn recover<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    file: &str,
+) -> Result<(), KvError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut name_buf = [0u8; 12];
+    let compacted = compact_name(file, &mut name_buf)?;
+    let partial = match dir.open_file_in_dir(compacted, Mode::ReadOnly) {
+        Ok(partial) => partial,
+        Err(Error::NotFound) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let found = scan(&partial, 0, None)?;
+    let complete = found.compacted_len > 0 && found.compacted_len == partial.length();
+    partial.close()?;
+    if complete {
+        replace_log(dir, file, compacted)?;
+    }
+    dir.delete_file_in_dir(compacted)?;
+    Ok(())
+}
+
+fn compact<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    file: &str,
+) -> Result<(), KvError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut name_buf = [0u8; 12];
+    let compacted = compact_name(file, &mut name_buf)?;
+    let log = match dir.open_file_in_dir(file, Mode::ReadOnly) {
+        Ok(log) => log,
+        Err(Error::NotFound) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let out = dir.open_file_in_dir(compacted, Mode::ReadWriteCreateOrTruncate)?;
+    let mut offset = 0;
+    while let Some(record) = read_record(&log, offset)? {
+        offset = record.end;
+        let key = &record.key[..record.key_len];
+        // Keep a record only if no later record overrides it
+        if key.is_empty() || scan(&log, record.end, Some(key))?.latest.is_some() {
+            continue;
+        }
+        let mut chunk = [0u8; 32];
+        let mut crc = Crc32::new();
+        let [low, high] = record.value_len.to_le_bytes();
+        for part in [&[key.len() as u8, low, high][..], key] {
+            crc.update(part);
+            out.write(part)?;
+        }
+        log.seek_from_start(record.value_offset)?;
+        let mut left = usize::from(record.value_len);
+        while left > 0 {
+            let n = left.min(chunk.len());
+            if !read_exact(&log, &mut chunk[..n])? {
+                break;
+            }
+            crc.update(&chunk[..n]);
+            out.write(&chunk[..n])?;
+            left -= n;
+        }
+        out.write(&crc.finish().to_le_bytes())?;
+    }
+    let mut crc = Crc32::new();
+    crc.update(&[0, 0, 0]);
+    out.write(&[0, 0, 0])?;
+    out.write(&crc.finish().to_le_bytes())?;
+    out.close()?;
+    log.close()?;
+
+    replace_log(dir, file, compacted)?;
+    dir.delete_file_in_dir(compacted)?;
+    Ok(())
+}
+
+/// Replace `file` with a copy of `compacted`
+fn replace_log<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    file: &str,
+    compacted: &str,
+) -> Result<(), KvError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    match dir.delete_file_in_dir(file) {
+        Ok(()) | Err(Error::NotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+    // A compacted log holds one record per key, so it is small enough to
+    // copy without yielding, keeping the store synchronous
+    let input = dir.open_file_in_dir(compacted, Mode::ReadOnly)?;
+    let output = dir.open_file_in_dir(file, Mode::ReadWriteCreateOrTruncate)?;
+    let mut buffer = [0u8; 64];
+    loop {
+        let n = input.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        output.write(&buffer[..n])?;
+    }
+    output.close()?;
+    input.close()?;
+    Ok(())
+}
+
+/// What [`scan`] found
+struct Scan {
+    /// Value offset and length of the last record for the key looked for
+    latest: Option<(u32, u16)>,
+    /// End of the last intact record
+    valid_end: u32,
+    /// End of the last compaction marker, or 0 if there is none
+    compacted_len: u32,
+}
+
+/// Walk the intact records from `offset`, noting the last one for `key`
+fn scan<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    log: &File<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    offset: u32,
+    key: Option<&[u8]>,
+) -> Result<Scan, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut found = Scan {
+        latest: None,
+        valid_end: offset,
+        compacted_len: 0,
+    };
+    while let Some(record) = read_record(log, found.valid_end)? {
+        let record_key = &record.key[..record.key_len];
+        if record_key.is_empty() {
+            found.compacted_len = record.end;
+        } else if key == Some(record_key) {
+            found.latest = Some((record.value_offset, record.value_len));
+        }
+        found.valid_end = record.end;
+    }
+    Ok(found)
+}
+
+/// A record whose CRC checked out
+struct Record {
+    key: [u8; KV_MAX_KEY],
+    key_len: usize,
+    value_offset: u32,
+    value_len: u16,
+    end: u32,
+}
+
+/// Read and check the record at `offset`, or None at the end of the log or
+/// if the record is damaged
+fn read_record<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    log: &File<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    offset: u32,
+) -> Result<Option<Record>, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    if offset >= log.length() {
+        return Ok(None);
+    }
+    log.seek_from_start(offset)?;
+    let mut header = [0u8; HEADER_LEN as usize];
+    if !read_exact(log, &mut header)? {
+        return Ok(None);
+    }
+    let key_len = usize::from(header[0]);
+    let value_len = u16::from_le_bytes([header[1], header[2]]);
+    if key_len > KV_MAX_KEY {
+        return Ok(None);
+    }
+    let mut record = Record {
+        key: [0; KV_MAX_KEY],
+        key_len,
+        value_offset: offset + HEADER_LEN + key_len as u32,
+        value_len,
+        end: offset + HEADER_LEN + key_len as u32 + u32::from(value_len) + CRC_LEN,
+    };
+    if !read_exact(log, &mut record.key[..key_len])? {
+        return Ok(None);
+    }
+    let mut crc = Crc32::new();
+    crc.update(&header);
+    crc.update(&record.key[..key_len]);
+    let mut chunk = [0u8; 32];
+    let mut left = usize::from(value_len);
+    while left > 0 {
+        let n = left.min(chunk.len());
+        if !read_exact(log, &mut chunk[..n])? {
+            return Ok(None);
+        }
+        crc.update(&chunk[..n]);
+        left -= n;
+    }
+    let mut stored = [0u8; CRC_LEN as usize];
+    if !read_exact(log, &mut stored)? || u32::from_le_bytes(stored) != crc.finish() {
+        return Ok(None);
+    }
+    Ok(Some(record))
+}
+
+/// Fill `buffer`, returning false if the file ends first
+fn read_exact<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    file: &File<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    buffer: &mut [u8],
+) -> Result<bool, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let n = file.read(&mut buffer[filled..])?;
+        if n == 0 {
+            return Ok(false);
+        }
+        filled += n;
+    }
+    Ok(true)
+}
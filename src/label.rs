@@ -0,0 +1,172 @@
+//! Reading and setting a card's FAT volume label
+//!
+//! A label is stored in two places: the boot sector, which embedded-sdmmc
+//! reads first, and an entry in the root directory with the volume label
+//! attribute, which is what Windows' `dir` shows. embedded-sdmmc can only
+//! read it, so [`set_volume_label`] patches the raw blocks.
+
+use embedded_sdmmc::{
+    Block, BlockDevice, BlockIdx, Error, FilenameError, RawVolume, TimeSource, VolumeIdx,
+    VolumeManager, VolumeName,
+};
+
+use crate::diag::{le16, le32, read_boot_sector};
+
+/// Length of a FAT volume label, space padded
+pub const VOLUME_LABEL_LEN: usize = 11;
+
+/// Errors returned by [`set_volume_label`]
+#[derive(Debug, Clone)]
+pub enum LabelError<E> {
+    /// A block could not be read or written
+    Device(E),
+    /// The label breaks the FAT rules: 1 to 11 characters, ISO-8859-1, none
+    /// of `"*+,./:;<=>?[\]|` or control characters
+    InvalidLabel(FilenameError),
+    /// There is no FAT volume at that index
+    NoVolume,
+    /// The boot sector has no extended parameter block to hold a label
+    NoLabelField,
+}
+
+impl<E> From<E> for LabelError<E> {
+    fn from(error: E) -> Self {
+        LabelError::Device(error)
+    }
+}
+
+/// Read the label of `volume` (e.g. "LOGGER 07") into `buffer` and print it,
+/// so a card can be identified from the console without a PC.
+///
+/// embedded-sdmmc looks in the boot sector first and falls back to the
+/// root directory's label entry; cards formatted without a label give
+/// None. Labels are ISO-8859-1, so bytes outside ASCII are shown as `?`.
+/// The label is printed unless [`crate::verbosity`] is quiet.
+pub fn volume_label<
+    'a,
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    volume_mgr: &VolumeManager<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    volume: RawVolume,
+    buffer: &'a mut [u8; 11],
+) -> Result<Option<&'a str>, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let Some(name) = volume_mgr.get_root_volume_label(volume)? else {
+        return Ok(None);
+    };
+    let name = name.name();
+    for (out, &byte) in buffer.iter_mut().zip(name) {
+        *out = if byte.is_ascii() && !byte.is_ascii_control() {
+            byte
+        } else {
+            b'?'
+        };
+    }
+    let label = core::str::from_utf8(&buffer[..name.len()]).unwrap_or_default();
+    if crate::verbosity() != crate::Verbosity::Quiet {
+        println!("Volume label: {}", label);
+    }
+    Ok(Some(label))
+}
+
+/// Set the label of `volume` to `label`, e.g. "LOGGER01", when provisioning
+/// a card. Lowercase ASCII letters are stored uppercase, as DOS and Windows
+/// do.
+///
+/// Writes the label into the boot sector (and the FAT32 backup boot sector)
+/// and, if the root directory already has a label entry, updates it too. A
+/// missing label entry isn't created, so on such cards Windows' `dir` shows
+/// no label while other tools and [`volume_label`] see the new one. Only
+/// the first cluster of a FAT32 root directory is searched.
+///
+/// Call this with the volume closed: embedded-sdmmc reads the label when
+/// it opens a volume, and its cached copy won't change.
+pub fn set_volume_label<D: BlockDevice>(
+    device: &D,
+    volume: VolumeIdx,
+    label: &str,
+) -> Result<(), LabelError<D::Error>> {
+    let mut upper = [0u8; VOLUME_LABEL_LEN * 2];
+    let label = label.as_bytes();
+    let upper = upper
+        .get_mut(..label.len())
+        .ok_or(LabelError::InvalidLabel(FilenameError::NameTooLong))?;
+    upper.copy_from_slice(label);
+    upper.make_ascii_uppercase();
+    let upper = core::str::from_utf8(upper)
+        .map_err(|_| LabelError::InvalidLabel(FilenameError::InvalidCharacter))?;
+    let name = VolumeName::create_from_str(upper).map_err(LabelError::InvalidLabel)?;
+    let mut padded = [b' '; VOLUME_LABEL_LEN];
+    padded[..name.name().len()].copy_from_slice(name.name());
+
+    let mut block = [Block::new()];
+    let Some((boot_lba, _)) = read_boot_sector(device, volume, &mut block)? else {
+        return Err(LabelError::NoVolume);
+    };
+    let boot = &block[0].contents;
+    let fat32 = le16(boot, 22) == 0;
+    // Extended boot signature, then the label 5 bytes after it
+    let signature_offset = if fat32 { 66 } else { 38 };
+    if boot[signature_offset] != 0x29 {
+        return Err(LabelError::NoLabelField);
+    }
+    let label_offset = signature_offset + 5;
+    let reserved = u32::from(le16(boot, 14));
+    let fats = u32::from(boot[16]);
+    let sectors_per_cluster = u32::from(boot[13]);
+    let (root_lba, root_sectors) = if fat32 {
+        let fat_size = le32(boot, 36);
+        let root_cluster = le32(boot, 44);
+        let data_lba = boot_lba + reserved + fats * fat_size;
+        let root = data_lba + root_cluster.saturating_sub(2) * sectors_per_cluster;
+        (root, sectors_per_cluster)
+    } else {
+        let fat_size = u32::from(le16(boot, 22));
+        let entries = u32::from(le16(boot, 17));
+        (
+            boot_lba + reserved + fats * fat_size,
+            (entries * 32).div_ceil(512),
+        )
+    };
+    let backup = fat32
+        .then(|| u32::from(le16(boot, 50)))
+        .filter(|&sector| sector != 0 && sector != 0xFFFF);
+
+    block[0].contents[label_offset..label_offset + VOLUME_LABEL_LEN].copy_from_slice(&padded);
+    device.write(&block, BlockIdx(boot_lba))?;
+    if let Some(sector) = backup {
+        device.read(&mut block, BlockIdx(boot_lba + sector))?;
+        if block[0].contents[signature_offset] == 0x29 {
+            block[0].contents[label_offset..label_offset + VOLUME_LABEL_LEN]
+                .copy_from_slice(&padded);
+            device.write(&block, BlockIdx(boot_lba + sector))?;
+        }
+    }
+
+    for lba in root_lba..root_lba + root_sectors {
+        device.read(&mut block, BlockIdx(lba))?;
+        for entry in block[0].contents.chunks_exact_mut(32) {
+            match entry[0] {
+                // End of directory
+                0x00 => return Ok(()),
+                // Deleted
+                0xE5 => continue,
+                _ => {}
+            }
+            // The volume label bit without the long-name attribute combination
+            if entry[11] & 0x08 != 0 && entry[11] & 0x0F != 0x0F {
+                entry[..VOLUME_LABEL_LEN].copy_from_slice(&padded);
+                device.write(&block, BlockIdx(lba))?;
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,50 +1,416 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 //! ESP32 SD Card utilities and helpers
 //!
 //! This library provides common utilities for working with SD cards on ESP32,
-//! including retry logic, time sources, and formatting helpers.
+//! including retry logic, time sources, formatting helpers, columnar logging,
+//! field-editable configuration files, and checksum sidecars for archived logs.
+//!
+//! The esp-hal glue (random filenames and retry jitter from the hardware RNG,
+//! the RTC behind [`RtcTimeSource::from_rtc`]) is in the [`esp`] module,
+//! behind the default `esp` feature, which also sends console output through
+//! esp-println. Without it the rest only builds on embedded-sdmmc,
+//! embedded-io and embassy-time and prints nothing, and its tests run on the
+//! host, e.g. `cargo test --no-default-features --target
+//! x86_64-unknown-linux-gnu`. [`prelude`] re-exports the commonly used items.
+
+use core::sync::atomic::{AtomicU8, Ordering};
 
 use embassy_time::{Duration, Timer};
-use esp_hal::rng::Rng;
+
+/// Console output, through esp-println with the `esp` feature and discarded
+/// without it
+#[cfg(feature = "esp")]
+macro_rules! println {
+    ($($arg:tt)*) => {
+        esp_println::println!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "esp"))]
+macro_rules! println {
+    ($($arg:tt)*) => {{
+        let _ = format_args!($($arg)*);
+    }};
+}
+
+mod audit;
+mod bench;
+mod boot_count;
+mod cancel;
+mod capacity;
+mod card;
+mod checksum;
+mod columnar;
+#[cfg(feature = "esp")]
+mod compat;
+mod config;
+mod csv;
+mod diag;
+mod durable;
+mod error_kind;
+#[cfg(feature = "esp")]
+pub mod esp;
+mod export;
+mod flush_policy;
+mod fs;
+mod hint;
+mod human;
+mod identity;
+mod kv;
+mod label;
+mod migrate;
+mod paged;
+mod phase_timer;
+mod read_only;
+mod resume;
+mod retry;
+mod rtc_time;
+mod scan;
+mod search;
+mod sector_cache;
+mod self_test;
+mod shared_log;
+mod sim_clock;
+mod snapshot;
+mod span;
+mod sparse;
+mod state;
+mod time;
+#[cfg(feature = "recover")]
+mod undelete;
+mod verbosity;
+mod wipe;
+mod writer;
+mod yield_budget;
+
+pub use audit::{
+    audit_record, verify_audit_chain, AuditEntry, AuditError, VerifyResult, AUDIT_FILE,
+    AUDIT_LINE_MAX,
+};
+pub use bench::{auto_tune_buffer, bench_write};
+pub use boot_count::{increment_boot_count, read_boot_count, BOOT_COUNT_FILE};
+pub use cancel::{CancelToken, CancellableError};
+pub use capacity::{verify_capacity, CapacityError};
+pub use card::{diagnose_spi_mode, reset_card_spi, ResetError, SpiModeDiagnosis};
+pub use checksum::{
+    checksum_file, sidecar_name, verify_checksum_sidecar, write_checksum_sidecar, ChecksumStatus,
+    ChecksumWriter, Crc32, SIDECAR_EXTENSION,
+};
+pub use columnar::{
+    read_columnar_chunk, ColumnarError, ColumnarWriter, COLUMNAR_HEADER_LEN, COLUMNAR_MAGIC,
+    COLUMNAR_VERSION,
+};
+#[cfg(feature = "esp")]
+#[allow(deprecated)]
+pub use compat::{
+    generate_filename, generate_filename_for, generate_random_filename, generate_token_filename,
+    retry_with_jitter, try_retry_with_jitter,
+};
+pub use config::{
+    read_config, ConfigIssue, ConfigIssueKind, ConfigLineError, LoggerConfig, CONFIG_MAX_LINE,
+};
+pub use csv::{
+    escape_csv_field, format_csv_row, needs_quoting, CsvError, CsvHeader, FieldJoiner, LineEnding,
+    CONTINUATION_PREFIX, CONTINUED_FIELD,
+};
+pub use diag::{
+    bytes_per_cluster, dump_filesystem_headers, free_space, free_space_sampled, reserve_for,
+    DumpError,
+};
+pub use durable::DurableSeq;
+pub use error_kind::FsErrorKind;
+pub use export::{export_over_serial, ExportEncoding, ExportError};
+pub use flush_policy::{AdaptiveFlushPolicy, MaxDataLoss};
+pub use fs::{
+    bump_filename, copy_file, copy_file_between, count_dir_entries, create_new_file, flush_all,
+    read_file_chunks, read_file_chunks_with_progress, replace_extension, suffix_filename,
+    touch_file, trim_partial_row, unique_suffixed_name, use_subdir_when_full, ActiveDir, CopyMode,
+    TrimOutcome, FAT16_ROOT_ENTRIES,
+};
+pub use hint::ErrorHint;
+pub use human::{format_bytes, format_rate, HumanBytes, HumanRate, Units};
+pub use identity::{
+    CardDecision, CardIdentity, IdentityPin, IdentityReport, IdentityStatus, OnMismatch,
+    IDENTITY_FILE, QUARANTINE_DIR,
+};
+pub use kv::{
+    kv_compact, kv_get, kv_put, KvError, KV_COMPACT_EXTENSION, KV_COMPACT_THRESHOLD, KV_MAX_KEY,
+};
+pub use label::{set_volume_label, volume_label, LabelError, VOLUME_LABEL_LEN};
+pub use migrate::{migrate_to_daily_dirs, MigrationReport, LEGACY_DIR};
+pub use paged::{read_page, PagedError, PagedWriter, PAGE_PADDING};
+pub use phase_timer::PhaseTimer;
+pub use read_only::{
+    ReadOnlyDevice, ReadOnlyDeviceError, ReadOnlyError, ReadOnlyHealthError, ReadOnlySd,
+};
+pub use resume::{
+    clear_position, load_position, read_file_chunks_from, save_position, POSITION_EXTENSION,
+};
+pub use retry::{
+    apply_jitter, should_retry, BackoffStrategy, RetryBudget, RetryConfig, DEFAULT_JITTER_PERCENT,
+};
+pub use rtc_time::RtcTimeSource;
+pub use scan::{
+    dir_usage, find_newest_file, list_dir, list_files_sorted, scan_dir, DirUsage, ScanError,
+    ScanMode, ScanReport, SortOrder,
+};
+pub use search::{logged_duration, seek_to_timestamp, SeekError};
+pub use sector_cache::{CacheSlot, CacheStats, CachedBlockDevice};
+pub use self_test::{self_test, verify_canary, SelfTestError, SelfTestStep, SELF_TEST_FILENAME};
+pub use shared_log::{SharedLog, SharedLogError};
+pub use sim_clock::SimClock;
+pub use snapshot::{tail_file, SnapshotReader};
+pub use span::{is_volume_full, VolumeSpanPolicy};
+pub use sparse::{SparseLog, SparseRow};
+pub use state::{LoggerState, LoggerStateCell, LoggerStateReceiver};
+pub use time::{
+    epoch_to_timestamp, format_iso8601, timestamp_to_epoch_ms, TimeSourceExt, FAT_MAX_EPOCH,
+    FAT_MIN_EPOCH, ISO8601_LEN,
+};
+#[cfg(feature = "recover")]
+pub use undelete::{scan_deleted, undelete, DeletedEntry, UndeleteError};
+pub use verbosity::{set_verbosity, verbosity, Verbosity};
+pub use wipe::{
+    pending_wipe, truncate_file, wipe_file, WipePattern, TRUNCATE_EXTENSION, WIPE_EXTENSION,
+};
+pub use writer::{
+    BuildInfo, CsvWriter, Field, FlushOutcome, Scale, SlowWritePolicy, Timestamps, Transaction,
+    WriterError, DEFAULT_SIZE_LIMIT_MARGIN, FAT32_MAX_FILE_SIZE, MARK_LABEL_MAX,
+};
+pub use yield_budget::YieldBudget;
+
+/// The items most applications need, for `use esp32_sdcard::prelude::*;`
+pub mod prelude {
+    #[cfg(feature = "esp")]
+    pub use crate::esp::generate_filename_for;
+    pub use crate::{
+        create_new_file, retry_with_backoff, retry_with_hint, set_verbosity, CsvWriter,
+        DummyTimeSource, ErrorHint, Field, LoggerConfig, TimeSourceExt, Timestamps, Verbosity,
+        WriterError, YieldBudget,
+    };
+}
 
 /// Maximum number of retries for SD card operations
 pub const MAX_RETRIES: u8 = 4;
 
 /// Retry operations with 500ms backoff, useful for SD card initialization
-pub async fn retry_with_backoff<T, E, F, Fut>(operation_name: &str, mut operation: F) -> Option<T>
+///
+/// How much is printed depends on [`verbosity`]: nothing, a single line once all
+/// retries are exhausted (the default), or every failed attempt.
+pub async fn retry_with_backoff<T, E, F, Fut>(operation_name: &str, operation: F) -> Option<T>
+where
+    F: FnMut() -> Fut,
+    Fut: core::future::Future<Output = Result<T, E>>,
+    E: core::fmt::Debug,
+{
+    try_retry_with_backoff(operation_name, operation).await.ok()
+}
+
+/// Like [`retry_with_backoff`], but returns the error from the last attempt
+/// once every attempt has failed, so the caller can decide what to do about
+/// it (re-initialise the bus, halt, carry on without the card)
+pub async fn try_retry_with_backoff<T, E, F, Fut>(
+    operation_name: &str,
+    operation: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: core::future::Future<Output = Result<T, E>>,
+    E: core::fmt::Debug,
+{
+    try_retry_with_backoff_cfg(RetryConfig::DEFAULT, operation_name, operation).await
+}
+
+/// Like [`retry_with_backoff`], with the number of attempts and the delay
+/// between them taken from `config`, e.g. more attempts for a card that is
+/// slow to wake on a cold boot, or an exponential [`BackoffStrategy`] for a bus that
+/// may be wedged. Retrying also stops early once the next delay would take
+/// the time spent waiting past `config.budget_ms`.
+pub async fn retry_with_backoff_cfg<T, E, F, Fut>(
+    config: RetryConfig,
+    operation_name: &str,
+    operation: F,
+) -> Option<T>
+where
+    F: FnMut() -> Fut,
+    Fut: core::future::Future<Output = Result<T, E>>,
+    E: core::fmt::Debug,
+{
+    try_retry_with_backoff_cfg(config, operation_name, operation)
+        .await
+        .ok()
+}
+
+/// Like [`retry_with_backoff_cfg`], but returns the error from the last
+/// attempt once every attempt has failed; see [`try_retry_with_backoff`].
+///
+/// Delays are never jittered, since there is no random source; use
+/// [`try_retry_with_jitter_with`] or [`esp::try_retry_with_jitter`] for that.
+pub async fn try_retry_with_backoff_cfg<T, E, F, Fut>(
+    config: RetryConfig,
+    operation_name: &str,
+    operation: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: core::future::Future<Output = Result<T, E>>,
+    E: core::fmt::Debug,
+{
+    try_retry_with_jitter_with(config.with_jitter(0), || 0, operation_name, operation).await
+}
+
+/// Like [`try_retry_with_backoff_cfg`], spreading each delay by up to
+/// `config.jitter_percent` percent either way with randomness drawn from
+/// `next_u32`, so devices that fail together, e.g. on a shared power rail,
+/// don't keep retrying in lockstep. With no jitter configured `next_u32`
+/// isn't called and the delays are exactly those of `config.backoff`.
+pub async fn try_retry_with_jitter_with<T, E, F, Fut, R>(
+    config: RetryConfig,
+    mut next_u32: R,
+    operation_name: &str,
+    mut operation: F,
+) -> Result<T, E>
 where
     F: FnMut() -> Fut,
     Fut: core::future::Future<Output = Result<T, E>>,
     E: core::fmt::Debug,
+    R: FnMut() -> u32,
 {
-    for attempt in 1..=MAX_RETRIES {
+    let max_retries = config.attempts();
+    let mut budget = RetryBudget::new(config.budget_ms);
+    let mut attempt = 1;
+    loop {
         match operation().await {
-            Ok(result) => return Some(result),
+            Ok(result) => return Ok(result),
             Err(e) => {
-                esp_println::println!(
-                    "{} failed: {:?} - Retry {}/{}",
-                    operation_name,
-                    e,
-                    attempt,
-                    MAX_RETRIES
-                );
-                if attempt >= MAX_RETRIES {
-                    esp_println::println!(
-                        "{} failed after {} retries",
-                        operation_name,
-                        MAX_RETRIES
+                if verbosity() == Verbosity::Verbose {
+                    println!(
+                        "{} failed: {:?} - Retry {}/{}",
+                        operation_name, e, attempt, max_retries
                     );
-                    return None;
                 }
-                Timer::after(Duration::from_millis(500)).await;
+                let mut delay_ms = config.delay_ms(attempt);
+                if config.jitter_percent > 0 {
+                    delay_ms = apply_jitter(delay_ms, config.jitter_percent, next_u32());
+                }
+                if !should_retry(attempt, max_retries, delay_ms, &budget) {
+                    match verbosity() {
+                        Verbosity::Quiet => {}
+                        Verbosity::Summary => println!(
+                            "{} failed after {} attempts: {:?}",
+                            operation_name, attempt, e
+                        ),
+                        Verbosity::Verbose => {
+                            println!("{} failed after {} retries", operation_name, attempt)
+                        }
+                    }
+                    return Err(e);
+                }
+                Timer::after(Duration::from_millis(delay_ms.into())).await;
+                budget.spend(delay_ms);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Like [`retry_with_backoff`], but once every attempt has failed also prints
+/// a hint on what to check (e.g. wiring for timeouts, formatting for volume
+/// errors), unless [`Verbosity::Quiet`]
+pub async fn retry_with_hint<T, E, F, Fut>(operation_name: &str, mut operation: F) -> Option<T>
+where
+    F: FnMut() -> Fut,
+    Fut: core::future::Future<Output = Result<T, E>>,
+    E: core::fmt::Debug + ErrorHint,
+{
+    let last_hint = core::cell::Cell::new(None);
+    let last_hint_ref = &last_hint;
+    let result = retry_with_backoff(operation_name, || {
+        let attempt = operation();
+        async move { attempt.await.inspect_err(|e| last_hint_ref.set(e.hint())) }
+    })
+    .await;
+    match last_hint.get() {
+        Some(hint) if result.is_none() && verbosity() != Verbosity::Quiet => {
+            println!("    Hint: {}", hint)
+        }
+        _ => {}
+    }
+    result
+}
+
+/// True if `error` means no card answered at all, as when none is inserted
+pub fn is_no_card(error: &embedded_sdmmc::SdCardError) -> bool {
+    use embedded_sdmmc::SdCardError;
+    matches!(
+        error,
+        SdCardError::CardNotFound
+            | SdCardError::TimeoutCommand(_)
+            | SdCardError::TimeoutACommand(_)
+    )
+}
+
+/// Wait for a card to be inserted, running `probe` (e.g. `sdcard.num_bytes()`)
+/// every `poll_interval` while it fails with an [`is_no_card`] error.
+///
+/// Returns the first result that isn't a no-card error, or the last error
+/// once `timeout` has passed; `None` waits forever. Prints a single
+/// "Waiting for SD card" line when the first probe finds no card, unless
+/// [`Verbosity::Quiet`].
+pub async fn wait_for_card<T, F, Fut>(
+    timeout: Option<Duration>,
+    poll_interval: Duration,
+    mut probe: F,
+) -> Result<T, embedded_sdmmc::SdCardError>
+where
+    F: FnMut() -> Fut,
+    Fut: core::future::Future<Output = Result<T, embedded_sdmmc::SdCardError>>,
+{
+    let deadline = timeout.map(|timeout| embassy_time::Instant::now() + timeout);
+    let mut announced = false;
+    loop {
+        match probe().await {
+            Err(e) if is_no_card(&e) => {
+                if deadline.is_some_and(|deadline| embassy_time::Instant::now() >= deadline) {
+                    return Err(e);
+                }
+                if !announced && verbosity() != Verbosity::Quiet {
+                    println!("Waiting for SD card to be inserted...");
+                }
+                announced = true;
+                Timer::after(poll_interval).await;
             }
+            result => return result,
         }
     }
-    None
 }
 
-/// Dummy time source for embedded-sdmmc (use RTC for real timestamps)
+/// Like [`retry_with_backoff`], but stores the number of the attempt in
+/// progress (1 to [`MAX_RETRIES`]) in `progress` before each attempt, so
+/// another task can show e.g. "retrying... 2/4". The value is left at the
+/// last attempt made.
+pub async fn retry_with_progress<T, E, F, Fut>(
+    operation_name: &str,
+    progress: &AtomicU8,
+    mut operation: F,
+) -> Option<T>
+where
+    F: FnMut() -> Fut,
+    Fut: core::future::Future<Output = Result<T, E>>,
+    E: core::fmt::Debug,
+{
+    let mut attempt = 0;
+    retry_with_backoff(operation_name, || {
+        attempt += 1;
+        progress.store(attempt, Ordering::Relaxed);
+        operation()
+    })
+    .await
+}
+
+/// Dummy time source for embedded-sdmmc, stamping every file 1970-01-01
+/// (use [`RtcTimeSource`] for real timestamps)
 pub struct DummyTimeSource;
 
 impl embedded_sdmmc::TimeSource for DummyTimeSource {
@@ -60,12 +426,14 @@ impl embedded_sdmmc::TimeSource for DummyTimeSource {
     }
 }
 
-/// Generate random 8.3 filename (e.g., "ABC12345.CSV")
-/// Note: This is the max length for a filename in this filesystem.
-pub fn generate_random_filename(rng: &mut Rng, filename: &mut [u8; 12]) {
+impl TimeSourceExt for DummyTimeSource {}
+
+/// Generate random 8.3 filename (e.g., "ABC12345.CSV"), drawing randomness from `next_u32`,
+/// e.g. a seeded PRNG so tests produce the same names on every run
+pub fn generate_random_filename_with<F: FnMut() -> u32>(mut next_u32: F, filename: &mut [u8; 12]) {
     const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
     for byte in filename.iter_mut().take(8) {
-        let idx = (rng.random() as usize) % CHARS.len();
+        let idx = (next_u32() as usize) % CHARS.len();
         *byte = CHARS[idx];
     }
     filename[8] = b'.';
@@ -74,7 +442,117 @@ pub fn generate_random_filename(rng: &mut Rng, filename: &mut [u8; 12]) {
     filename[11] = b'V';
 }
 
-/// Format CSV line as "timestamp,count,counter\n", returns bytes written
+/// Generate an 8.3 filename of `prefix` followed by random base-36
+/// characters up to 8, with `extension`, e.g. "LOGK3Z9Q.BIN" for "log" and
+/// "bin", drawing randomness from `next_u32`. Returns the name, which is
+/// also left in `filename` with any unused bytes zeroed.
+///
+/// Both parts are uppercased. The prefix may be empty and is at most 7
+/// characters, leaving at least one random one; the extension is at most 3
+/// and may be empty for a name without a dot. Characters FAT doesn't allow
+/// in short names, such as spaces, `*` or `.`, give
+/// `FilenameError::InvalidCharacter`, and over-long parts
+/// `FilenameError::NameTooLong`.
+pub fn generate_filename_with<'a, F: FnMut() -> u32>(
+    mut next_u32: F,
+    prefix: &str,
+    extension: &str,
+    filename: &'a mut [u8; 12],
+) -> Result<&'a str, embedded_sdmmc::FilenameError> {
+    use embedded_sdmmc::FilenameError;
+
+    const CHARS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    if prefix.len() > 7 || extension.len() > 3 {
+        return Err(FilenameError::NameTooLong);
+    }
+    if !prefix
+        .bytes()
+        .chain(extension.bytes())
+        .all(is_short_name_char)
+    {
+        return Err(FilenameError::InvalidCharacter);
+    }
+
+    filename.fill(0);
+    filename[..prefix.len()].copy_from_slice(prefix.as_bytes());
+    for byte in &mut filename[prefix.len()..8] {
+        *byte = CHARS[(next_u32() as usize) % CHARS.len()];
+    }
+    let mut len = 8;
+    if !extension.is_empty() {
+        filename[8] = b'.';
+        filename[9..9 + extension.len()].copy_from_slice(extension.as_bytes());
+        len += 1 + extension.len();
+    }
+    filename[..len].make_ascii_uppercase();
+    // Only ASCII was copied in
+    core::str::from_utf8(&filename[..len]).map_err(|_| FilenameError::Utf8Error)
+}
+
+/// True if `byte` may appear in a FAT short name, in either case
+fn is_short_name_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(&byte)
+}
+
+/// Generate an 8.3 filename with a `token_len`-character stem (clamped to
+/// 1..=8) drawn from `next_u32`, e.g. "K3Z9Q.CSV", returns bytes written.
+///
+/// Tokens use base 36 (0-9A-Z) rather than base 62: FAT short names are
+/// case-insensitive, so "aB" and "Ab" would name the same file. Each
+/// character carries about 5.2 bits, so 8 characters give about 41 bits.
+pub fn generate_token_filename_with<F: FnMut() -> u32>(
+    mut next_u32: F,
+    filename: &mut [u8; 12],
+    token_len: usize,
+) -> usize {
+    const CHARS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let token_len = token_len.clamp(1, 8);
+    for byte in filename.iter_mut().take(token_len) {
+        *byte = CHARS[(next_u32() as usize) % CHARS.len()];
+    }
+    filename[token_len..token_len + 4].copy_from_slice(b".CSV");
+    token_len + 4
+}
+
+/// Generate a filename from the date and time, "MMDDHHMM.CSV" (e.g. "07141530.CSV")
+pub fn generate_dated_filename(timestamp: &embedded_sdmmc::Timestamp, filename: &mut [u8; 12]) {
+    let fields = [
+        timestamp.zero_indexed_month + 1,
+        timestamp.zero_indexed_day + 1,
+        timestamp.hours,
+        timestamp.minutes,
+    ];
+    for (digits, value) in filename.chunks_exact_mut(2).zip(fields) {
+        digits[0] = b'0' + value / 10 % 10;
+        digits[1] = b'0' + value % 10;
+    }
+    filename[8..].copy_from_slice(b".CSV");
+}
+
+/// Generate a filename whose stem is the seconds since 1970 in 8 zero-padded
+/// base-36 digits (0-9A-Z), e.g. "00SSG1C0.CSV" for 2025-03-01T12:00:00,
+/// so sorting names alphabetically sorts them chronologically.
+///
+/// In 8 characters, decimal would give one-second resolution for only about
+/// 3 years, or one-minute resolution for 190 years, and
+/// [`generate_dated_filename`] wraps every year. Base 36 keeps one-second
+/// resolution for tens of thousands of years but isn't readable at a glance.
+/// Two files started in the same second get the same name, so create them
+/// with [`create_new_file`].
+pub fn generate_sortable_filename(timestamp: &embedded_sdmmc::Timestamp, filename: &mut [u8; 12]) {
+    const CHARS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let mut seconds = timestamp_to_epoch_ms(timestamp, 0) / 1000;
+    for digit in filename[..8].iter_mut().rev() {
+        *digit = CHARS[(seconds % 36) as usize];
+        seconds /= 36;
+    }
+    filename[8..].copy_from_slice(b".CSV");
+}
+
+/// Format CSV line as "timestamp,count,counter\n", returns bytes written.
+///
+/// Fixed to the demo's columns and truncates silently; see
+/// [`format_csv_row`] for any columns.
 pub fn format_csv_line(buffer: &mut [u8], timestamp: u64, counter: u32) -> usize {
     let mut cursor = 0;
 
@@ -105,3 +583,251 @@ pub fn format_csv_line(buffer: &mut [u8], timestamp: u64, counter: u32) -> usize
 
     cursor
 }
+
+/// Format a scaled integer as a signed fixed-point number, returns bytes
+/// written (0 if `buffer` is too small).
+///
+/// `value` is in units of 10^-`frac_digits` (at most 9), so -1234 with
+/// `int_width` 3 and `frac_digits` 2 gives "-012.34". The sign is always
+/// written ('+' or '-') and the integer part is zero-padded to `int_width`,
+/// so columns line up; integer parts wider than `int_width` are not truncated.
+pub fn format_fixed_signed(buffer: &mut [u8], value: i32, int_width: u8, frac_digits: u8) -> usize {
+    let frac_digits = u32::from(frac_digits.min(9));
+    let scale = 10u32.pow(frac_digits);
+    let magnitude = value.unsigned_abs();
+
+    let mut int_buf = itoa::Buffer::new();
+    let int_str = int_buf.format(magnitude / scale).as_bytes();
+    let padding = usize::from(int_width).saturating_sub(int_str.len());
+    let frac_len = if frac_digits > 0 {
+        1 + frac_digits as usize
+    } else {
+        0
+    };
+    let total = 1 + padding + int_str.len() + frac_len;
+    if buffer.len() < total {
+        return 0;
+    }
+
+    buffer[0] = if value < 0 { b'-' } else { b'+' };
+    buffer[1..1 + padding].fill(b'0');
+    let mut cursor = 1 + padding;
+    buffer[cursor..cursor + int_str.len()].copy_from_slice(int_str);
+    cursor += int_str.len();
+    if frac_digits > 0 {
+        buffer[cursor] = b'.';
+        let mut frac = magnitude % scale;
+        for digit in buffer[cursor + 1..total].iter_mut().rev() {
+            *digit = b'0' + (frac % 10) as u8;
+            frac /= 10;
+        }
+    }
+
+    total
+}
+
+/// Join `values` with `inner_delim` into a single CSV field, e.g. "12;-3;981"
+/// for a 3-axis reading, returns bytes written (0 if `buffer` is too small).
+///
+/// `inner_delim` must differ from the row delimiter (',') and must not need
+/// quoting, otherwise the packed values split into separate columns.
+pub fn format_packed_field(buffer: &mut [u8], values: &[i32], inner_delim: u8) -> usize {
+    let mut cursor = 0;
+    for (i, value) in values.iter().enumerate() {
+        let mut value_buf = itoa::Buffer::new();
+        let digits = value_buf.format(*value).as_bytes();
+        let sep = usize::from(i > 0);
+        let Some(out) = buffer.get_mut(cursor..cursor + sep + digits.len()) else {
+            return 0;
+        };
+        if sep == 1 {
+            out[0] = inner_delim;
+        }
+        out[sep..].copy_from_slice(digits);
+        cursor += sep + digits.len();
+    }
+    cursor
+}
+
+/// Format `value` with `group_char` between every three digits, e.g.
+/// "1,234,567", returns bytes written (0 if `buffer` is too small).
+///
+/// Meant for human-readable status text, not CSV data: a ',' separator
+/// would split the number across columns.
+pub fn format_grouped(buffer: &mut [u8], value: u64, group_char: u8) -> usize {
+    let mut digits_buf = itoa::Buffer::new();
+    let digits = digits_buf.format(value).as_bytes();
+    let total = digits.len() + (digits.len() - 1) / 3;
+    let Some(out) = buffer.get_mut(..total) else {
+        return 0;
+    };
+    let mut cursor = 0;
+    for (i, &digit) in digits.iter().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            out[cursor] = group_char;
+            cursor += 1;
+        }
+        out[cursor] = digit;
+        cursor += 1;
+    }
+    total
+}
+
+/// Format one Prometheus textfile metric line, e.g.
+/// `sd_rows_written{card="A",state="ok"} 1234` plus a newline, returns bytes
+/// written (0 if `buffer` is too small).
+///
+/// Label values are escaped as the exposition format requires (backslash,
+/// double quote and newline); `name` and the label names are written as
+/// given, so they must already be valid metric and label names. With no
+/// labels the braces are left out.
+pub fn format_prom_line(
+    buffer: &mut [u8],
+    name: &str,
+    value: u64,
+    labels: &[(&str, &str)],
+) -> usize {
+    let mut cursor = 0;
+    let mut push = |bytes: &[u8]| -> Option<()> {
+        buffer
+            .get_mut(cursor..cursor + bytes.len())?
+            .copy_from_slice(bytes);
+        cursor += bytes.len();
+        Some(())
+    };
+    let mut write = || -> Option<()> {
+        push(name.as_bytes())?;
+        for (i, (label, label_value)) in labels.iter().enumerate() {
+            push(if i == 0 { b"{" } else { b"," })?;
+            push(label.as_bytes())?;
+            push(b"=\"")?;
+            for &byte in label_value.as_bytes() {
+                match byte {
+                    b'\\' => push(b"\\\\")?,
+                    b'"' => push(b"\\\"")?,
+                    b'\n' => push(b"\\n")?,
+                    _ => push(&[byte])?,
+                }
+            }
+            push(b"\"")?;
+        }
+        if !labels.is_empty() {
+            push(b"}")?;
+        }
+        let mut value_buf = itoa::Buffer::new();
+        push(b" ")?;
+        push(value_buf.format(value).as_bytes())?;
+        push(b"\n")
+    };
+    match write() {
+        Some(()) => cursor,
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use embassy_futures::block_on;
+    use embedded_sdmmc::FilenameError;
+
+    use super::*;
+
+    /// Always returns 0, picking the first of the random characters
+    fn zeros() -> u32 {
+        0
+    }
+
+    #[test]
+    fn filename_prefix_and_extension_are_uppercased() {
+        let mut filename = [0u8; 12];
+        assert_eq!(
+            generate_filename_with(zeros, "log", "bin", &mut filename).ok(),
+            Some("LOG00000.BIN")
+        );
+        assert_eq!(
+            generate_filename_with(zeros, "temp_ab", "csv", &mut filename).ok(),
+            Some("TEMP_AB0.CSV")
+        );
+    }
+
+    #[test]
+    fn filename_without_extension_has_no_dot() {
+        let mut filename = [0xFFu8; 12];
+        assert_eq!(
+            generate_filename_with(zeros, "DATA", "", &mut filename).ok(),
+            Some("DATA0000")
+        );
+        assert_eq!(&filename[8..], &[0; 4]);
+        assert_eq!(
+            generate_filename_with(zeros, "", "", &mut filename).ok(),
+            Some("00000000")
+        );
+    }
+
+    #[test]
+    fn filename_prefix_leaves_a_random_character() {
+        let mut filename = [0u8; 12];
+        assert!(matches!(
+            generate_filename_with(zeros, "ABCDEFGH", "CSV", &mut filename),
+            Err(FilenameError::NameTooLong)
+        ));
+        assert!(matches!(
+            generate_filename_with(zeros, "ABC", "CSVX", &mut filename),
+            Err(FilenameError::NameTooLong)
+        ));
+        assert!(generate_filename_with(zeros, "ABCDEFG", "CSV", &mut filename).is_ok());
+    }
+
+    #[test]
+    fn filename_rejects_characters_fat_does_not_allow() {
+        let mut filename = [0u8; 12];
+        for (prefix, extension) in [
+            ("LOG*", "CSV"),
+            ("MY LOG", "CSV"),
+            ("A.B", "CSV"),
+            ("LOG", "C*"),
+            ("LOG", "C V"),
+            ("LÖG", "CSV"),
+        ] {
+            assert!(
+                matches!(
+                    generate_filename_with(zeros, prefix, extension, &mut filename),
+                    Err(FilenameError::InvalidCharacter)
+                ),
+                "{prefix:?} {extension:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn retry_succeeds_on_the_third_attempt() {
+        let attempts = Cell::new(0);
+        let result = block_on(try_retry_with_backoff("test", || {
+            attempts.set(attempts.get() + 1);
+            let attempt = attempts.get();
+            async move {
+                if attempt < 3 {
+                    Err(attempt)
+                } else {
+                    Ok(attempt)
+                }
+            }
+        }));
+        assert_eq!(result, Ok(3));
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn retry_returns_the_last_error_once_every_attempt_fails() {
+        let attempts = Cell::new(0);
+        let result: Result<(), u8> = block_on(try_retry_with_backoff("test", || {
+            attempts.set(attempts.get() + 1);
+            let attempt = attempts.get();
+            async move { Err(attempt) }
+        }));
+        assert_eq!(result, Err(MAX_RETRIES));
+        assert_eq!(attempts.get(), MAX_RETRIES);
+    }
+}
@@ -0,0 +1,232 @@
+//! Moving logs from a flat root directory into per-day subdirectories
+//!
+//! embedded-sdmmc has no rename, so each file is copied into its day's
+//! directory and then deleted from the root. The root itself is the progress
+//! record: an interrupted migration leaves the unmoved files (and at worst
+//! one partial copy, which is overwritten) in place, so the next boot simply
+//! carries on.
+
+use embedded_sdmmc::{BlockDevice, DirEntry, Directory, Error, ShortFileName, Timestamp};
+
+use crate::fs::{copy_file_between, CopyMode};
+use crate::{CancellableError, TimeSourceExt, YieldBudget};
+
+/// Directory for files whose day can't be determined
+pub const LEGACY_DIR: &str = "LEGACY";
+
+/// Files looked up per directory scan; the root can't be modified mid-scan
+const BATCH: usize = 8;
+
+/// Outcome of [`migrate_to_daily_dirs`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MigrationReport {
+    /// Files moved into a day directory
+    pub moved: usize,
+    /// Matching files still in the root, to be moved on a later boot
+    pub remaining: usize,
+    /// Files that could not be moved and were left in the root
+    pub failed: usize,
+    /// The budget was cancelled before `max_files` files were handled
+    pub cancelled: bool,
+}
+
+/// Move up to `max_files` CSV files whose names start with `prefix` from
+/// `root_dir` into `YYYYMMDD` subdirectories, returning what was done.
+///
+/// The day comes from the file's FAT modification time if it is after 1980
+/// (i.e. was written with a real clock; FAT can't store earlier dates, so a
+/// file written without one reads back as 1980-01-01), otherwise from a
+/// date-style name (`MMDDHHMM.CSV`, as made by
+/// [`crate::generate_dated_filename`]) with the year taken from
+/// `time_source`, otherwise the file goes to [`LEGACY_DIR`]. `max_files`
+/// bounds the work done per boot; call again on the next boot until
+/// `remaining` is 0. If `budget` is cancelled the migration stops part way
+/// through the file in progress, setting `cancelled`; that file stays in the
+/// root and its partial copy is overwritten when the next call moves it with
+/// the rest. The report is printed unless [`crate::Verbosity::Quiet`].
+pub async fn migrate_to_daily_dirs<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    root_dir: &Directory<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    prefix: &str,
+    time_source: &T,
+    max_files: usize,
+    buffer: &mut [u8],
+    budget: &mut YieldBudget,
+) -> Result<MigrationReport, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSourceExt,
+{
+    let mut report = MigrationReport::default();
+    // Names that failed stay in the root; skip them so later batches make progress
+    let mut skip = 0;
+    while report.moved + report.failed < max_files && !report.cancelled {
+        let mut batch: [Option<DirEntry>; BATCH] = [const { None }; BATCH];
+        let mut found = 0;
+        let mut seen = 0;
+        root_dir.iterate_dir(|entry| {
+            if !is_candidate(entry, prefix) {
+                return;
+            }
+            seen += 1;
+            if seen > skip && found < BATCH {
+                batch[found] = Some(entry.clone());
+                found += 1;
+            }
+        })?;
+        if found == 0 {
+            break;
+        }
+
+        for entry in batch.iter().flatten() {
+            if report.moved + report.failed >= max_files {
+                break;
+            }
+            match move_to_day_dir(root_dir, entry, time_source, buffer, budget).await {
+                Ok(()) => report.moved += 1,
+                Err(CancellableError::Cancelled { .. }) => {
+                    report.cancelled = true;
+                    break;
+                }
+                Err(CancellableError::Fs(e)) => {
+                    report.failed += 1;
+                    skip += 1;
+                    if crate::verbosity() == crate::Verbosity::Verbose {
+                        println!("Could not migrate {}: {:?}", entry.name, e);
+                    }
+                }
+            }
+            if budget.is_cancelled() {
+                report.cancelled = true;
+                break;
+            }
+        }
+    }
+
+    root_dir.iterate_dir(|entry| {
+        if is_candidate(entry, prefix) {
+            report.remaining += 1;
+        }
+    })?;
+    report.remaining -= report.failed.min(report.remaining);
+
+    if crate::verbosity() != crate::Verbosity::Quiet {
+        println!(
+            "Migration: {} moved, {} remaining, {} failed",
+            report.moved, report.remaining, report.failed
+        );
+    }
+    Ok(report)
+}
+
+fn is_candidate(entry: &DirEntry, prefix: &str) -> bool {
+    !entry.attributes.is_directory()
+        && !entry.attributes.is_volume()
+        && entry.name.extension() == b"CSV"
+        && entry.name.base_name().starts_with(prefix.as_bytes())
+}
+
+async fn move_to_day_dir<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    root_dir: &Directory<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    entry: &DirEntry,
+    time_source: &T,
+    buffer: &mut [u8],
+    budget: &mut YieldBudget,
+) -> Result<(), CancellableError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSourceExt,
+{
+    let mut dir_name = [0u8; 8];
+    let dir_name = match day_of(entry, time_source) {
+        Some(day) => format_day(&day, &mut dir_name),
+        None => LEGACY_DIR,
+    };
+    match root_dir.make_dir_in_dir(dir_name) {
+        Ok(()) | Err(Error::DirAlreadyExists) => {}
+        Err(e) => return Err(e.into()),
+    }
+    let day_dir = root_dir.open_dir(dir_name)?;
+    // Overwrite, since a copy left by an interrupted migration may be partial
+    let copied = copy_file_between(
+        root_dir,
+        &entry.name,
+        &day_dir,
+        &entry.name,
+        buffer,
+        CopyMode::Overwrite,
+        budget,
+    )
+    .await;
+    day_dir.close()?;
+    copied?;
+    Ok(root_dir.delete_file_in_dir(&entry.name)?)
+}
+
+/// The day `entry` was logged, if it can be worked out
+fn day_of<T: TimeSourceExt>(entry: &DirEntry, time_source: &T) -> Option<Timestamp> {
+    // FAT's earliest year, 1980, is what a clock that was never set gives
+    if entry.mtime.year_since_1970 > 10 {
+        return Some(entry.mtime);
+    }
+    let (month, day) = date_from_name(&entry.name)?;
+    if !time_source.is_real() {
+        return None;
+    }
+    let now = time_source.get_timestamp();
+    // A date later in the year than today must be from last year
+    let year_since_1970 = if (month, day) > (now.zero_indexed_month, now.zero_indexed_day) {
+        now.year_since_1970.checked_sub(1)?
+    } else {
+        now.year_since_1970
+    };
+    Some(Timestamp {
+        year_since_1970,
+        zero_indexed_month: month,
+        zero_indexed_day: day,
+        hours: 0,
+        minutes: 0,
+        seconds: 0,
+    })
+}
+
+/// Zero-indexed month and day from an `MMDDHHMM` base name
+fn date_from_name(name: &ShortFileName) -> Option<(u8, u8)> {
+    let base = name.base_name();
+    if base.len() != 8 || !base.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let pair = |i: usize| (base[i] - b'0') * 10 + (base[i + 1] - b'0');
+    let (month, day) = (pair(0), pair(2));
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || pair(4) > 23 || pair(6) > 59 {
+        return None;
+    }
+    Some((month - 1, day - 1))
+}
+
+/// Format `day` as "YYYYMMDD" into `buffer`
+fn format_day<'a>(day: &Timestamp, buffer: &'a mut [u8; 8]) -> &'a str {
+    let year = 1970 + u16::from(day.year_since_1970);
+    let fields = [
+        (year / 100) as u8,
+        (year % 100) as u8,
+        day.zero_indexed_month + 1,
+        day.zero_indexed_day + 1,
+    ];
+    for (digits, value) in buffer.chunks_exact_mut(2).zip(fields) {
+        digits[0] = b'0' + value / 10 % 10;
+        digits[1] = b'0' + value % 10;
+    }
+    core::str::from_utf8(buffer).unwrap_or(LEGACY_DIR)
+}
@@ -0,0 +1,157 @@
+//! Fixed-size pages for random access on the device
+//!
+//! [`PagedWriter`] packs whole rows into `PAGE`-byte pages and pads each page
+//! with newlines before starting the next, so no row crosses a page boundary
+//! and page `n` always starts at byte `n * PAGE`. [`read_page`] then seeks
+//! straight to any page without scanning the file. The padding shows up as
+//! blank lines, which CSV readers skip.
+
+use embedded_io::{Read, Seek, SeekFrom, Write};
+
+/// Byte used to fill the unused end of each page
+pub const PAGE_PADDING: u8 = b'\n';
+
+/// Errors returned by [`PagedWriter`] and [`read_page`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagedError<E> {
+    /// The underlying file failed
+    Io(E),
+    /// The row is longer than a whole page
+    RowTooLong {
+        /// Length of the row
+        len: usize,
+        /// Page size
+        page: usize,
+    },
+}
+
+/// Writes rows into `PAGE`-byte pages, never splitting a row across two, so
+/// page `n` can be read back with [`read_page`].
+///
+/// The page being filled is held in RAM, so `PAGE` bytes are lost on power
+/// failure unless [`PagedWriter::flush`] is called. Use a multiple of the
+/// 512-byte block size so every page write covers whole blocks.
+pub struct PagedWriter<const PAGE: usize> {
+    page: [u8; PAGE],
+    len: usize,
+    pages_written: u32,
+    /// Padding still owed to a page cut short before this writer started
+    torn_padding: usize,
+}
+
+impl<const PAGE: usize> PagedWriter<PAGE> {
+    /// Start at page 0 of a new file
+    pub const fn new() -> Self {
+        Self::resume(0)
+    }
+
+    /// Continue an existing file of `file_len` bytes, appending at its end.
+    ///
+    /// If power was lost while a page was being written, the file ends part
+    /// way through it; the first page written then starts with the padding
+    /// that completes the torn page, so every later page still starts at a
+    /// multiple of `PAGE`. A row cut off in the torn page stays cut off, as
+    /// [`read_page`] returns it.
+    pub const fn resume(file_len: u64) -> Self {
+        const { assert!(PAGE > 0, "PAGE must be non-zero") };
+        let torn = (file_len % PAGE as u64) as usize;
+        Self {
+            page: [PAGE_PADDING; PAGE],
+            len: 0,
+            pages_written: file_len.div_ceil(PAGE as u64) as u32,
+            torn_padding: if torn == 0 { 0 } else { PAGE - torn },
+        }
+    }
+
+    /// Number of pages written, counting a torn page found by
+    /// [`PagedWriter::resume`], which is also the index of the page being
+    /// filled
+    pub fn pages_written(&self) -> u32 {
+        self.pages_written
+    }
+
+    /// Bytes buffered in the page being filled
+    pub fn pending_bytes(&self) -> usize {
+        self.len
+    }
+
+    /// Add `row` (including its newline) to the current page, first writing
+    /// the page out padded if the row doesn't fit. Returns true if a page
+    /// was written.
+    pub fn push_row<W: Write>(
+        &mut self,
+        out: &mut W,
+        row: &[u8],
+    ) -> Result<bool, PagedError<W::Error>> {
+        if row.len() > PAGE {
+            return Err(PagedError::RowTooLong {
+                len: row.len(),
+                page: PAGE,
+            });
+        }
+        let mut wrote = false;
+        if self.len + row.len() > PAGE {
+            self.flush(out)?;
+            wrote = true;
+        }
+        self.page[self.len..self.len + row.len()].copy_from_slice(row);
+        self.len += row.len();
+        Ok(wrote)
+    }
+
+    /// Write the current page padded to its full size, if it holds any rows.
+    ///
+    /// The next row starts a new page, so flushing often wastes space.
+    pub fn flush<W: Write>(&mut self, out: &mut W) -> Result<(), PagedError<W::Error>> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        while self.torn_padding > 0 {
+            let padding = [PAGE_PADDING; 64];
+            let len = self.torn_padding.min(padding.len());
+            out.write_all(&padding[..len]).map_err(PagedError::Io)?;
+            self.torn_padding -= len;
+        }
+        out.write_all(&self.page).map_err(PagedError::Io)?;
+        self.page.fill(PAGE_PADDING);
+        self.len = 0;
+        self.pages_written += 1;
+        Ok(())
+    }
+}
+
+impl<const PAGE: usize> Default for PagedWriter<PAGE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Read page `n` of a file written by [`PagedWriter`] into `page`, returns
+/// the length of its rows with the trailing padding removed, or 0 if the file
+/// has no page `n`.
+///
+/// A page cut short by power loss is returned as far as it was written.
+pub fn read_page<R: Read + Seek, const PAGE: usize>(
+    file: &mut R,
+    n: u32,
+    page: &mut [u8; PAGE],
+) -> Result<usize, PagedError<R::Error>> {
+    let start = u64::from(n) * PAGE as u64;
+    // Seeking past the end is an error for embedded-sdmmc files
+    if start >= file.seek(SeekFrom::End(0)).map_err(PagedError::Io)? {
+        return Ok(0);
+    }
+    file.seek(SeekFrom::Start(start)).map_err(PagedError::Io)?;
+    let mut filled = 0;
+    while filled < PAGE {
+        match file.read(&mut page[filled..]).map_err(PagedError::Io)? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(page[..filled]
+        .iter()
+        .rposition(|&b| b != PAGE_PADDING)
+        .map_or(0, |last| last + 2)
+        .min(filled))
+}
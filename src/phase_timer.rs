@@ -0,0 +1,67 @@
+//! Boot-time breakdown by init phase
+//!
+//! Card init, volume mount, directory and file open each cost a different
+//! amount depending on the card. [`PhaseTimer`] records how long each phase
+//! took so it is obvious where the time to first row goes.
+
+use embassy_time::{Duration, Instant};
+
+/// Records the duration of up to `N` named phases
+#[derive(Debug, Clone, Copy)]
+pub struct PhaseTimer<const N: usize> {
+    start: Instant,
+    last: Instant,
+    phases: [(&'static str, Duration); N],
+    len: usize,
+}
+
+impl<const N: usize> PhaseTimer<N> {
+    /// Start timing now
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            last: now,
+            phases: [("", Duration::from_ticks(0)); N],
+            len: 0,
+        }
+    }
+
+    /// End the current phase, naming it `name`, and start the next one.
+    /// Phases beyond `N` are still included in [`PhaseTimer::total`] but not listed.
+    pub fn mark(&mut self, name: &'static str) {
+        let now = Instant::now();
+        if let Some(slot) = self.phases.get_mut(self.len) {
+            *slot = (name, now - self.last);
+            self.len += 1;
+        }
+        self.last = now;
+    }
+
+    /// The phases recorded so far, in order
+    pub fn phases(&self) -> &[(&'static str, Duration)] {
+        &self.phases[..self.len]
+    }
+
+    /// Time from [`PhaseTimer::new`] to the last [`PhaseTimer::mark`]
+    pub fn total(&self) -> Duration {
+        self.last - self.start
+    }
+
+    /// Print one line per phase plus the total, unless [`crate::Verbosity::Quiet`]
+    pub fn print(&self) {
+        if crate::verbosity() == crate::Verbosity::Quiet {
+            return;
+        }
+        for (name, took) in self.phases() {
+            println!("    {:<20} {:>6} ms", name, took.as_millis());
+        }
+        println!("    {:<20} {:>6} ms", "total", self.total().as_millis());
+    }
+}
+
+impl<const N: usize> Default for PhaseTimer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,237 @@
+//! Mounting a card for reading only
+//!
+//! [`ReadOnlySd`] owns the volume manager and offers only read-side
+//! helpers, so code holding one has no way to reach a write: there is no
+//! method that returns a directory or a writable file. Underneath, the
+//! block device is wrapped in [`ReadOnlyDevice`], which refuses every
+//! write, so not even a bug in the filesystem driver can change the card.
+//!
+//! embedded-sdmmc writes nothing when mounting a volume or opening and
+//! closing files read-only: there is no dirty bit or access time to
+//! update. Closing the volume is another matter, since on FAT32 it writes
+//! the FSInfo sector back, so [`ReadOnlySd::close`] hands back the device
+//! without closing the volume; nothing was changed, so nothing is lost.
+
+use embedded_sdmmc::{
+    Block, BlockCount, BlockDevice, BlockIdx, DirEntry, Directory, Error, Mode, RawVolume,
+    TimeSource, VolumeIdx, VolumeManager,
+};
+
+use crate::checksum::{checksum_file, verify_checksum_sidecar, ChecksumStatus};
+use crate::config::{read_config, ConfigLineError};
+use crate::fs::read_file_chunks;
+use crate::{CancellableError, YieldBudget};
+
+/// Errors from a [`ReadOnlyDevice`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadOnlyDeviceError<E> {
+    /// The wrapped device failed
+    Device(E),
+    /// Something tried to write; nothing was written
+    WriteRefused,
+}
+
+/// Block device wrapper that reads through and refuses every write
+pub struct ReadOnlyDevice<D> {
+    inner: D,
+}
+
+impl<D: BlockDevice> ReadOnlyDevice<D> {
+    /// Wrap `inner`
+    pub fn new(inner: D) -> Self {
+        Self { inner }
+    }
+
+    /// The wrapped device
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for ReadOnlyDevice<D> {
+    type Error = ReadOnlyDeviceError<D::Error>;
+
+    fn read(&self, blocks: &mut [Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        self.inner
+            .read(blocks, start_block_idx)
+            .map_err(ReadOnlyDeviceError::Device)
+    }
+
+    fn write(&self, _blocks: &[Block], _start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        Err(ReadOnlyDeviceError::WriteRefused)
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        self.inner.num_blocks().map_err(ReadOnlyDeviceError::Device)
+    }
+}
+
+/// Filesystem error from a [`ReadOnlySd`]
+pub type ReadOnlyError<E> = Error<ReadOnlyDeviceError<E>>;
+
+/// Why [`ReadOnlySd::health_check`] failed
+#[derive(Debug, Clone)]
+pub enum ReadOnlyHealthError<E: core::fmt::Debug> {
+    /// `name` could not be read to the end
+    Unreadable {
+        /// File that failed
+        name: usize,
+        /// The error it got
+        error: ReadOnlyError<E>,
+    },
+    /// `name` doesn't match its checksum sidecar
+    ChecksumMismatch {
+        /// Index into the names passed to the check
+        name: usize,
+    },
+    /// The budget was cancelled while checking `name`; the names before it
+    /// passed
+    Cancelled {
+        /// Index into the names passed to the check
+        name: usize,
+    },
+}
+
+/// A mounted volume that can only be read
+pub struct ReadOnlySd<
+    D: BlockDevice,
+    T: TimeSource,
+    const MAX_DIRS: usize = 4,
+    const MAX_FILES: usize = 4,
+    const MAX_VOLUMES: usize = 1,
+> {
+    volume_mgr: VolumeManager<ReadOnlyDevice<D>, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    volume: RawVolume,
+}
+
+impl<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>
+    ReadOnlySd<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    /// Mount `volume` of `device` for reading
+    pub fn open(
+        device: D,
+        time_source: T,
+        volume: VolumeIdx,
+    ) -> Result<Self, ReadOnlyError<D::Error>> {
+        let volume_mgr =
+            VolumeManager::new_with_limits(ReadOnlyDevice::new(device), time_source, 0);
+        let volume = volume_mgr.open_raw_volume(volume)?;
+        Ok(Self { volume_mgr, volume })
+    }
+
+    /// Call `on_entry` for each entry in the root directory
+    pub fn list_dir<F>(&self, on_entry: F) -> Result<(), ReadOnlyError<D::Error>>
+    where
+        F: FnMut(&DirEntry),
+    {
+        self.root()?.iterate_dir(on_entry)
+    }
+
+    /// Read `name` from the root directory through `buffer`, handing each
+    /// chunk to `on_chunk`; see [`crate::read_file_chunks`]
+    pub fn read_file<F>(
+        &self,
+        name: &str,
+        buffer: &mut [u8],
+        on_chunk: F,
+    ) -> Result<u32, ReadOnlyError<D::Error>>
+    where
+        F: FnMut(&[u8]),
+    {
+        read_file_chunks(&self.root()?, name, buffer, on_chunk)
+    }
+
+    /// Parse the `key=value` file `name`; see [`crate::read_config`]
+    pub fn read_config<F>(&self, name: &str, on_entry: F) -> Result<(), ReadOnlyError<D::Error>>
+    where
+        F: FnMut(u32, Result<(&str, &str), ConfigLineError>),
+    {
+        let root = self.root()?;
+        let mut file = root.open_file_in_dir(name, Mode::ReadOnly)?;
+        let result = read_config(&mut file, on_entry);
+        file.close()?;
+        result
+    }
+
+    /// CRC-32 of `name`; see [`crate::checksum_file`]
+    pub async fn checksum_file(
+        &self,
+        name: &str,
+        buffer: &mut [u8],
+        budget: &mut YieldBudget,
+    ) -> Result<u32, CancellableError<ReadOnlyDeviceError<D::Error>>> {
+        checksum_file(&self.root()?, name, buffer, budget).await
+    }
+
+    /// Compare `name` with its checksum sidecar; see
+    /// [`crate::verify_checksum_sidecar`]
+    pub async fn verify_checksum_sidecar(
+        &self,
+        name: &str,
+        buffer: &mut [u8],
+        budget: &mut YieldBudget,
+    ) -> Result<ChecksumStatus, CancellableError<ReadOnlyDeviceError<D::Error>>> {
+        verify_checksum_sidecar(&self.root()?, name, buffer, budget).await
+    }
+
+    /// Check that each of `names` can be read to the end and, if it has a
+    /// checksum sidecar, matches it. Unlike [`crate::self_test`] this
+    /// creates no scratch file. Returns the number of files whose checksum
+    /// was verified. Checksumming spends `budget` as it goes, stopping with
+    /// [`ReadOnlyHealthError::Cancelled`] if it is cancelled.
+    pub async fn health_check(
+        &self,
+        names: &[&str],
+        buffer: &mut [u8],
+        budget: &mut YieldBudget,
+    ) -> Result<u32, ReadOnlyHealthError<D::Error>> {
+        let mut verified = 0;
+        for (index, name) in names.iter().enumerate() {
+            let failed = |error| match error {
+                CancellableError::Fs(error) => {
+                    ReadOnlyHealthError::Unreadable { name: index, error }
+                }
+                CancellableError::Cancelled { .. } => {
+                    ReadOnlyHealthError::Cancelled { name: index }
+                }
+            };
+            match self.verify_checksum_sidecar(name, buffer, budget).await {
+                Ok(ChecksumStatus::Match) => verified += 1,
+                Ok(ChecksumStatus::Mismatch { .. }) => {
+                    return Err(ReadOnlyHealthError::ChecksumMismatch { name: index })
+                }
+                // No usable sidecar: reading the whole file is all we can check
+                Ok(ChecksumStatus::BadSidecar) | Err(CancellableError::Fs(Error::NotFound)) => {
+                    self.checksum_file(name, buffer, budget)
+                        .await
+                        .map_err(failed)?;
+                }
+                Err(e) => return Err(failed(e)),
+            }
+        }
+        Ok(verified)
+    }
+
+    /// Unmount, returning the device. The volume is dropped rather than
+    /// closed, as closing a FAT32 volume rewrites its FSInfo sector, which
+    /// the device would refuse.
+    pub fn close(self) -> D {
+        let (device, _) = self.volume_mgr.free();
+        device.into_inner()
+    }
+
+    fn root(
+        &self,
+    ) -> Result<
+        Directory<'_, ReadOnlyDevice<D>, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+        ReadOnlyError<D::Error>,
+    > {
+        Ok(self
+            .volume_mgr
+            .open_root_dir(self.volume)?
+            .to_directory(&self.volume_mgr))
+    }
+}
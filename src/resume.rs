@@ -0,0 +1,154 @@
+//! Resumable transfers of large files
+//!
+//! The offset of the last byte successfully sent is kept in a small marker
+//! file next to the data file (`LOG00001.CSV` → `LOG00001.POS`), so an upload
+//! interrupted by a dropped connection or a reboot continues where it stopped
+//! instead of starting over.
+
+use embedded_sdmmc::{BlockDevice, Directory, Error, FilenameError, Mode, TimeSource};
+
+use crate::fs::replace_extension;
+use crate::read_file_chunks;
+
+/// Extension used for transfer position marker files
+pub const POSITION_EXTENSION: &str = "POS";
+
+/// Read the saved transfer position for `data_name`, or 0 if none was saved
+/// (or the marker is unreadable).
+pub fn load_position<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    data_name: &str,
+) -> Result<u32, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut name_buf = [0u8; 12];
+    let name = marker_name(data_name, &mut name_buf)?;
+
+    let mut digits = [0u8; 10];
+    let mut len = 0;
+    let mut buffer = [0u8; 16];
+    match read_file_chunks(dir, name, &mut buffer, |chunk| {
+        let take = chunk.len().min(digits.len() - len);
+        digits[len..len + take].copy_from_slice(&chunk[..take]);
+        len += take;
+    }) {
+        Ok(_) => {}
+        Err(Error::NotFound) => return Ok(0),
+        Err(e) => return Err(e),
+    }
+    let end = digits[..len]
+        .iter()
+        .position(|b| !b.is_ascii_digit())
+        .unwrap_or(len);
+    Ok(core::str::from_utf8(&digits[..end])
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0))
+}
+
+/// Record that everything before `offset` in `data_name` has been sent
+pub fn save_position<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    data_name: &str,
+    offset: u32,
+) -> Result<(), Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut name_buf = [0u8; 12];
+    let name = marker_name(data_name, &mut name_buf)?;
+    let mut itoa_buf = itoa::Buffer::new();
+    let digits = itoa_buf.format(offset).as_bytes();
+    let mut line = [0u8; 11];
+    line[..digits.len()].copy_from_slice(digits);
+    line[digits.len()] = b'\n';
+
+    let file = dir.open_file_in_dir(name, Mode::ReadWriteCreateOrTruncate)?;
+    file.write(&line[..=digits.len()])?;
+    file.close()
+}
+
+/// Delete the position marker for `data_name` once its transfer is complete.
+/// Succeeds if there was no marker.
+pub fn clear_position<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    data_name: &str,
+) -> Result<(), Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut name_buf = [0u8; 12];
+    match dir.delete_file_in_dir(marker_name(data_name, &mut name_buf)?) {
+        Ok(()) | Err(Error::NotFound) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read `name` from byte `start` onwards through `buffer`, handing each chunk
+/// and its file offset to `on_chunk`. Stops early if `on_chunk` returns false.
+///
+/// Returns the offset just past the last chunk `on_chunk` accepted, which is
+/// the value to pass to [`save_position`]. Fails with `Error::InvalidOffset`
+/// if `start` is beyond the end of the file, e.g. because it was replaced.
+pub fn read_file_chunks_from<
+    D,
+    T,
+    F,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    name: &str,
+    start: u32,
+    buffer: &mut [u8],
+    mut on_chunk: F,
+) -> Result<u32, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    F: FnMut(u32, &[u8]) -> bool,
+{
+    let file = dir.open_file_in_dir(name, Mode::ReadOnly)?;
+    file.seek_from_start(start)?;
+    let mut offset = start;
+    loop {
+        let n = file.read(buffer)?;
+        if n == 0 || !on_chunk(offset, &buffer[..n]) {
+            break;
+        }
+        offset += n as u32;
+    }
+    file.close()?;
+    Ok(offset)
+}
+
+fn marker_name<'a, E>(data_name: &str, buffer: &'a mut [u8; 12]) -> Result<&'a str, Error<E>>
+where
+    E: core::fmt::Debug,
+{
+    replace_extension(data_name, POSITION_EXTENSION, buffer)
+        .ok_or(Error::FilenameError(FilenameError::NameTooLong))
+}
@@ -0,0 +1,384 @@
+//! Retry and backoff decisions as plain arithmetic
+//!
+//! Nothing here sleeps, allocates or touches hardware, so the same policy can
+//! drive [`crate::retry_with_backoff`] and the retries of unrelated
+//! peripherals (a modem, a GPS) alike. Times are in milliseconds.
+
+use embassy_time::Duration;
+
+/// How long to wait between attempts
+///
+/// Delays are stored as whole milliseconds in a `u32`, the unit the retry
+/// budget is kept in, rather than as [`Duration`]s; build them from
+/// `Duration`s with [`BackoffStrategy::fixed`] and
+/// [`BackoffStrategy::exponential`]. Longer delays saturate at `u32::MAX`
+/// ms, about 49 days.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackoffStrategy {
+    /// The same delay after every failure
+    Fixed {
+        /// Delay in milliseconds
+        delay_ms: u32,
+    },
+    /// `initial_ms` after the first failure, multiplied by `factor` after
+    /// each one after that, never more than `cap_ms`
+    Exponential {
+        /// Delay after the first failure in milliseconds
+        initial_ms: u32,
+        /// Growth per attempt, e.g. 2 to double; 0 is treated as 1, a fixed
+        /// delay
+        factor: u32,
+        /// Longest delay in milliseconds
+        cap_ms: u32,
+    },
+}
+
+impl BackoffStrategy {
+    /// What [`crate::retry_with_backoff`] uses: 500 ms between attempts
+    pub const DEFAULT: Self = BackoffStrategy::Fixed { delay_ms: 500 };
+
+    /// The same `delay` after every failure
+    pub const fn fixed(delay: Duration) -> Self {
+        BackoffStrategy::Fixed {
+            delay_ms: millis_u32(delay),
+        }
+    }
+
+    /// `base` after the first failure, multiplied by `factor` after each one
+    /// after that, never more than `max`
+    ///
+    /// ```
+    /// use embassy_time::Duration;
+    /// use esp32_sdcard::BackoffStrategy;
+    ///
+    /// let backoff =
+    ///     BackoffStrategy::exponential(Duration::from_millis(100), 2, Duration::from_secs(1));
+    /// assert_eq!(
+    ///     backoff,
+    ///     BackoffStrategy::Exponential {
+    ///         initial_ms: 100,
+    ///         factor: 2,
+    ///         cap_ms: 1000,
+    ///     }
+    /// );
+    /// ```
+    pub const fn exponential(base: Duration, factor: u32, max: Duration) -> Self {
+        BackoffStrategy::Exponential {
+            initial_ms: millis_u32(base),
+            factor,
+            cap_ms: millis_u32(max),
+        }
+    }
+
+    /// Delay in milliseconds after failed attempt number `attempt` (1 for
+    /// the first attempt): `min(initial_ms * factor^(attempt - 1), cap_ms)`
+    /// when exponential, saturating rather than overflowing. Exponential
+    /// delays never decrease from one attempt to the next and never exceed
+    /// the cap.
+    ///
+    /// ```
+    /// use esp32_sdcard::BackoffStrategy;
+    ///
+    /// let backoff = BackoffStrategy::Exponential {
+    ///     initial_ms: 100,
+    ///     factor: 2,
+    ///     cap_ms: 1000,
+    /// };
+    /// assert_eq!(backoff.delay_ms(1), 100);
+    /// assert_eq!(backoff.delay_ms(2), 200);
+    /// assert_eq!(backoff.delay_ms(4), 800);
+    /// assert_eq!(backoff.delay_ms(5), 1000);
+    /// assert_eq!(BackoffStrategy::DEFAULT.delay_ms(5), 500);
+    /// ```
+    pub fn delay_ms(&self, attempt: u8) -> u32 {
+        match *self {
+            BackoffStrategy::Fixed { delay_ms } => delay_ms,
+            BackoffStrategy::Exponential {
+                initial_ms,
+                factor,
+                cap_ms,
+            } => {
+                let growth = u64::from(factor.max(1))
+                    .checked_pow(u32::from(attempt.saturating_sub(1)))
+                    .unwrap_or(u64::MAX);
+                u64::from(initial_ms)
+                    .saturating_mul(growth)
+                    .min(u64::from(cap_ms)) as u32
+            }
+        }
+    }
+}
+
+impl Default for BackoffStrategy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Spread `delay_ms` by up to ±`jitter_percent` percent (capped at 100),
+/// using `random` as the source of randomness, so devices that failed
+/// together don't all retry at the same moment.
+///
+/// ```
+/// use esp32_sdcard::apply_jitter;
+///
+/// // 10% of 1000 ms spreads the delay over 900..=1100 ms
+/// assert_eq!(apply_jitter(1000, 10, 0), 900);
+/// assert_eq!(apply_jitter(1000, 10, 100), 1000);
+/// assert_eq!(apply_jitter(1000, 10, 200), 1100);
+/// assert_eq!(apply_jitter(1000, 0, 12345), 1000);
+/// ```
+pub fn apply_jitter(delay_ms: u32, jitter_percent: u8, random: u32) -> u32 {
+    let spread = u64::from(delay_ms) * u64::from(jitter_percent.min(100)) / 100;
+    if spread == 0 {
+        return delay_ms;
+    }
+    // Offset in 0..=2*spread, centred on the original delay
+    let offset = u64::from(random) % (2 * spread + 1);
+    (u64::from(delay_ms) + offset - spread).min(u64::from(u32::MAX)) as u32
+}
+
+/// Tracks time spent on an operation against a total time budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryBudget {
+    budget_ms: u32,
+    spent_ms: u32,
+}
+
+impl RetryBudget {
+    /// A budget of `budget_ms`; `u32::MAX` is effectively unlimited
+    pub const fn new(budget_ms: u32) -> Self {
+        Self {
+            budget_ms,
+            spent_ms: 0,
+        }
+    }
+
+    /// Record `ms` spent on attempts or waiting
+    pub fn spend(&mut self, ms: u32) {
+        self.spent_ms = self.spent_ms.saturating_add(ms);
+    }
+
+    /// Milliseconds left, 0 once exhausted
+    pub fn remaining_ms(&self) -> u32 {
+        self.budget_ms.saturating_sub(self.spent_ms)
+    }
+
+    /// True if waiting `delay_ms` more would still be within budget
+    pub fn allows(&self, delay_ms: u32) -> bool {
+        delay_ms <= self.remaining_ms()
+    }
+}
+
+/// True if another attempt should follow failed attempt number `attempt`:
+/// fewer than `max_attempts` have been made and waiting `delay_ms` first
+/// fits in `budget`
+///
+/// ```
+/// use esp32_sdcard::{should_retry, RetryBudget};
+///
+/// let mut budget = RetryBudget::new(1000);
+/// assert!(should_retry(1, 3, 500, &budget));
+/// assert!(!should_retry(3, 3, 500, &budget));
+/// budget.spend(600);
+/// assert!(!should_retry(2, 3, 500, &budget));
+/// ```
+pub fn should_retry(attempt: u8, max_attempts: u8, delay_ms: u32, budget: &RetryBudget) -> bool {
+    attempt < max_attempts && budget.allows(delay_ms)
+}
+
+/// Jitter [`crate::esp::retry_with_jitter`] applies to each delay, in percent
+pub const DEFAULT_JITTER_PERCENT: u8 = 25;
+
+/// Attempt count, backoff, jitter and time budget for [`crate::retry_with_backoff_cfg`]
+///
+/// Times are kept in milliseconds (`budget_ms`, and the delays in
+/// [`BackoffStrategy`]) so the budget arithmetic needs no conversions;
+/// [`RetryConfig::new`], [`BackoffStrategy::fixed`] and
+/// [`BackoffStrategy::exponential`] take [`Duration`]s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryConfig {
+    /// Attempts to make in total; 0 is treated as 1
+    pub max_retries: u8,
+    /// Delay between attempts
+    pub backoff: BackoffStrategy,
+    /// Spread each delay by up to this many percent either way (capped at
+    /// 100), when the retry helper is given a random source; 0 for exact
+    /// delays
+    pub jitter_percent: u8,
+    /// Total time to spend waiting between attempts in milliseconds; no
+    /// more attempts are made once the next delay would exceed it.
+    /// `u32::MAX` is effectively unlimited.
+    pub budget_ms: u32,
+}
+
+impl RetryConfig {
+    /// What [`crate::retry_with_backoff`] uses: [`crate::MAX_RETRIES`]
+    /// attempts, 500 ms apart
+    pub const DEFAULT: Self = Self::new(crate::MAX_RETRIES, Duration::from_millis(500));
+
+    /// `max_retries` attempts a fixed `delay` apart; 0 attempts becomes 1
+    pub const fn new(max_retries: u8, delay: Duration) -> Self {
+        Self {
+            max_retries: if max_retries == 0 { 1 } else { max_retries },
+            backoff: BackoffStrategy::fixed(delay),
+            jitter_percent: 0,
+            budget_ms: u32::MAX,
+        }
+    }
+
+    /// Use `backoff` to compute the delay after each failed attempt, e.g.
+    /// short waits for a card that is slow to come up and longer ones once
+    /// the bus looks wedged:
+    ///
+    /// ```text
+    /// RetryConfig::new(6, Duration::from_millis(100)).with_backoff(BackoffStrategy::exponential(
+    ///     Duration::from_millis(100),
+    ///     2,
+    ///     Duration::from_secs(2),
+    /// ))
+    /// ```
+    pub const fn with_backoff(mut self, backoff: BackoffStrategy) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Spread each delay by up to `percent` percent either way; see
+    /// [`crate::esp::try_retry_with_jitter`]
+    pub const fn with_jitter(mut self, percent: u8) -> Self {
+        self.jitter_percent = percent;
+        self
+    }
+
+    /// Stop retrying once waiting for the next attempt would take the total
+    /// time spent waiting past `budget_ms`, e.g. to give up on a card before
+    /// a watchdog fires however many attempts are left
+    pub const fn with_budget(mut self, budget_ms: u32) -> Self {
+        self.budget_ms = budget_ms;
+        self
+    }
+
+    /// Attempts to make, at least 1 even if `max_retries` was set to 0
+    pub fn attempts(&self) -> u8 {
+        self.max_retries.max(1)
+    }
+
+    /// Delay after failed attempt number `attempt` in milliseconds; see
+    /// [`BackoffStrategy::delay_ms`]
+    ///
+    /// ```
+    /// use embassy_time::Duration;
+    /// use esp32_sdcard::RetryConfig;
+    ///
+    /// let config = RetryConfig::new(4, Duration::from_millis(250));
+    /// assert_eq!(config.delay_ms(1), 250);
+    /// assert_eq!(config.delay_ms(3), 250);
+    /// ```
+    pub fn delay_ms(&self, attempt: u8) -> u32 {
+        self.backoff.delay_ms(attempt)
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// `duration` in whole milliseconds, saturating at `u32::MAX`
+const fn millis_u32(duration: Duration) -> u32 {
+    let millis = duration.as_millis();
+    if millis > u32::MAX as u64 {
+        u32::MAX
+    } else {
+        millis as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_monotone(backoff: BackoffStrategy, cap_ms: u32) {
+        let mut previous = 0;
+        for attempt in 1..=u8::MAX {
+            let delay = backoff.delay_ms(attempt);
+            assert!(delay >= previous, "{backoff:?} shrank at attempt {attempt}");
+            assert!(
+                delay <= cap_ms,
+                "{backoff:?} passed its cap at attempt {attempt}"
+            );
+            previous = delay;
+        }
+    }
+
+    #[test]
+    fn exponential_delays_never_decrease_or_pass_the_cap() {
+        for factor in [0, 1, 2, 3, 10, u32::MAX] {
+            for (initial_ms, cap_ms) in [(1, 1000), (100, 2000), (500, 500), (7, u32::MAX)] {
+                assert_monotone(
+                    BackoffStrategy::Exponential {
+                        initial_ms,
+                        factor,
+                        cap_ms,
+                    },
+                    cap_ms,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn exponential_saturates_at_the_cap() {
+        let backoff = BackoffStrategy::Exponential {
+            initial_ms: u32::MAX / 2,
+            factor: 3,
+            cap_ms: u32::MAX,
+        };
+        assert_eq!(backoff.delay_ms(1), u32::MAX / 2);
+        assert_eq!(backoff.delay_ms(2), u32::MAX);
+        assert_eq!(backoff.delay_ms(u8::MAX), u32::MAX);
+    }
+
+    #[test]
+    fn attempt_zero_is_treated_as_the_first() {
+        let backoff = BackoffStrategy::Exponential {
+            initial_ms: 100,
+            factor: 2,
+            cap_ms: 1000,
+        };
+        assert_eq!(backoff.delay_ms(0), backoff.delay_ms(1));
+    }
+
+    #[test]
+    fn fixed_delay_is_constant() {
+        assert_monotone(BackoffStrategy::DEFAULT, 500);
+        assert_eq!(BackoffStrategy::DEFAULT.delay_ms(1), 500);
+        assert_eq!(BackoffStrategy::DEFAULT.delay_ms(u8::MAX), 500);
+    }
+
+    #[test]
+    fn durations_saturate_at_u32_max_milliseconds() {
+        assert_eq!(
+            BackoffStrategy::fixed(Duration::from_secs(u64::from(u32::MAX))),
+            BackoffStrategy::Fixed { delay_ms: u32::MAX }
+        );
+        assert_eq!(
+            BackoffStrategy::exponential(Duration::from_micros(1500), 2, Duration::from_secs(5)),
+            BackoffStrategy::Exponential {
+                initial_ms: 1,
+                factor: 2,
+                cap_ms: 5000,
+            }
+        );
+    }
+
+    #[test]
+    fn jitter_stays_within_its_spread() {
+        for random in [0, 1, 99, 12_345, u32::MAX] {
+            let delay = apply_jitter(1000, 25, random);
+            assert!((750..=1250).contains(&delay), "{delay}");
+        }
+        assert_eq!(apply_jitter(u32::MAX, 100, u32::MAX), u32::MAX);
+    }
+}
@@ -0,0 +1,140 @@
+//! Wall-clock [`TimeSource`] for real file timestamps
+//!
+//! With [`crate::DummyTimeSource`] every file is stamped 1970-01-01, so a PC
+//! can't sort them by date. [`RtcTimeSource`] reads the time from the ESP32's
+//! RTC ([`RtcTimeSource::from_rtc`]), from a function (e.g. a GPS receiver)
+//! or from embassy's uptime clock, once it has been seeded with
+//! [`RtcTimeSource::set_epoch`]:
+//!
+//! ```text
+//! static CLOCK: RtcTimeSource = RtcTimeSource::new();
+//! CLOCK.set_epoch(ntp_seconds);
+//! let volume_mgr = VolumeManager::new(sdcard, &CLOCK);
+//! ```
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use embassy_time::Instant;
+use embedded_sdmmc::{TimeSource, Timestamp};
+
+use crate::time::{epoch_to_timestamp, TimeSourceExt, FAT_MIN_EPOCH};
+
+/// A clock that keeps the time it is set to, in microseconds since the Unix
+/// epoch, e.g. the ESP32's RTC (see `esp.rs`)
+pub(crate) trait SettableClock {
+    fn now_us(&self) -> u64;
+    fn set_us(&self, us: u64);
+}
+
+/// Where [`RtcTimeSource`] gets the time from
+#[derive(Clone, Copy)]
+enum Clock<'a> {
+    Uptime,
+    // Only the esp module builds one
+    #[cfg_attr(not(feature = "esp"), allow(dead_code))]
+    Settable(&'a dyn SettableClock),
+    Function(fn() -> u64),
+}
+
+/// [`TimeSource`] that turns Unix time into FAT timestamps, taken as UTC
+///
+/// Before it is seeded, the uptime clock reads as 1980-01-01, the earliest
+/// time FAT can store, and [`TimeSourceExt::is_real`] is false. Times
+/// outside FAT's range (1980 to 2107) are clamped.
+pub struct RtcTimeSource<'a> {
+    clock: Clock<'a>,
+    /// Seconds to add to the clock's own reading
+    offset: Mutex<Cell<Option<i64>>>,
+}
+
+impl<'a> RtcTimeSource<'a> {
+    /// Count forward from embassy's uptime clock once seeded with
+    /// [`RtcTimeSource::set_epoch`]. The time is lost on reset.
+    pub const fn new() -> Self {
+        Self::with_clock(Clock::Uptime)
+    }
+
+    /// Read `clock`, which [`RtcTimeSource::set_epoch`] sets
+    #[cfg_attr(not(feature = "esp"), allow(dead_code))]
+    pub(crate) const fn from_settable(clock: &'a dyn SettableClock) -> Self {
+        Self::with_clock(Clock::Settable(clock))
+    }
+
+    /// Call `epoch_secs` for the seconds since the Unix epoch, e.g. from an
+    /// external RTC chip. [`RtcTimeSource::set_epoch`] corrects it by an
+    /// offset.
+    pub const fn from_fn(epoch_secs: fn() -> u64) -> Self {
+        Self::with_clock(Clock::Function(epoch_secs))
+    }
+
+    const fn with_clock(clock: Clock<'a>) -> Self {
+        Self {
+            clock,
+            offset: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    /// Set the current time to `epoch_secs` seconds since the Unix epoch,
+    /// e.g. from NTP or GPS at boot; the clock ticks forward from there
+    pub fn set_epoch(&self, epoch_secs: u64) {
+        if let Clock::Settable(clock) = self.clock {
+            clock.set_us(epoch_secs.saturating_mul(1_000_000));
+            return;
+        }
+        let offset = epoch_secs as i64 - self.raw_secs() as i64;
+        critical_section::with(|cs| self.offset.borrow(cs).set(Some(offset)));
+    }
+
+    /// Seconds since the Unix epoch, or None if the uptime clock hasn't been
+    /// seeded
+    pub fn epoch(&self) -> Option<u64> {
+        let offset = critical_section::with(|cs| self.offset.borrow(cs).get());
+        let offset = match (self.clock, offset) {
+            (Clock::Uptime, None) => return None,
+            (_, offset) => offset.unwrap_or(0),
+        };
+        Some(self.raw_secs().saturating_add_signed(offset))
+    }
+
+    /// The clock's own reading in seconds
+    fn raw_secs(&self) -> u64 {
+        match self.clock {
+            Clock::Uptime => Instant::now().as_secs(),
+            Clock::Settable(clock) => clock.now_us() / 1_000_000,
+            Clock::Function(epoch_secs) => epoch_secs(),
+        }
+    }
+}
+
+impl Default for RtcTimeSource<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimeSource for RtcTimeSource<'_> {
+    fn get_timestamp(&self) -> Timestamp {
+        epoch_to_timestamp(self.epoch().unwrap_or(FAT_MIN_EPOCH))
+    }
+}
+
+impl TimeSource for &RtcTimeSource<'_> {
+    fn get_timestamp(&self) -> Timestamp {
+        (**self).get_timestamp()
+    }
+}
+
+impl TimeSourceExt for RtcTimeSource<'_> {
+    /// True once the time is set: the uptime clock has been seeded, or the
+    /// RTC or function reads 1980 or later rather than time since boot
+    fn is_real(&self) -> bool {
+        self.epoch().is_some_and(|secs| secs >= FAT_MIN_EPOCH)
+    }
+}
+
+impl TimeSourceExt for &RtcTimeSource<'_> {
+    fn is_real(&self) -> bool {
+        (**self).is_real()
+    }
+}
@@ -0,0 +1,265 @@
+//! Directory scanning that tolerates corrupted entries
+//!
+//! embedded-sdmmc decodes whatever bytes are in a directory slot, so a
+//! corrupted directory yields entries with garbage names, impossible
+//! timestamps or sizes with no clusters. These helpers check each entry for
+//! plausibility and, in [`ScanMode::Tolerant`], skip and count bad ones
+//! instead of letting them poison the whole result.
+
+use embedded_sdmmc::{BlockDevice, ClusterId, DirEntry, Directory, Error, TimeSource};
+
+/// What to do when a directory entry looks corrupted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScanMode {
+    /// Fail with [`ScanError::InvalidEntry`] on the first bad entry, having
+    /// visited only the entries before it
+    Strict,
+    /// Skip bad entries and count them in the [`ScanReport`]
+    #[default]
+    Tolerant,
+}
+
+/// Summary of a directory scan
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ScanReport {
+    /// Valid entries visited (excluding `.`, `..` and the volume label)
+    pub entries: usize,
+    /// Entries skipped because they looked corrupted
+    pub skipped: usize,
+    /// Space-padded 8.3 name bytes of the first skipped entry, for diagnostics
+    pub first_skipped: Option<[u8; 11]>,
+}
+
+/// Errors returned by the directory scanners
+#[derive(Debug, Clone)]
+pub enum ScanError<E: core::fmt::Debug> {
+    /// The filesystem failed
+    Fs(Error<E>),
+    /// [`ScanMode::Strict`] found an entry that looks corrupted; holds its name bytes
+    InvalidEntry([u8; 11]),
+}
+
+impl<E: core::fmt::Debug> From<Error<E>> for ScanError<E> {
+    fn from(e: Error<E>) -> Self {
+        ScanError::Fs(e)
+    }
+}
+
+/// Totals from [`dir_usage`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DirUsage {
+    /// Number of files
+    pub files: usize,
+    /// Number of subdirectories
+    pub dirs: usize,
+    /// Sum of file sizes in bytes
+    pub bytes: u64,
+}
+
+/// Call `on_entry` for every plausible entry in `dir`, skipping `.`, `..` and
+/// the volume label.
+///
+/// In [`ScanMode::Strict`] `on_entry` isn't called for anything after the
+/// first bad entry. embedded-sdmmc can't stop a directory walk part way, so
+/// the rest of the directory is still read, just ignored.
+pub fn scan_dir<D, T, F, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    mode: ScanMode,
+    mut on_entry: F,
+) -> Result<ScanReport, ScanError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    F: FnMut(&DirEntry),
+{
+    let mut report = ScanReport::default();
+    dir.iterate_dir(|entry| {
+        if entry.attributes.is_volume() || is_dot_entry(entry) {
+            return;
+        }
+        if mode == ScanMode::Strict && report.first_skipped.is_some() {
+            return;
+        }
+        if is_plausible(entry) {
+            report.entries += 1;
+            on_entry(entry);
+        } else {
+            report.skipped += 1;
+            report
+                .first_skipped
+                .get_or_insert_with(|| padded_name(entry));
+        }
+    })?;
+
+    match (mode, report.first_skipped) {
+        (ScanMode::Strict, Some(name)) => Err(ScanError::InvalidEntry(name)),
+        _ => Ok(report),
+    }
+}
+
+/// Collect entries of `dir` into `out`, returning how many were stored.
+/// Entries beyond `out.len()` are counted in the report but not stored.
+pub fn list_dir<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    mode: ScanMode,
+    out: &mut [Option<DirEntry>],
+) -> Result<(usize, ScanReport), ScanError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut stored = 0;
+    let report = scan_dir(dir, mode, |entry| {
+        if let Some(slot) = out.get_mut(stored) {
+            *slot = Some(entry.clone());
+            stored += 1;
+        }
+    })?;
+    Ok((stored, report))
+}
+
+/// Order used by [`list_files_sorted`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    /// Oldest modification time first
+    #[default]
+    OldestFirst,
+    /// Newest modification time first
+    NewestFirst,
+}
+
+/// Collect the files (not directories) in `dir` into `out`, sorted by
+/// modification time, returning how many were stored.
+///
+/// Sorting needs every entry in memory, so at most `out.len()` files are
+/// returned: with more files than that, `out` holds the first `out.len()` in
+/// `order` (e.g. the oldest ones with [`SortOrder::OldestFirst`]), which is
+/// what batch processing and pruning want. Each slot is a `DirEntry` of
+/// roughly 40 bytes. Files with equal times keep directory order.
+pub fn list_files_sorted<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    mode: ScanMode,
+    order: SortOrder,
+    out: &mut [Option<DirEntry>],
+) -> Result<(usize, ScanReport), ScanError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut stored = 0;
+    let report = scan_dir(dir, mode, |entry| {
+        if entry.attributes.is_directory() {
+            return;
+        }
+        let comes_before = |other: &DirEntry| match order {
+            SortOrder::OldestFirst => entry.mtime < other.mtime,
+            SortOrder::NewestFirst => entry.mtime > other.mtime,
+        };
+        // Insertion sort into the bounded slice, dropping the last entry when full
+        let position = out[..stored]
+            .iter()
+            .position(|slot| slot.as_ref().is_some_and(comes_before))
+            .unwrap_or(stored);
+        if position == out.len() {
+            return;
+        }
+        if stored < out.len() {
+            stored += 1;
+        }
+        out[position..stored].rotate_right(1);
+        out[position] = Some(entry.clone());
+    })?;
+    Ok((stored, report))
+}
+
+/// Find the file in `dir` with the latest modification time
+pub fn find_newest_file<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    mode: ScanMode,
+) -> Result<(Option<DirEntry>, ScanReport), ScanError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut newest: Option<DirEntry> = None;
+    let report = scan_dir(dir, mode, |entry| {
+        if entry.attributes.is_directory() {
+            return;
+        }
+        if newest.as_ref().is_none_or(|n| entry.mtime > n.mtime) {
+            newest = Some(entry.clone());
+        }
+    })?;
+    Ok((newest, report))
+}
+
+/// Count files, subdirectories and bytes used by files directly in `dir`
+pub fn dir_usage<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    mode: ScanMode,
+) -> Result<(DirUsage, ScanReport), ScanError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut usage = DirUsage::default();
+    let report = scan_dir(dir, mode, |entry| {
+        if entry.attributes.is_directory() {
+            usage.dirs += 1;
+        } else {
+            usage.files += 1;
+            usage.bytes += u64::from(entry.size);
+        }
+    })?;
+    Ok((usage, report))
+}
+
+fn is_dot_entry(entry: &DirEntry) -> bool {
+    matches!(entry.name.base_name(), b"." | b"..") && entry.name.extension().is_empty()
+}
+
+/// Whether an entry could have been written by a working FAT driver
+fn is_plausible(entry: &DirEntry) -> bool {
+    let name_ok = !entry.name.base_name().is_empty()
+        && entry
+            .name
+            .base_name()
+            .iter()
+            .chain(entry.name.extension())
+            .all(|&b| is_valid_sfn_byte(b));
+    let ts = &entry.mtime;
+    let time_ok = ts.zero_indexed_month < 12
+        && ts.zero_indexed_day < 31
+        && ts.hours < 24
+        && ts.minutes < 60
+        && ts.seconds < 60;
+    // A file with data must own at least one cluster
+    let cluster_ok =
+        entry.attributes.is_directory() || entry.size == 0 || entry.cluster != ClusterId::EMPTY;
+    name_ok && time_ok && cluster_ok
+}
+
+fn is_valid_sfn_byte(b: u8) -> bool {
+    b.is_ascii_uppercase() || b.is_ascii_digit() || b >= 0x80 || b"!#$%&'()-@^_`{}~".contains(&b)
+}
+
+fn padded_name(entry: &DirEntry) -> [u8; 11] {
+    let mut name = [b' '; 11];
+    let base = entry.name.base_name();
+    let ext = entry.name.extension();
+    name[..base.len().min(8)].copy_from_slice(&base[..base.len().min(8)]);
+    name[8..8 + ext.len().min(3)].copy_from_slice(&ext[..ext.len().min(3)]);
+    name
+}
@@ -0,0 +1,240 @@
+//! Timestamp lookups in CSV logs
+//!
+//! Rows are appended in time order, so the first row at or after a given time
+//! can be found with O(log n) short reads instead of scanning the whole file.
+
+use embedded_sdmmc::{BlockDevice, Directory, Error, File, Mode, TimeSource};
+
+/// Errors returned by [`seek_to_timestamp`]
+#[derive(Debug, Clone)]
+pub enum SeekError<E: core::fmt::Debug> {
+    /// The filesystem failed
+    Fs(Error<E>),
+    /// A line is longer than the scratch buffer, so its timestamp can't be read
+    LineTooLong,
+}
+
+impl<E: core::fmt::Debug> From<Error<E>> for SeekError<E> {
+    fn from(e: Error<E>) -> Self {
+        SeekError::Fs(e)
+    }
+}
+
+/// Find the byte offset of the first row in `name` whose timestamp column is
+/// at least `target`, or the file length if there is none.
+///
+/// `ts_column` is the zero-based column holding an integer timestamp (e.g.
+/// unix seconds or `uptime_ms`). Lines where that column isn't an integer,
+/// such as the header or `#` comments, are skipped. Rows must be in
+/// non-decreasing timestamp order, except that rows up to `tolerance` out of
+/// order are handled: the search lands `tolerance` early and scans forward
+/// from there. `scratch` must hold the longest line.
+pub fn seek_to_timestamp<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    name: &str,
+    ts_column: usize,
+    target: i64,
+    tolerance: i64,
+    scratch: &mut [u8],
+) -> Result<u64, SeekError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let file = dir.open_file_in_dir(name, Mode::ReadOnly)?;
+    let rows = Rows {
+        file: &file,
+        len: file.length(),
+        ts_column,
+    };
+
+    // Smallest probe offset whose next timestamped row is at or after the early target
+    let early_target = target.saturating_sub(tolerance);
+    let (mut lo, mut hi) = (0, rows.len);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match rows.first_timestamped(mid, scratch)? {
+            Some((_, ts)) if ts < early_target => lo = mid + 1,
+            _ => hi = mid,
+        }
+    }
+
+    // Walk forward past any rows that are early but within the tolerance
+    let mut start = rows.line_start(lo, scratch)?;
+    let found = loop {
+        let Some(row_start) = start else {
+            break rows.len;
+        };
+        let (ts, next) = rows.read_row(row_start, scratch)?;
+        if ts.is_some_and(|ts| ts >= target) {
+            break row_start;
+        }
+        start = (next < rows.len).then_some(next);
+    };
+    file.close()?;
+    Ok(u64::from(found))
+}
+
+/// Difference between the last and first timestamps in column `ts_column`
+/// of `name`, in the column's units, or None if it has no timestamped rows.
+///
+/// Lines without an integer in that column, such as the header and `#`
+/// comments, are skipped. Only the head and tail of the file are read.
+/// `scratch` must hold the longest line.
+pub fn logged_duration<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    name: &str,
+    ts_column: usize,
+    scratch: &mut [u8],
+) -> Result<Option<i64>, SeekError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let file = dir.open_file_in_dir(name, Mode::ReadOnly)?;
+    let rows = Rows {
+        file: &file,
+        len: file.length(),
+        ts_column,
+    };
+    let duration = match rows.first_timestamped(0, scratch)? {
+        Some((first_start, first)) => rows
+            .last_timestamped(first_start, scratch)?
+            .map(|last| last.saturating_sub(first)),
+        None => None,
+    };
+    file.close()?;
+    Ok(duration)
+}
+
+/// Line-oriented reads from a CSV file
+struct Rows<'a, 'f, D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    file: &'a File<'f, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    len: u32,
+    ts_column: usize,
+}
+
+impl<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>
+    Rows<'_, '_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    /// Start of the first line beginning at or after `offset`, if any
+    fn line_start(&self, offset: u32, scratch: &mut [u8]) -> Result<Option<u32>, Error<D::Error>> {
+        if offset == 0 {
+            return Ok((self.len > 0).then_some(0));
+        }
+        // Include the previous byte so a line starting exactly at `offset` is found
+        let mut position = offset - 1;
+        self.file.seek_from_start(position)?;
+        loop {
+            let n = self.file.read(scratch)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            if let Some(i) = scratch[..n].iter().position(|&b| b == b'\n') {
+                let start = position + i as u32 + 1;
+                return Ok((start < self.len).then_some(start));
+            }
+            position += n as u32;
+        }
+    }
+
+    /// Parse the row starting at `start`, returning its timestamp (if it has
+    /// one) and the start of the next line
+    fn read_row(
+        &self,
+        start: u32,
+        scratch: &mut [u8],
+    ) -> Result<(Option<i64>, u32), SeekError<D::Error>> {
+        self.file.seek_from_start(start)?;
+        let mut filled = 0;
+        let line_len = loop {
+            let n = self.file.read(&mut scratch[filled..])?;
+            if let Some(i) = scratch[filled..filled + n].iter().position(|&b| b == b'\n') {
+                break filled + i;
+            }
+            filled += n;
+            if n == 0 {
+                // Last line without a trailing newline
+                break filled;
+            }
+            if filled == scratch.len() {
+                return Err(SeekError::LineTooLong);
+            }
+        };
+        let line = &scratch[..line_len];
+        let next = start + line_len as u32 + 1;
+        Ok((parse_column(line, self.ts_column), next.min(self.len)))
+    }
+
+    /// Timestamp of the last timestamped row, which is at or after `floor`,
+    /// reading back from the end in growing windows
+    fn last_timestamped(
+        &self,
+        floor: u32,
+        scratch: &mut [u8],
+    ) -> Result<Option<i64>, SeekError<D::Error>> {
+        let mut window = (scratch.len() as u32).max(1);
+        loop {
+            let from = self.len.saturating_sub(window).max(floor);
+            let mut last = None;
+            let mut start = self.line_start(from, scratch)?;
+            while let Some(row_start) = start {
+                let (ts, next) = self.read_row(row_start, scratch)?;
+                last = ts.or(last);
+                start = (next < self.len).then_some(next);
+            }
+            if last.is_some() || from == floor {
+                return Ok(last);
+            }
+            window = window.saturating_mul(2);
+        }
+    }
+
+    /// Offset and timestamp of the first timestamped row at or after `offset`
+    fn first_timestamped(
+        &self,
+        offset: u32,
+        scratch: &mut [u8],
+    ) -> Result<Option<(u32, i64)>, SeekError<D::Error>> {
+        let mut start = self.line_start(offset, scratch)?;
+        while let Some(row_start) = start {
+            let (ts, next) = self.read_row(row_start, scratch)?;
+            if let Some(ts) = ts {
+                return Ok(Some((row_start, ts)));
+            }
+            start = (next < self.len).then_some(next);
+        }
+        Ok(None)
+    }
+}
+
+/// Parse column `column` of a CSV line as an integer, allowing surrounding
+/// quotes and whitespace
+fn parse_column(line: &[u8], column: usize) -> Option<i64> {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if line.first() == Some(&b'#') {
+        return None;
+    }
+    let field = line.split(|&b| b == b',').nth(column)?;
+    let field = core::str::from_utf8(field).ok()?.trim().trim_matches('"');
+    field.parse().ok()
+}
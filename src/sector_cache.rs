@@ -0,0 +1,183 @@
+//! A small read cache for the blocks read repeatedly at startup
+//!
+//! Opening the volume, finding the active file and reading its header and
+//! tail each go back to the card for the same handful of blocks.
+//! [`CachedBlockDevice`] wraps the block device and keeps the last few
+//! single-block reads in caller-provided slots. Every write goes through
+//! the wrapper and drops any cached copy of the blocks it touches, so reads
+//! never see stale data.
+
+use core::cell::{Cell, RefCell};
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+
+use crate::checksum::Crc32;
+
+/// Storage for one cached block
+#[derive(Debug, Clone)]
+pub struct CacheSlot {
+    lba: Option<u32>,
+    crc: u32,
+    block: Block,
+    last_used: u32,
+}
+
+impl CacheSlot {
+    /// An empty slot
+    pub fn new() -> Self {
+        Self {
+            lba: None,
+            crc: 0,
+            block: Block::new(),
+            last_used: 0,
+        }
+    }
+}
+
+impl Default for CacheSlot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hit and miss counts of a [`CachedBlockDevice`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// Reads answered from the cache
+    pub hits: u32,
+    /// Reads that went to the card
+    pub misses: u32,
+}
+
+/// Block device wrapper that caches single-block reads in `slots`
+///
+/// Two to four slots cover the startup reads. Each slot is checked against
+/// a CRC before use, so a corrupted slot costs a card read rather than
+/// returning bad data. Call [`CachedBlockDevice::disable`] once steady-state
+/// logging begins; the slots' RAM is free again once the wrapper is dropped
+/// or taken apart with [`CachedBlockDevice::into_inner`].
+pub struct CachedBlockDevice<'a, D> {
+    inner: D,
+    slots: RefCell<&'a mut [CacheSlot]>,
+    enabled: Cell<bool>,
+    clock: Cell<u32>,
+    stats: Cell<CacheStats>,
+}
+
+impl<'a, D: BlockDevice> CachedBlockDevice<'a, D> {
+    /// Wrap `inner`, caching in `slots`
+    pub fn new(inner: D, slots: &'a mut [CacheSlot]) -> Self {
+        for slot in slots.iter_mut() {
+            slot.lba = None;
+        }
+        Self {
+            inner,
+            slots: RefCell::new(slots),
+            enabled: Cell::new(true),
+            clock: Cell::new(0),
+            stats: Cell::new(CacheStats::default()),
+        }
+    }
+
+    /// Stop caching and forget every cached block; reads and writes go
+    /// straight to the card from now on
+    pub fn disable(&self) {
+        self.enabled.set(false);
+        for slot in self.slots.borrow_mut().iter_mut() {
+            slot.lba = None;
+        }
+    }
+
+    /// Hits and misses so far
+    pub fn stats(&self) -> CacheStats {
+        self.stats.get()
+    }
+
+    /// The wrapped device, giving back the slots
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    fn lookup(&self, lba: u32, out: &mut Block) -> bool {
+        let mut slots = self.slots.borrow_mut();
+        let Some(slot) = slots.iter_mut().find(|slot| slot.lba == Some(lba)) else {
+            return false;
+        };
+        if crc_of(&slot.block) != slot.crc {
+            slot.lba = None;
+            return false;
+        }
+        out.contents.copy_from_slice(&slot.block.contents);
+        slot.last_used = self.tick();
+        true
+    }
+
+    fn store(&self, lba: u32, block: &Block) {
+        let mut slots = self.slots.borrow_mut();
+        let Some(slot) = slots
+            .iter_mut()
+            .min_by_key(|slot| slot.lba.map_or(0, |_| slot.last_used + 1))
+        else {
+            return;
+        };
+        slot.block.contents.copy_from_slice(&block.contents);
+        slot.crc = crc_of(block);
+        slot.lba = Some(lba);
+        slot.last_used = self.tick();
+    }
+
+    fn tick(&self) -> u32 {
+        let now = self.clock.get().wrapping_add(1);
+        self.clock.set(now);
+        now
+    }
+
+    fn count(&self, hit: bool) {
+        let mut stats = self.stats.get();
+        if hit {
+            stats.hits = stats.hits.saturating_add(1);
+        } else {
+            stats.misses = stats.misses.saturating_add(1);
+        }
+        self.stats.set(stats);
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for CachedBlockDevice<'_, D> {
+    type Error = D::Error;
+
+    fn read(&self, blocks: &mut [Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        // Multi-block reads are bulk data, not the metadata worth caching
+        if !self.enabled.get() || blocks.len() != 1 {
+            return self.inner.read(blocks, start_block_idx);
+        }
+        let lba = start_block_idx.0;
+        let hit = self.lookup(lba, &mut blocks[0]);
+        self.count(hit);
+        if !hit {
+            self.inner.read(blocks, start_block_idx)?;
+            self.store(lba, &blocks[0]);
+        }
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        let written = start_block_idx.0..start_block_idx.0.saturating_add(blocks.len() as u32);
+        for slot in self.slots.borrow_mut().iter_mut() {
+            if slot.lba.is_some_and(|lba| written.contains(&lba)) {
+                slot.lba = None;
+            }
+        }
+        self.inner.write(blocks, start_block_idx)
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        self.inner.num_blocks()
+    }
+}
+
+fn crc_of(block: &Block) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(&block.contents);
+    crc.finish()
+}
@@ -0,0 +1,142 @@
+//! End-to-end check of the write path: SPI, card and filesystem
+
+use embedded_sdmmc::{BlockDevice, Directory, Error, Mode, TimeSource};
+
+/// Scratch file written and deleted by [`self_test`]
+pub const SELF_TEST_FILENAME: &str = "SELFTEST.TMP";
+
+/// Bytes written by [`self_test`]; more than two blocks so block boundaries are crossed
+const SELF_TEST_LEN: usize = 1100;
+
+/// Stage of [`self_test`] that failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTestStep {
+    /// Removing a scratch file left by an interrupted test
+    Cleanup,
+    /// Creating the scratch file
+    Create,
+    /// Writing the test pattern
+    Write,
+    /// Flushing and closing the file
+    Flush,
+    /// Reopening and reading the file back
+    Read,
+    /// Deleting the scratch file
+    Delete,
+}
+
+/// Why [`self_test`] failed
+#[derive(Debug, Clone)]
+pub enum SelfTestError<E: core::fmt::Debug> {
+    /// A filesystem operation failed at `step`
+    Fs {
+        /// What the test was doing
+        step: SelfTestStep,
+        /// The error it got
+        error: Error<E>,
+    },
+    /// The data read back differs from what was written, starting at `offset`
+    Mismatch {
+        /// First differing byte
+        offset: usize,
+    },
+    /// The file read back is a different length than was written
+    WrongLength {
+        /// Bytes read back
+        read: usize,
+    },
+}
+
+/// Write a known pattern to [`SELF_TEST_FILENAME`] in `dir`, read it back,
+/// compare and delete the file.
+///
+/// Succeeding shows that the SPI bus, the card and the filesystem all work
+/// for writes as well as reads, which mounting alone doesn't prove.
+pub fn self_test<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+) -> Result<(), SelfTestError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let fail = |step| move |error| SelfTestError::Fs { step, error };
+
+    match dir.delete_file_in_dir(SELF_TEST_FILENAME) {
+        Ok(()) | Err(Error::NotFound) => {}
+        Err(e) => return Err(fail(SelfTestStep::Cleanup)(e)),
+    }
+
+    let file = dir
+        .open_file_in_dir(SELF_TEST_FILENAME, Mode::ReadWriteCreate)
+        .map_err(fail(SelfTestStep::Create))?;
+    let mut chunk = [0u8; 64];
+    for start in (0..SELF_TEST_LEN).step_by(chunk.len()) {
+        let len = chunk.len().min(SELF_TEST_LEN - start);
+        for (i, byte) in chunk[..len].iter_mut().enumerate() {
+            *byte = pattern(start + i);
+        }
+        file.write(&chunk[..len])
+            .map_err(fail(SelfTestStep::Write))?;
+    }
+    file.close().map_err(fail(SelfTestStep::Flush))?;
+
+    let file = dir
+        .open_file_in_dir(SELF_TEST_FILENAME, Mode::ReadOnly)
+        .map_err(fail(SelfTestStep::Read))?;
+    let mut read = 0;
+    loop {
+        let n = file.read(&mut chunk).map_err(fail(SelfTestStep::Read))?;
+        if n == 0 {
+            break;
+        }
+        if let Some(i) = (0..n).find(|&i| chunk[i] != pattern(read + i)) {
+            return Err(SelfTestError::Mismatch { offset: read + i });
+        }
+        read += n;
+    }
+    file.close().map_err(fail(SelfTestStep::Read))?;
+    if read != SELF_TEST_LEN {
+        return Err(SelfTestError::WrongLength { read });
+    }
+
+    dir.delete_file_in_dir(SELF_TEST_FILENAME)
+        .map_err(fail(SelfTestStep::Delete))
+}
+
+/// Test byte at `offset`; not a multiple of the block size, so misplaced
+/// blocks are caught
+fn pattern(offset: usize) -> u8 {
+    (offset.wrapping_mul(7) ^ (offset >> 8)) as u8
+}
+
+/// Read the canary file `name` written at provisioning and compare it with
+/// `expected` byte for byte.
+///
+/// Returns false if the contents or length differ, or the file is missing or
+/// can't be read. Unlike [`self_test`] this writes nothing, and because the
+/// canary sits on the card for its whole life it catches data going bad at
+/// rest, so treat false as a sign the card should be replaced.
+pub fn verify_canary<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    name: &str,
+    expected: &[u8],
+) -> bool
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut chunk = [0u8; 64];
+    let mut offset = 0;
+    let mut matches = true;
+    let read = crate::read_file_chunks(dir, name, &mut chunk, |data| {
+        matches &= expected.get(offset..offset + data.len()) == Some(data);
+        offset += data.len();
+    });
+    matches && read.is_ok_and(|len| len as usize == expected.len())
+}
@@ -0,0 +1,120 @@
+//! One log file shared fairly between several logging tasks
+//!
+//! Tasks on the same executor that log to one file would normally share it
+//! behind a lock, and whoever flushes holds that lock for as many block
+//! writes as there is data, starving every other task. [`SharedLog`]
+//! instead has writers append whole rows to a RAM buffer, which never
+//! touches the card, and drains that buffer to the file one block at a
+//! time, yielding between blocks.
+//!
+//! Fairness guarantees:
+//!
+//! - Rows are appended whole, so rows from different tasks never interleave,
+//!   and they reach the file in the order their `write_row` calls completed.
+//! - Nothing is borrowed across an `.await`, so no task ever waits for
+//!   another task's I/O to finish beyond the single block write in progress.
+//! - [`SharedLog::flush`] only drains what was buffered when it was called,
+//!   so writers adding rows can't keep it running forever.
+//! - A writer that finds the buffer full writes one block itself and yields
+//!   before trying again, so a full buffer doesn't depend on a separate
+//!   flushing task being scheduled.
+//!
+//! Block writes themselves are synchronous, so the latency other tasks see
+//! is bounded by the [`YieldBudget`] passed to `flush` (one block per yield
+//! at most with `YieldBudget::new(1)`).
+
+use core::cell::{Cell, RefCell};
+
+use embedded_io::Write;
+use embedded_sdmmc::Block;
+
+use crate::YieldBudget;
+
+/// Errors returned by [`SharedLog`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SharedLogError<E> {
+    /// The row is longer than the whole buffer and can never be queued
+    RowTooLong,
+    /// Writing to the file failed
+    Write(E),
+}
+
+impl<E> From<E> for SharedLogError<E> {
+    fn from(error: E) -> Self {
+        SharedLogError::Write(error)
+    }
+}
+
+/// A file shared between tasks through an `N`-byte row buffer.
+///
+/// Share it by reference between tasks on the same executor; it is not
+/// `Sync`, so it can't be used from interrupts or another core.
+pub struct SharedLog<W, const N: usize> {
+    file: RefCell<W>,
+    buf: RefCell<[u8; N]>,
+    len: Cell<usize>,
+}
+
+impl<W: Write, const N: usize> SharedLog<W, N> {
+    /// Share `file`. Make `N` a few blocks so writers rarely find it full.
+    pub fn new(file: W) -> Self {
+        Self {
+            file: RefCell::new(file),
+            buf: RefCell::new([0; N]),
+            len: Cell::new(0),
+        }
+    }
+
+    /// Bytes waiting to be written to the file
+    pub fn buffered(&self) -> usize {
+        self.len.get()
+    }
+
+    /// Queue `row` (including its line ending) as one unit.
+    ///
+    /// Returns immediately if there is room; otherwise writes a block of
+    /// older rows to the file and yields until there is.
+    pub async fn write_row(&self, row: &[u8]) -> Result<(), SharedLogError<W::Error>> {
+        if row.len() > N {
+            return Err(SharedLogError::RowTooLong);
+        }
+        while self.len.get() + row.len() > N {
+            self.write_block()?;
+            embassy_futures::yield_now().await;
+        }
+        let len = self.len.get();
+        self.buf.borrow_mut()[len..len + row.len()].copy_from_slice(row);
+        self.len.set(len + row.len());
+        Ok(())
+    }
+
+    /// Write out everything buffered when called and flush the file,
+    /// spending `budget` per block so other tasks get to run in between.
+    ///
+    /// Rows queued while this runs are left for the next flush.
+    pub async fn flush(&self, budget: &mut YieldBudget) -> Result<(), W::Error> {
+        let mut remaining = self.len.get();
+        while remaining > 0 {
+            let written = self.write_block()?;
+            remaining = remaining.saturating_sub(written);
+            budget.spend_bytes(written as u32).await;
+        }
+        self.file.borrow_mut().flush()
+    }
+
+    /// Give back the file; anything still buffered is lost, so flush first
+    pub fn into_inner(self) -> W {
+        self.file.into_inner()
+    }
+
+    /// Write up to one block from the front of the buffer, returns bytes written
+    fn write_block(&self) -> Result<usize, W::Error> {
+        let mut buf = self.buf.borrow_mut();
+        let len = self.len.get();
+        let n = len.min(Block::LEN);
+        self.file.borrow_mut().write_all(&buf[..n])?;
+        buf.copy_within(n..len, 0);
+        self.len.set(len - n);
+        Ok(n)
+    }
+}
@@ -0,0 +1,103 @@
+//! Accelerated uptime for soak testing
+//!
+//! Rotation and retention logic that acts on hours or days of uptime can't
+//! be exercised in real time. A [`SimClock`] in a `static` stands in for
+//! the uptime source of [`crate::CsvWriter::with_uptime_source`] and
+//! anything else that takes a `fn() -> u64`, running faster than real time
+//! or jumping forward on request:
+//!
+//! ```text
+//! static CLOCK: SimClock = SimClock::new();
+//! let writer = CsvWriter::new(file, time_source).with_uptime_source(|| CLOCK.now_ms());
+//! CLOCK.set_factor(3600); // an hour per second
+//! CLOCK.advance(Duration::from_secs(86_400)); // skip a day
+//! ```
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use embassy_time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+struct State {
+    /// Simulated milliseconds up to `since`
+    base_ms: u64,
+    /// Real time the current factor took effect
+    since: Instant,
+    factor: u32,
+}
+
+/// Uptime clock that runs `factor` times faster than real time and can be
+/// moved forward by hand
+pub struct SimClock {
+    state: Mutex<Cell<Option<State>>>,
+}
+
+impl SimClock {
+    /// A clock starting at zero and running at real speed from its first use
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    /// Simulated milliseconds since the clock started
+    pub fn now_ms(&self) -> u64 {
+        critical_section::with(|cs| {
+            let state = self.state(cs);
+            Self::elapsed_ms(&state)
+        })
+    }
+
+    /// Simulated time since the clock started
+    pub fn now(&self) -> Duration {
+        Duration::from_millis(self.now_ms())
+    }
+
+    /// Run `factor` times faster than real time from now on; 0 freezes the
+    /// clock so it only moves with [`SimClock::advance`]
+    pub fn set_factor(&self, factor: u32) {
+        critical_section::with(|cs| {
+            let state = self.state(cs);
+            self.state.borrow(cs).set(Some(State {
+                base_ms: Self::elapsed_ms(&state),
+                since: Instant::now(),
+                factor,
+            }));
+        });
+    }
+
+    /// Jump forward by `by`
+    pub fn advance(&self, by: Duration) {
+        critical_section::with(|cs| {
+            let mut state = self.state(cs);
+            state.base_ms = state.base_ms.saturating_add(by.as_millis());
+            self.state.borrow(cs).set(Some(state));
+        });
+    }
+
+    /// The state, starting the clock if this is its first use
+    fn state(&self, cs: critical_section::CriticalSection<'_>) -> State {
+        let cell = self.state.borrow(cs);
+        let state = cell.get().unwrap_or(State {
+            base_ms: 0,
+            since: Instant::now(),
+            factor: 1,
+        });
+        cell.set(Some(state));
+        state
+    }
+
+    fn elapsed_ms(state: &State) -> u64 {
+        let real_ms = state.since.elapsed().as_millis();
+        state
+            .base_ms
+            .saturating_add(real_ms.saturating_mul(u64::from(state.factor)))
+    }
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
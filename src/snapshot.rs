@@ -0,0 +1,160 @@
+//! Reading a file that is still being appended to
+//!
+//! embedded-sdmmc refuses to open a file twice, so a second task can't just
+//! open the active log. A [`SnapshotReader`] reads through the writer's own
+//! handle instead: each read seeks to the reader's position, reads, and seeks
+//! back to where the writer left off. Every call goes through the same
+//! `VolumeManager`, so reads and writes are serialized and never interleave
+//! mid-operation.
+
+use embedded_sdmmc::{BlockDevice, Error, File, TimeSource};
+
+/// Reads the bytes a file held when the snapshot was taken, while the same
+/// handle keeps being written to.
+///
+/// Creating one flushes the file and records its length; reads never go past
+/// that length, so rows appended afterwards (including a half-written one) are
+/// never seen. Take a new snapshot to see newer data; it costs one flush.
+pub struct SnapshotReader<
+    'a,
+    'f,
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+> where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    file: &'a File<'f, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    len: u32,
+    position: u32,
+}
+
+impl<'a, 'f, D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>
+    SnapshotReader<'a, 'f, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    /// Flush `file` and snapshot its current length, starting at offset 0
+    pub fn new(
+        file: &'a File<'f, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    ) -> Result<Self, Error<D::Error>> {
+        file.flush()?;
+        Ok(Self {
+            file,
+            len: file.length(),
+            position: 0,
+        })
+    }
+
+    /// Length of the file when the snapshot was taken
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    /// True if the file was empty when the snapshot was taken
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Offset the next read starts from
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    /// Move to `position`, clamped to the snapshot length
+    pub fn seek(&mut self, position: u32) {
+        self.position = position.min(self.len);
+    }
+
+    /// Move to `bytes` before the end of the snapshot, e.g. to show the last
+    /// screenful of a log
+    pub fn seek_from_end(&mut self, bytes: u32) {
+        self.position = self.len.saturating_sub(bytes);
+    }
+
+    /// Read up to `buffer.len()` bytes, returning 0 at the end of the snapshot.
+    ///
+    /// The file's own offset is restored afterwards, even on error, so the
+    /// writer's next append lands where it would have anyway.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error<D::Error>> {
+        let remaining = (self.len - self.position) as usize;
+        let want = buffer.len().min(remaining);
+        if want == 0 {
+            return Ok(0);
+        }
+
+        let writer_offset = self.file.offset();
+        let result = self
+            .file
+            .seek_from_start(self.position)
+            .and_then(|()| self.file.read(&mut buffer[..want]));
+        self.file.seek_from_start(writer_offset)?;
+        let n = result?;
+        self.position += n as u32;
+        Ok(n)
+    }
+}
+
+impl<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>
+    embedded_io::ErrorType for SnapshotReader<'_, '_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    type Error = Error<D::Error>;
+}
+
+impl<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>
+    embedded_io::Read for SnapshotReader<'_, '_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        SnapshotReader::read(self, buffer)
+    }
+}
+
+/// Read the last complete lines of `file` that fit in `buffer`, while the
+/// same handle keeps being appended to, e.g. to tail a log through
+/// [`crate::CsvWriter::get_ref`].
+///
+/// Reads through a [`SnapshotReader`], so the write position is left where
+/// it was and a row still being written isn't included. A line cut by the
+/// start of the window is dropped, unless that would leave nothing. Returns
+/// the lines read.
+pub fn tail_file<
+    'b,
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    file: &File<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    buffer: &'b mut [u8],
+) -> Result<&'b [u8], Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut reader = SnapshotReader::new(file)?;
+    reader.seek_from_end(buffer.len() as u32);
+    let at_start = reader.position() == 0;
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    let start = match buffer[..filled].iter().position(|&b| b == b'\n') {
+        Some(i) if !at_start && i + 1 < filled => i + 1,
+        _ => 0,
+    };
+    Ok(&buffer[start..filled])
+}
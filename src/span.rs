@@ -0,0 +1,76 @@
+//! Continuing a log on the next partition when one fills up
+//!
+//! Handles in embedded-sdmmc close when dropped, so switching volumes is:
+//! drop the file, directory and volume for the full partition (in that
+//! order), then open the volume [`VolumeSpanPolicy::advance`] returns and
+//! recreate the directory layout and log file there.
+
+use embedded_sdmmc::{BlockDevice, Error, TimeSource, Volume, VolumeIdx, VolumeManager};
+
+/// True if `error` means the volume has no room left for the write
+pub fn is_volume_full<E: core::fmt::Debug>(error: &Error<E>) -> bool {
+    matches!(error, Error::DiskFull | Error::NotEnoughSpace)
+}
+
+/// Ordered list of partitions to log onto, moving to the next when one fills
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VolumeSpanPolicy<const N: usize> {
+    volumes: [VolumeIdx; N],
+    current: usize,
+}
+
+impl<const N: usize> VolumeSpanPolicy<N> {
+    /// Log onto `volumes` in order, starting with the first
+    pub const fn new(volumes: [VolumeIdx; N]) -> Self {
+        const { assert!(N > 0, "VolumeSpanPolicy needs at least one volume") };
+        Self {
+            volumes,
+            current: 0,
+        }
+    }
+
+    /// The volume currently being logged onto
+    pub fn current(&self) -> VolumeIdx {
+        self.volumes[self.current]
+    }
+
+    /// True if there is no volume left to span onto
+    pub fn is_last(&self) -> bool {
+        self.current + 1 >= N
+    }
+
+    /// Move on to the next volume and return it, or None (staying on the
+    /// last one) if the list is exhausted and the usual disk-full handling
+    /// should take over
+    pub fn advance(&mut self) -> Option<VolumeIdx> {
+        if self.is_last() {
+            return None;
+        }
+        self.current += 1;
+        if crate::verbosity() != crate::Verbosity::Quiet {
+            println!("Volume full, continuing on volume {}", self.current().0);
+        }
+        Some(self.current())
+    }
+
+    /// Open the current volume, moving on past volumes that don't exist or
+    /// can't be mounted. Returns the last error if none of the remaining
+    /// volumes opens.
+    pub fn open<'v, D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+        &mut self,
+        volume_mgr: &'v VolumeManager<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    ) -> Result<Volume<'v, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>, Error<D::Error>>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        loop {
+            match volume_mgr.open_volume(self.current()) {
+                Err(Error::NoSuchVolume | Error::FormatError(_)) if !self.is_last() => {
+                    self.current += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
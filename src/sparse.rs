@@ -0,0 +1,97 @@
+//! Logging only the values that changed, with periodic full keyframes
+//!
+//! A [`SparseLog`] decides, row by row, which values are worth writing: a
+//! column is written when it moves more than the threshold from the value
+//! last written for it, and every keyframe interval all columns are written
+//! so a reader can start from any keyframe. [`crate::CsvWriter::write_sparse_row`]
+//! writes the result:
+//!
+//! ```text
+//! # keyframe
+//! 1000,215,48,1
+//! 1500,,49,
+//! 2500,219,,
+//! # keyframe
+//! 61000,219,49,0
+//! ```
+//!
+//! A keyframe is a `# keyframe` comment line followed by a row with every
+//! value. Other rows leave unchanged values empty; carry the last value
+//! forward (e.g. pandas `read_csv(comment="#").ffill()`) to rebuild them.
+//! Rows where nothing changed are not written at all.
+
+use embassy_time::Duration;
+
+/// What [`SparseLog::classify`] decided for a row
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SparseRow {
+    /// Nothing changed enough; write nothing
+    Skip,
+    /// Write the values marked as changed
+    Changes,
+    /// Write every value after a keyframe marker
+    Keyframe,
+}
+
+/// Change detection and keyframe timing for rows of `COLS` values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SparseLog<const COLS: usize> {
+    threshold: u32,
+    keyframe_interval: Duration,
+    last: [i32; COLS],
+    changed: [bool; COLS],
+    last_keyframe_ms: Option<u64>,
+}
+
+impl<const COLS: usize> SparseLog<COLS> {
+    /// Write a value when it differs from the last one written for its
+    /// column by more than `threshold` (0 writes every change), and every
+    /// value at least once per `keyframe_interval`
+    pub const fn new(threshold: u32, keyframe_interval: Duration) -> Self {
+        Self {
+            threshold,
+            keyframe_interval,
+            last: [0; COLS],
+            changed: [false; COLS],
+            last_keyframe_ms: None,
+        }
+    }
+
+    /// Decide what to write for `values` at uptime `now_ms`, remembering
+    /// the values that will be written
+    pub fn classify(&mut self, values: &[i32; COLS], now_ms: u64) -> SparseRow {
+        let keyframe_due = self
+            .last_keyframe_ms
+            .is_none_or(|last| now_ms.saturating_sub(last) >= self.keyframe_interval.as_millis());
+        if keyframe_due {
+            self.last = *values;
+            self.changed = [true; COLS];
+            self.last_keyframe_ms = Some(now_ms);
+            return SparseRow::Keyframe;
+        }
+        let mut any = false;
+        for ((last, changed), &value) in self.last.iter_mut().zip(&mut self.changed).zip(values) {
+            *changed = value.abs_diff(*last) > self.threshold;
+            if *changed {
+                *last = value;
+                any = true;
+            }
+        }
+        if any {
+            SparseRow::Changes
+        } else {
+            SparseRow::Skip
+        }
+    }
+
+    /// Whether column `index` is written for the row last classified
+    pub fn is_changed(&self, index: usize) -> bool {
+        self.changed.get(index).copied().unwrap_or(false)
+    }
+
+    /// Write a keyframe for the next row whatever the time, e.g. at the
+    /// start of a new file
+    pub fn force_keyframe(&mut self) {
+        self.last_keyframe_ms = None;
+    }
+}
@@ -0,0 +1,136 @@
+//! Logger lifecycle state published for display tasks
+//!
+//! The logging task sets the state as it moves through its lifecycle and a
+//! UI task renders it from a [`LoggerStateCell::state_receiver`], which
+//! wakes on each change rather than rebuilding the state from console
+//! output:
+//!
+//! ```text
+//! Initializing ──► Logging ◄──► Recovering
+//!      │              │              │
+//!      ▼              ▼              ▼
+//!   Stopped ◄───── CardFull ──────► Stopped
+//! ```
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::watch::{Receiver, Watch};
+
+/// What the logger is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoggerState {
+    /// Bringing up the card and filesystem
+    #[default]
+    Initializing,
+    /// Writing rows to the file named by the 8.3 name in `name`
+    Logging {
+        /// File name, e.g. "LOG00017.CSV", padded with zeros
+        name: [u8; 12],
+        /// Rows written to the file, rounded down to a multiple of
+        /// [`LoggerState::PROGRESS_STEP`]
+        rows: u32,
+    },
+    /// Re-initialising after errors
+    Recovering {
+        /// Recovery attempt in progress, from 1
+        attempt: u8,
+    },
+    /// The card has no room left; rows are being dropped
+    CardFull,
+    /// Logging has ended and won't resume without a restart
+    Stopped,
+}
+
+impl LoggerState {
+    /// Granularity of the `rows` count in [`LoggerState::Logging`], so
+    /// receivers wake every this many rows rather than on every row
+    pub const PROGRESS_STEP: u32 = 100;
+
+    /// `Logging` for the file `name`; names longer than 12 bytes are cut short
+    pub fn logging(name: &str) -> Self {
+        let mut padded = [0u8; 12];
+        let len = name.len().min(padded.len());
+        padded[..len].copy_from_slice(&name.as_bytes()[..len]);
+        LoggerState::Logging {
+            name: padded,
+            rows: 0,
+        }
+    }
+
+    /// The file being logged to, if any
+    pub fn file_name(&self) -> Option<&str> {
+        let LoggerState::Logging { name, .. } = self else {
+            return None;
+        };
+        let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        core::str::from_utf8(&name[..len]).ok()
+    }
+}
+
+/// Receiver of [`LoggerState`] changes, from [`LoggerStateCell::state_receiver`]
+pub type LoggerStateReceiver<'a> =
+    Receiver<'a, CriticalSectionRawMutex, LoggerState, { LoggerStateCell::MAX_RECEIVERS }>;
+
+/// Current [`LoggerState`], shared between the logging task and observers
+///
+/// Meant to live in a `static`. Backed by an `embassy_sync` [`Watch`], so
+/// receivers are woken by [`LoggerStateCell::set`] and skip straight to the
+/// latest state if they fall behind.
+pub struct LoggerStateCell {
+    watch: Watch<CriticalSectionRawMutex, LoggerState, { LoggerStateCell::MAX_RECEIVERS }>,
+}
+
+impl LoggerStateCell {
+    /// How many [`LoggerStateCell::state_receiver`]s can exist at once
+    pub const MAX_RECEIVERS: usize = 4;
+
+    /// Start in [`LoggerState::Initializing`]
+    pub const fn new() -> Self {
+        Self {
+            watch: Watch::new_with(LoggerState::Initializing),
+        }
+    }
+
+    /// The current state
+    pub fn get(&self) -> LoggerState {
+        self.watch.try_get().unwrap_or_default()
+    }
+
+    /// Move to `state`; setting the current state again is not a change
+    pub fn set(&self, state: LoggerState) {
+        self.watch.sender().send_if_modified(|current| {
+            if *current == Some(state) {
+                return false;
+            }
+            *current = Some(state);
+            true
+        });
+    }
+
+    /// Update the row count of the `Logging` state to `rows`, which only
+    /// wakes receivers when it crosses a [`LoggerState::PROGRESS_STEP`]
+    pub fn set_rows(&self, rows: u32) {
+        let rows = rows - rows % LoggerState::PROGRESS_STEP;
+        self.watch
+            .sender()
+            .send_if_modified(|current| match current {
+                Some(LoggerState::Logging { rows: seen, .. }) if *seen != rows => {
+                    *seen = rows;
+                    true
+                }
+                _ => false,
+            });
+    }
+
+    /// A receiver whose `changed().await` returns each new state, or `None`
+    /// if [`LoggerStateCell::MAX_RECEIVERS`] are already in use. Its `get()`
+    /// returns the current state straight away.
+    pub fn state_receiver(&self) -> Option<LoggerStateReceiver<'_>> {
+        self.watch.receiver()
+    }
+}
+
+impl Default for LoggerStateCell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,243 @@
+//! Time formatting helpers
+
+use embedded_sdmmc::Timestamp;
+
+/// Length of a timestamp formatted by [`format_iso8601`]
+pub const ISO8601_LEN: usize = 19;
+
+/// Format a FAT timestamp as "YYYY-MM-DDTHH:MM:SS", returns bytes written
+/// (0 if `buffer` is too small)
+pub fn format_iso8601(buffer: &mut [u8], timestamp: &Timestamp) -> usize {
+    if buffer.len() < ISO8601_LEN {
+        return 0;
+    }
+
+    let year = 1970 + u16::from(timestamp.year_since_1970);
+    let fields = [
+        (year / 100) as u8,
+        (year % 100) as u8,
+        timestamp.zero_indexed_month + 1,
+        timestamp.zero_indexed_day + 1,
+        timestamp.hours,
+        timestamp.minutes,
+        timestamp.seconds,
+    ];
+    // Separator written after each two-digit field
+    let separators = [
+        None,
+        Some(b'-'),
+        Some(b'-'),
+        Some(b'T'),
+        Some(b':'),
+        Some(b':'),
+        None,
+    ];
+
+    let mut cursor = 0;
+    for (value, separator) in fields.iter().zip(separators) {
+        buffer[cursor] = b'0' + value / 10 % 10;
+        buffer[cursor + 1] = b'0' + value % 10;
+        cursor += 2;
+        if let Some(separator) = separator {
+            buffer[cursor] = separator;
+            cursor += 1;
+        }
+    }
+
+    cursor
+}
+
+/// Convert a FAT timestamp to milliseconds since the Unix epoch, taking the
+/// sub-second part from `uptime_sub_ms` (clamped to 999).
+///
+/// `Timestamp` only has second resolution, so pass something like
+/// `Instant::now().as_millis() % 1000` to get distinct, increasing values
+/// for rows logged within one second. The uptime's second boundary isn't
+/// aligned with the clock's, so the milliseconds order rows rather than
+/// place them exactly. The timestamp is taken as UTC.
+pub fn timestamp_to_epoch_ms(timestamp: &Timestamp, uptime_sub_ms: u16) -> u64 {
+    let days = days_from_civil(
+        1970 + i64::from(timestamp.year_since_1970),
+        u32::from(timestamp.zero_indexed_month) + 1,
+        u32::from(timestamp.zero_indexed_day) + 1,
+    );
+    let seconds = days as u64 * 86_400
+        + u64::from(timestamp.hours) * 3600
+        + u64::from(timestamp.minutes) * 60
+        + u64::from(timestamp.seconds);
+    seconds * 1000 + u64::from(uptime_sub_ms.min(999))
+}
+
+/// Earliest time a FAT directory entry can hold, 1980-01-01T00:00:00, in
+/// seconds since the Unix epoch
+pub const FAT_MIN_EPOCH: u64 = 315_532_800;
+
+/// Latest time a FAT directory entry can hold, 2107-12-31T23:59:59, in
+/// seconds since the Unix epoch
+pub const FAT_MAX_EPOCH: u64 = 4_354_819_199;
+
+/// Convert seconds since the Unix epoch (UTC) to a FAT timestamp, clamped
+/// to [`FAT_MIN_EPOCH`]..=[`FAT_MAX_EPOCH`], the range a directory entry
+/// can store. The inverse of [`timestamp_to_epoch_ms`] within that range.
+///
+/// ```text
+/// 951_782_400   -> 2000-02-29T00:00:00 (year_since_1970 30, month 1, day 28)
+/// 1_709_251_199 -> 2024-02-29T23:59:59
+/// 1_709_251_200 -> 2024-03-01T00:00:00
+/// 0             -> 1980-01-01T00:00:00
+/// u64::MAX      -> 2107-12-31T23:59:59
+/// ```
+pub fn epoch_to_timestamp(epoch_secs: u64) -> Timestamp {
+    let secs = epoch_secs.clamp(FAT_MIN_EPOCH, FAT_MAX_EPOCH);
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+    Timestamp {
+        year_since_1970: (year - 1970) as u8,
+        zero_indexed_month: (month - 1) as u8,
+        zero_indexed_day: (day - 1) as u8,
+        hours: (time / 3600) as u8,
+        minutes: (time / 60 % 60) as u8,
+        seconds: (time % 60) as u8,
+    }
+}
+
+/// Proleptic Gregorian date `days` days after 1970-01-01, the inverse of
+/// [`days_from_civil`]
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Days from 1970-01-01 to the given proleptic Gregorian date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_from_march = i64::from((month + 9) % 12);
+    let day_of_year = (153 * month_from_march + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Extra information about a [`embedded_sdmmc::TimeSource`]
+pub trait TimeSourceExt: embedded_sdmmc::TimeSource {
+    /// True if `get_timestamp` returns real wall-clock time rather than a placeholder
+    fn is_real(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp(year: u16, month: u8, day: u8, hours: u8, minutes: u8, seconds: u8) -> Timestamp {
+        Timestamp {
+            year_since_1970: (year - 1970) as u8,
+            zero_indexed_month: month - 1,
+            zero_indexed_day: day - 1,
+            hours,
+            minutes,
+            seconds,
+        }
+    }
+
+    #[test]
+    fn fat_range_ends_round_trip() {
+        let min = timestamp(1980, 1, 1, 0, 0, 0);
+        let max = timestamp(2107, 12, 31, 23, 59, 59);
+        assert_eq!(epoch_to_timestamp(FAT_MIN_EPOCH), min);
+        assert_eq!(epoch_to_timestamp(FAT_MAX_EPOCH), max);
+        assert_eq!(timestamp_to_epoch_ms(&min, 0), FAT_MIN_EPOCH * 1000);
+        assert_eq!(timestamp_to_epoch_ms(&max, 0), FAT_MAX_EPOCH * 1000);
+    }
+
+    #[test]
+    fn out_of_range_epochs_are_clamped() {
+        assert_eq!(epoch_to_timestamp(0), timestamp(1980, 1, 1, 0, 0, 0));
+        assert_eq!(
+            epoch_to_timestamp(FAT_MIN_EPOCH - 1),
+            timestamp(1980, 1, 1, 0, 0, 0)
+        );
+        assert_eq!(
+            epoch_to_timestamp(FAT_MAX_EPOCH + 1),
+            timestamp(2107, 12, 31, 23, 59, 59)
+        );
+        assert_eq!(
+            epoch_to_timestamp(u64::MAX),
+            timestamp(2107, 12, 31, 23, 59, 59)
+        );
+    }
+
+    #[test]
+    fn leap_days() {
+        // 2000 is a leap year, 2100 isn't
+        assert_eq!(
+            epoch_to_timestamp(951_782_400),
+            timestamp(2000, 2, 29, 0, 0, 0)
+        );
+        assert_eq!(
+            epoch_to_timestamp(1_709_251_199),
+            timestamp(2024, 2, 29, 23, 59, 59)
+        );
+        assert_eq!(
+            epoch_to_timestamp(1_709_251_200),
+            timestamp(2024, 3, 1, 0, 0, 0)
+        );
+        assert_eq!(
+            epoch_to_timestamp(4_107_542_399),
+            timestamp(2100, 2, 28, 23, 59, 59)
+        );
+        assert_eq!(
+            epoch_to_timestamp(4_107_542_400),
+            timestamp(2100, 3, 1, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn year_end_rolls_over() {
+        let last = timestamp(2023, 12, 31, 23, 59, 59);
+        let epoch = timestamp_to_epoch_ms(&last, 0) / 1000;
+        assert_eq!(epoch, 1_704_067_199);
+        assert_eq!(
+            epoch_to_timestamp(epoch + 1),
+            timestamp(2024, 1, 1, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn sub_millisecond_part_is_clamped() {
+        let min = timestamp(1980, 1, 1, 0, 0, 0);
+        assert_eq!(timestamp_to_epoch_ms(&min, 999), FAT_MIN_EPOCH * 1000 + 999);
+        assert_eq!(
+            timestamp_to_epoch_ms(&min, 5000),
+            FAT_MIN_EPOCH * 1000 + 999
+        );
+    }
+
+    #[test]
+    fn iso8601_of_the_range_ends() {
+        let mut buffer = [0u8; ISO8601_LEN];
+        let len = format_iso8601(&mut buffer, &epoch_to_timestamp(FAT_MAX_EPOCH));
+        assert_eq!(&buffer[..len], b"2107-12-31T23:59:59");
+        let len = format_iso8601(&mut buffer, &epoch_to_timestamp(FAT_MIN_EPOCH));
+        assert_eq!(&buffer[..len], b"1980-01-01T00:00:00");
+        assert_eq!(
+            format_iso8601(&mut buffer[..ISO8601_LEN - 1], &epoch_to_timestamp(0)),
+            0
+        );
+    }
+}
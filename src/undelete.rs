@@ -0,0 +1,439 @@
+//! Best-effort recovery of deleted files on FAT32 volumes
+//!
+//! Deleting a file on FAT only replaces the first byte of its directory
+//! entry with 0xE5; the data stays on the card until something else is
+//! written over it. [`scan_deleted`] lists such entries and [`undelete`]
+//! brings one back if its clusters can be accounted for.
+//!
+//! embedded-sdmmc leaves a deleted file's cluster chain in the FAT, so
+//! files deleted on the device usually come back whole. Desktop systems
+//! mark the clusters free, losing the chain; [`undelete`] then assumes the
+//! clusters were contiguous, which holds for files written in one go on a
+//! card with free space but not for files that grew alongside others.
+//!
+//! Success is only likely if little has been written since the deletion.
+//! A restored file whose clusters were reused or scattered has the right
+//! size but wrong contents after the first cluster, so check restored data
+//! before trusting it.
+//!
+//! These functions read and write blocks directly, behind embedded-sdmmc's
+//! back: close the volume (and every file and directory on it) first, and
+//! reopen it afterwards.
+
+use embedded_sdmmc::{Block, BlockDevice, BlockIdx, ShortFileName, VolumeIdx};
+
+use crate::diag::{le16, le32, read_boot_sector, FSINFO_SIGNATURES};
+
+/// First name byte of a deleted directory entry
+const DELETED: u8 = 0xE5;
+/// Bytes in a directory entry
+const ENTRY_LEN: usize = 32;
+/// Long file name entries have all of read-only, hidden, system and volume set
+const ATTR_LONG_NAME: u8 = 0x0F;
+/// Volume label and directory attribute bits
+const ATTR_NOT_FILE: u8 = 0x08 | 0x10;
+/// FAT32 entries are 28 bits; the top 4 are reserved
+const FAT_MASK: u32 = 0x0FFF_FFFF;
+/// Cluster values at or above this end a chain
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+
+/// A deleted file found by [`scan_deleted`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeletedEntry {
+    /// The 8.3 name as stored, space padded, with the lost first character
+    /// replaced by `?`
+    pub name: [u8; 11],
+    /// File size in bytes
+    pub size: u32,
+    /// First cluster of the file's data, 0 for an empty file
+    pub first_cluster: u32,
+    /// Block holding the directory entry
+    pub entry_block: u32,
+    /// Byte offset of the directory entry within its block
+    pub entry_offset: u16,
+}
+
+impl DeletedEntry {
+    /// The name as `?ILENAME.EXT`, e.g. `?OG00017.CSV`
+    pub fn name_fragment<'a>(&self, buffer: &'a mut [u8; 12]) -> &'a str {
+        let base = trim_spaces(&self.name[..8]);
+        let extension = trim_spaces(&self.name[8..]);
+        let mut len = base.len();
+        buffer[..len].copy_from_slice(base);
+        if !extension.is_empty() {
+            buffer[len] = b'.';
+            buffer[len + 1..len + 1 + extension.len()].copy_from_slice(extension);
+            len += 1 + extension.len();
+        }
+        core::str::from_utf8(&buffer[..len]).unwrap_or("?")
+    }
+}
+
+/// Errors returned by [`scan_deleted`] and [`undelete`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UndeleteError<E> {
+    /// A block could not be read or written
+    Device(E),
+    /// The volume doesn't exist or isn't FAT32 with 512-byte sectors
+    NotFat32,
+    /// The directory entry no longer matches the [`DeletedEntry`], e.g.
+    /// because it was reused or already restored
+    Stale,
+    /// A cluster the file needs is in use by another file, or the chain
+    /// left in the FAT doesn't match the file's size; nothing was written
+    ChainClaimed {
+        /// First cluster found in use
+        cluster: u32,
+    },
+    /// The entry points outside the volume
+    OutOfRange,
+    /// `new_name` isn't a valid 8.3 file name
+    InvalidName,
+    /// A file called `new_name` already exists in the directory
+    NameExists,
+}
+
+impl<E> From<E> for UndeleteError<E> {
+    fn from(e: E) -> Self {
+        UndeleteError::Device(e)
+    }
+}
+
+/// Call `on_entry` for every deleted file in the directory starting at
+/// `dir_cluster`, or the root directory if None, on FAT32 `volume`. Never
+/// writes. Returns the number of entries found.
+pub fn scan_deleted<D, F>(
+    device: &D,
+    volume: VolumeIdx,
+    dir_cluster: Option<u32>,
+    mut on_entry: F,
+) -> Result<u32, UndeleteError<D::Error>>
+where
+    D: BlockDevice,
+    F: FnMut(&DeletedEntry),
+{
+    let fat = Fat32::read(device, volume)?;
+    let mut found = 0;
+    fat.for_each_entry(
+        device,
+        dir_cluster.unwrap_or(fat.root_cluster),
+        |lba, offset, entry| {
+            if let Some(deleted) = deleted_entry(lba, offset, entry) {
+                on_entry(&deleted);
+                found += 1;
+            }
+            true
+        },
+    )?;
+    Ok(found)
+}
+
+/// Restore `entry` from [`scan_deleted`] as `new_name` (an 8.3 name), in
+/// the directory starting at `dir_cluster` (None for the root).
+///
+/// Nothing is written unless the entry is still deleted and unchanged, no
+/// file in the directory is called `new_name` or starts at the same
+/// cluster, and either the FAT still holds a chain from the first cluster
+/// of exactly the file's length, or every cluster the file needs, assuming
+/// they are contiguous, is free. In the second case the chain is rebuilt
+/// first and the directory entry written last, so power loss part way
+/// through leaves clusters allocated to nothing rather than a broken file,
+/// and the free cluster count in FSInfo is marked unknown.
+pub fn undelete<D: BlockDevice>(
+    device: &D,
+    volume: VolumeIdx,
+    dir_cluster: Option<u32>,
+    entry: &DeletedEntry,
+    new_name: &str,
+) -> Result<(), UndeleteError<D::Error>> {
+    let name = short_name(new_name).ok_or(UndeleteError::InvalidName)?;
+    let fat = Fat32::read(device, volume)?;
+
+    let (mut name_taken, mut start_taken) = (false, false);
+    fat.for_each_entry(
+        device,
+        dir_cluster.unwrap_or(fat.root_cluster),
+        |_, _, dir_entry| {
+            let live = !matches!(dir_entry[0], 0 | DELETED)
+                && dir_entry[11] & ATTR_LONG_NAME != ATTR_LONG_NAME;
+            name_taken |= live && dir_entry[..11] == name;
+            start_taken |=
+                live && entry.first_cluster != 0 && first_cluster(dir_entry) == entry.first_cluster;
+            !name_taken
+        },
+    )?;
+    if name_taken {
+        return Err(UndeleteError::NameExists);
+    }
+    if start_taken {
+        return Err(UndeleteError::ChainClaimed {
+            cluster: entry.first_cluster,
+        });
+    }
+
+    let mut block = [Block::new()];
+    device.read(&mut block, BlockIdx(entry.entry_block))?;
+    let offset = usize::from(entry.entry_offset);
+    let current = block[0]
+        .contents
+        .get(offset..offset + ENTRY_LEN)
+        .and_then(|bytes| deleted_entry(entry.entry_block, entry.entry_offset, bytes));
+    if current.as_ref() != Some(entry) {
+        return Err(UndeleteError::Stale);
+    }
+
+    let clusters = entry.size.div_ceil(fat.cluster_size);
+    if clusters > 0 {
+        let first = entry.first_cluster;
+        if !(2..fat.clusters + 2).contains(&first) {
+            return Err(UndeleteError::OutOfRange);
+        }
+        let mut fat_block = [Block::new()];
+        if fat.entry(device, &mut fat_block, first)? != 0 {
+            fat.check_chain(device, &mut fat_block, first, clusters)?;
+        } else {
+            let end = first
+                .checked_add(clusters)
+                .filter(|&end| end <= fat.clusters + 2)
+                .ok_or(UndeleteError::OutOfRange)?;
+            fat.rebuild_chain(device, &mut fat_block, first, end)?;
+        }
+    }
+
+    block[0].contents[offset..offset + 11].copy_from_slice(&name);
+    device.write(&block, BlockIdx(entry.entry_block))?;
+    Ok(())
+}
+
+/// Where a FAT32 volume's structures are, from its boot sector
+struct Fat32 {
+    boot_lba: u32,
+    fat_start: u32,
+    fat_size: u32,
+    num_fats: u32,
+    data_start: u32,
+    sectors_per_cluster: u32,
+    cluster_size: u32,
+    root_cluster: u32,
+    /// Data clusters, numbered from 2
+    clusters: u32,
+    fsinfo_sector: u16,
+}
+
+impl Fat32 {
+    fn read<D: BlockDevice>(
+        device: &D,
+        volume: VolumeIdx,
+    ) -> Result<Self, UndeleteError<D::Error>> {
+        let mut block = [Block::new()];
+        let (boot_lba, cluster_size) =
+            read_boot_sector(device, volume, &mut block)?.ok_or(UndeleteError::NotFat32)?;
+        let boot = &block[0].contents;
+        let fat_size = le32(boot, 36);
+        let total_sectors = le32(boot, 32);
+        // FAT16 and FAT12 have a non-zero 16-bit FAT size
+        if le16(boot, 11) as usize != Block::LEN || le16(boot, 22) != 0 || fat_size == 0 {
+            return Err(UndeleteError::NotFat32);
+        }
+        let sectors_per_cluster = u32::from(boot[13]);
+        let fat_start = boot_lba + u32::from(le16(boot, 14));
+        let num_fats = u32::from(boot[16]);
+        let data_start = fat_start + num_fats * fat_size;
+        let data_sectors = (boot_lba + total_sectors).saturating_sub(data_start);
+        Ok(Self {
+            boot_lba,
+            fat_start,
+            fat_size,
+            num_fats,
+            data_start,
+            sectors_per_cluster,
+            cluster_size,
+            root_cluster: le32(boot, 44),
+            clusters: data_sectors / sectors_per_cluster,
+            fsinfo_sector: le16(boot, 48),
+        })
+    }
+
+    /// Check that the chain from `first` is `clusters` long and in range
+    fn check_chain<D: BlockDevice>(
+        &self,
+        device: &D,
+        block: &mut [Block; 1],
+        first: u32,
+        clusters: u32,
+    ) -> Result<(), UndeleteError<D::Error>> {
+        let mut cluster = first;
+        for remaining in (0..clusters).rev() {
+            let next = self.entry(device, block, cluster)?;
+            let linked = (2..self.clusters + 2).contains(&next);
+            if (remaining == 0) != (next >= END_OF_CHAIN) || (remaining > 0 && !linked) {
+                return Err(UndeleteError::ChainClaimed { cluster });
+            }
+            cluster = next;
+        }
+        Ok(())
+    }
+
+    /// Chain the clusters `first..end` in every FAT copy if all are free
+    fn rebuild_chain<D: BlockDevice>(
+        &self,
+        device: &D,
+        block: &mut [Block; 1],
+        first: u32,
+        end: u32,
+    ) -> Result<(), UndeleteError<D::Error>> {
+        for cluster in first..end {
+            if self.entry(device, block, cluster)? != 0 {
+                return Err(UndeleteError::ChainClaimed { cluster });
+            }
+        }
+        for copy in 0..self.num_fats {
+            for cluster in first..end {
+                let next = if cluster + 1 == end {
+                    FAT_MASK
+                } else {
+                    cluster + 1
+                };
+                self.set_entry(device, block, copy, cluster, next)?;
+            }
+        }
+        self.invalidate_free_count(device, block)?;
+        Ok(())
+    }
+
+    /// Block and byte offset of `cluster`'s entry in FAT `copy`
+    fn entry_position(&self, copy: u32, cluster: u32) -> (BlockIdx, usize) {
+        let byte = cluster as usize * 4;
+        let lba = self.fat_start + copy * self.fat_size + (byte / Block::LEN) as u32;
+        (BlockIdx(lba), byte % Block::LEN)
+    }
+
+    fn entry<D: BlockDevice>(
+        &self,
+        device: &D,
+        block: &mut [Block; 1],
+        cluster: u32,
+    ) -> Result<u32, D::Error> {
+        let (lba, offset) = self.entry_position(0, cluster);
+        device.read(block, lba)?;
+        Ok(le32(&block[0].contents, offset) & FAT_MASK)
+    }
+
+    fn set_entry<D: BlockDevice>(
+        &self,
+        device: &D,
+        block: &mut [Block; 1],
+        copy: u32,
+        cluster: u32,
+        value: u32,
+    ) -> Result<(), D::Error> {
+        let (lba, offset) = self.entry_position(copy, cluster);
+        device.read(block, lba)?;
+        let reserved = le32(&block[0].contents, offset) & !FAT_MASK;
+        block[0].contents[offset..offset + 4].copy_from_slice(&(reserved | value).to_le_bytes());
+        device.write(block, lba)
+    }
+
+    /// Mark the FSInfo free cluster count unknown so it gets recounted
+    fn invalidate_free_count<D: BlockDevice>(
+        &self,
+        device: &D,
+        block: &mut [Block; 1],
+    ) -> Result<(), D::Error> {
+        if self.fsinfo_sector == 0 || self.fsinfo_sector == 0xFFFF {
+            return Ok(());
+        }
+        let lba = BlockIdx(self.boot_lba + u32::from(self.fsinfo_sector));
+        device.read(block, lba)?;
+        let fsinfo = &mut block[0].contents;
+        if !FSINFO_SIGNATURES
+            .iter()
+            .all(|&(offset, expected)| le32(fsinfo, offset) == expected)
+        {
+            return Ok(());
+        }
+        fsinfo[488..492].copy_from_slice(&u32::MAX.to_le_bytes());
+        device.write(block, lba)
+    }
+
+    /// Call `on_entry(block, offset, entry)` for each entry in the directory
+    /// starting at `cluster`, until it returns false or the directory ends
+    fn for_each_entry<D, F>(
+        &self,
+        device: &D,
+        mut cluster: u32,
+        mut on_entry: F,
+    ) -> Result<(), UndeleteError<D::Error>>
+    where
+        D: BlockDevice,
+        F: FnMut(u32, u16, &[u8]) -> bool,
+    {
+        let mut block = [Block::new()];
+        let mut fat_block = [Block::new()];
+        // A chain longer than the volume has clusters must loop
+        for _ in 0..self.clusters {
+            if cluster < 2 || cluster >= self.clusters + 2 {
+                return Err(UndeleteError::OutOfRange);
+            }
+            let first_lba = self.data_start + (cluster - 2) * self.sectors_per_cluster;
+            for lba in first_lba..first_lba + self.sectors_per_cluster {
+                device.read(&mut block, BlockIdx(lba))?;
+                for offset in (0..Block::LEN).step_by(ENTRY_LEN) {
+                    let entry = &block[0].contents[offset..offset + ENTRY_LEN];
+                    if entry[0] == 0 || !on_entry(lba, offset as u16, entry) {
+                        return Ok(());
+                    }
+                }
+            }
+            cluster = self.entry(device, &mut fat_block, cluster)?;
+            if cluster >= END_OF_CHAIN {
+                return Ok(());
+            }
+        }
+        Err(UndeleteError::OutOfRange)
+    }
+}
+
+/// The deleted file described by directory entry `bytes`, if it is one
+fn deleted_entry(lba: u32, offset: u16, bytes: &[u8]) -> Option<DeletedEntry> {
+    let attributes = bytes[11];
+    if bytes[0] != DELETED
+        || attributes & ATTR_LONG_NAME == ATTR_LONG_NAME
+        || attributes & ATTR_NOT_FILE != 0
+    {
+        return None;
+    }
+    let mut name = [0u8; 11];
+    name.copy_from_slice(&bytes[..11]);
+    name[0] = b'?';
+    Some(DeletedEntry {
+        name,
+        size: le32(bytes, 28),
+        first_cluster: first_cluster(bytes),
+        entry_block: lba,
+        entry_offset: offset,
+    })
+}
+
+/// First cluster of directory entry `bytes`
+fn first_cluster(bytes: &[u8]) -> u32 {
+    u32::from(le16(bytes, 20)) << 16 | u32::from(le16(bytes, 26))
+}
+
+/// `name` as a space-padded 8.3 directory entry name
+fn short_name(name: &str) -> Option<[u8; 11]> {
+    let parsed = ShortFileName::create_from_str(name).ok()?;
+    let (base, extension) = (parsed.base_name(), parsed.extension());
+    if base.is_empty() || base[0] == b'.' {
+        return None;
+    }
+    let mut padded = [b' '; 11];
+    padded[..base.len()].copy_from_slice(base);
+    padded[8..8 + extension.len()].copy_from_slice(extension);
+    Some(padded)
+}
+
+fn trim_spaces(bytes: &[u8]) -> &[u8] {
+    let len = bytes.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+    &bytes[..len]
+}
@@ -0,0 +1,32 @@
+//! Crate-wide console verbosity
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// How much the crate's helpers print to the console
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[repr(u8)]
+pub enum Verbosity {
+    /// Print nothing
+    Quiet = 0,
+    /// Print one line when an operation finally fails, including the attempt count and last error
+    #[default]
+    Summary = 1,
+    /// Also print every failed attempt
+    Verbose = 2,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Summary as u8);
+
+/// Set how chatty the crate is; applies to every helper
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+/// The current crate-wide verbosity
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Summary,
+        _ => Verbosity::Verbose,
+    }
+}
@@ -0,0 +1,346 @@
+//! Destroying and shortening files in place
+//!
+//! Deleting a file only marks its clusters free, so the data stays readable
+//! with any recovery tool. [`wipe_file`] overwrites every allocated byte
+//! first. This is the best achievable through the filesystem: the card's
+//! flash translation layer remaps writes, so old copies of the data may
+//! survive in blocks the card has retired or not yet erased. Only a card-level
+//! erase, or destroying the card, gets past that.
+//!
+//! Both operations leave a marker file while running so an interrupted run
+//! can be found and finished on the next boot.
+
+use embedded_sdmmc::{BlockDevice, Directory, Error, FilenameError, Mode, TimeSource};
+
+use crate::fs::replace_extension;
+use crate::{CancellableError, YieldBudget};
+
+/// Extension of the marker naming a file whose wipe hasn't finished
+pub const WIPE_EXTENSION: &str = "WIP";
+
+/// Extension of the copy [`truncate_file`] keeps of the shortened file
+pub const TRUNCATE_EXTENSION: &str = "TRN";
+
+/// What [`wipe_file`] overwrites the data with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WipePattern {
+    /// Every byte set to this value
+    Fixed(u8),
+    /// Pseudorandom bytes from this seed (0 is replaced by a fixed seed)
+    Random(u32),
+}
+
+/// Overwrite `name` with `pattern` up to the end of its last cluster, flush,
+/// then delete it. Returns the number of bytes overwritten.
+///
+/// `cluster_size` is the volume's cluster size in bytes (see
+/// [`crate::bytes_per_cluster`]); the slack after the logical end of the
+/// file is overwritten too. `on_progress(bytes_wiped, total_bytes)` is
+/// called after each chunk. A `.WIP` marker holding `name` is kept while
+/// the wipe runs; if one is left by an interruption, [`pending_wipe`] finds
+/// it and calling `wipe_file` again finishes the job. `budget` is spent on
+/// every chunk so other tasks keep running; if it is cancelled the wipe
+/// stops with [`CancellableError::Cancelled`] and the marker stays, so
+/// [`pending_wipe`] finds the file to wipe again. See the module docs for
+/// the limits of what this guarantees.
+pub async fn wipe_file<
+    D,
+    T,
+    P,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    name: &str,
+    pattern: WipePattern,
+    cluster_size: u32,
+    scratch: &mut [u8],
+    mut on_progress: P,
+    budget: &mut YieldBudget,
+) -> Result<u32, CancellableError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    P: FnMut(u32, u32),
+{
+    let mut marker_buf = [0u8; 12];
+    let marker = marker_name(name, WIPE_EXTENSION, &mut marker_buf)?;
+    let file = match dir.open_file_in_dir(name, Mode::ReadWriteAppend) {
+        Ok(file) => file,
+        // Interrupted between deleting the file and removing the marker
+        Err(Error::NotFound) => {
+            delete_if_present(dir, marker)?;
+            return Ok(0);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    write_marker(dir, marker, name)?;
+
+    let cluster_size = cluster_size.max(1);
+    let total = file
+        .length()
+        .div_ceil(cluster_size)
+        .saturating_mul(cluster_size);
+    let mut state = match pattern {
+        WipePattern::Fixed(_) => 0,
+        WipePattern::Random(seed) => seed.max(1),
+    };
+    file.seek_from_start(0)?;
+    let mut wiped = 0u32;
+    while wiped < total {
+        let len = scratch.len().min((total - wiped) as usize);
+        match pattern {
+            WipePattern::Fixed(byte) => scratch[..len].fill(byte),
+            WipePattern::Random(_) => scratch[..len].iter_mut().for_each(|byte| {
+                // xorshift32
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                *byte = state as u8;
+            }),
+        }
+        file.write(&scratch[..len])?;
+        wiped += len as u32;
+        on_progress(wiped, total);
+        budget.spend_bytes(len as u32).await;
+        if budget.is_cancelled() {
+            file.close()?;
+            return Err(CancellableError::Cancelled { bytes: wiped });
+        }
+    }
+    file.flush()?;
+    file.close()?;
+    dir.delete_file_in_dir(name)?;
+    delete_if_present(dir, marker)?;
+    Ok(wiped)
+}
+
+/// Name of a file whose wipe was interrupted, read from a `.WIP` marker in
+/// `dir` into `buffer`, or None if there is none. Pass it to [`wipe_file`].
+pub fn pending_wipe<
+    'a,
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    buffer: &'a mut [u8; 12],
+) -> Result<Option<&'a str>, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut marker = None;
+    dir.iterate_dir(|entry| {
+        if marker.is_none()
+            && !entry.attributes.is_directory()
+            && entry.name.extension() == WIPE_EXTENSION.as_bytes()
+        {
+            marker = Some(entry.name.clone());
+        }
+    })?;
+    let Some(marker) = marker else {
+        return Ok(None);
+    };
+    let file = dir.open_file_in_dir(&marker, Mode::ReadOnly)?;
+    let mut contents = [0u8; 13];
+    let mut len = 0;
+    while len < contents.len() {
+        let n = file.read(&mut contents[len..])?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    file.close()?;
+    let name_len = contents[..len]
+        .iter()
+        .position(|&b| b == b'\n')
+        .unwrap_or(len)
+        .min(buffer.len());
+    buffer[..name_len].copy_from_slice(&contents[..name_len]);
+    match core::str::from_utf8(&buffer[..name_len]) {
+        Ok(name) if !name.is_empty() => Ok(Some(name)),
+        // An unreadable marker can't be finished; drop it
+        _ => {
+            dir.delete_file_in_dir(&marker)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Shorten `name` to its first `new_len` bytes. Does nothing if it is
+/// already that short.
+///
+/// embedded-sdmmc can't shrink a file, so the head is copied to a `.TRN`
+/// file, the original deleted and the copy written back under the original
+/// name. `on_progress(bytes_copied, total_bytes)` covers both copies. If
+/// interrupted, calling `truncate_file` again with the same arguments
+/// finishes the job: a complete `.TRN` copy is always written back, and an
+/// incomplete one means the original hasn't been touched yet. Both copies
+/// spend `budget` as they go; if it is cancelled, truncating stops with
+/// [`CancellableError::Cancelled`], counting bytes as `on_progress` does,
+/// and calling it again finishes the job as after an interruption.
+pub async fn truncate_file<
+    D,
+    T,
+    P,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    name: &str,
+    new_len: u32,
+    scratch: &mut [u8],
+    mut on_progress: P,
+    budget: &mut YieldBudget,
+) -> Result<(), CancellableError<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    P: FnMut(u32, u32),
+{
+    let mut copy_buf = [0u8; 12];
+    let copy = marker_name(name, TRUNCATE_EXTENSION, &mut copy_buf)?;
+    let total = new_len.saturating_mul(2);
+
+    let copy_complete = match dir.open_file_in_dir(copy, Mode::ReadOnly) {
+        Ok(file) => {
+            let complete = file.length() == new_len;
+            file.close()?;
+            complete
+        }
+        Err(Error::NotFound) => false,
+        Err(e) => return Err(e.into()),
+    };
+    if !copy_complete {
+        let input = dir.open_file_in_dir(name, Mode::ReadOnly)?;
+        if input.length() <= new_len {
+            input.close()?;
+            return Ok(delete_if_present(dir, copy)?);
+        }
+        let output = dir.open_file_in_dir(copy, Mode::ReadWriteCreateOrTruncate)?;
+        let copied = copy_head(
+            &input,
+            &output,
+            new_len,
+            scratch,
+            |done| on_progress(done, total),
+            budget,
+        )
+        .await?;
+        output.close()?;
+        input.close()?;
+        // The original is untouched until the copy is complete
+        if budget.is_cancelled() {
+            return Err(CancellableError::Cancelled { bytes: copied });
+        }
+    }
+
+    delete_if_present(dir, name)?;
+    let input = dir.open_file_in_dir(copy, Mode::ReadOnly)?;
+    let output = dir.open_file_in_dir(name, Mode::ReadWriteCreate)?;
+    let copied = copy_head(
+        &input,
+        &output,
+        new_len,
+        scratch,
+        |done| on_progress(new_len + done, total),
+        budget,
+    )
+    .await?;
+    output.close()?;
+    input.close()?;
+    // The complete copy is kept, so the write back is redone next time
+    if budget.is_cancelled() {
+        return Err(CancellableError::Cancelled {
+            bytes: new_len + copied,
+        });
+    }
+    Ok(dir.delete_file_in_dir(copy)?)
+}
+
+/// Copy the first `len` bytes of `input` to `output`, stopping early if
+/// `budget` is cancelled. Returns the bytes copied.
+async fn copy_head<
+    D,
+    T,
+    P,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    input: &embedded_sdmmc::File<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    output: &embedded_sdmmc::File<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    len: u32,
+    scratch: &mut [u8],
+    mut on_progress: P,
+    budget: &mut YieldBudget,
+) -> Result<u32, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    P: FnMut(u32),
+{
+    let mut copied = 0u32;
+    while copied < len && !budget.is_cancelled() {
+        let want = scratch.len().min((len - copied) as usize);
+        let n = input.read(&mut scratch[..want])?;
+        if n == 0 {
+            break;
+        }
+        output.write(&scratch[..n])?;
+        copied += n as u32;
+        on_progress(copied);
+        budget.spend_bytes(n as u32).await;
+    }
+    Ok(copied)
+}
+
+fn marker_name<'a, E: core::fmt::Debug>(
+    name: &str,
+    extension: &str,
+    buffer: &'a mut [u8; 12],
+) -> Result<&'a str, Error<E>> {
+    replace_extension(name, extension, buffer)
+        .ok_or(Error::FilenameError(FilenameError::FilenameEmpty))
+}
+
+fn write_marker<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    marker: &str,
+    name: &str,
+) -> Result<(), Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let file = dir.open_file_in_dir(marker, Mode::ReadWriteCreateOrTruncate)?;
+    file.write(name.as_bytes())?;
+    file.write(b"\n")?;
+    file.close()
+}
+
+fn delete_if_present<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    dir: &Directory<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    name: &str,
+) -> Result<(), Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    match dir.delete_file_in_dir(name) {
+        Ok(()) | Err(Error::NotFound) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,1132 @@
+//! CSV row writer with optional timestamp columns
+
+use embassy_time::{Duration, Instant};
+use embedded_io::Write;
+use embedded_sdmmc::TimeSource;
+
+use crate::checksum::{write_hex, Crc32};
+use crate::csv::{
+    escape_continuation_byte, escape_csv_field, quote_as_first_field, CONTINUATION_PREFIX,
+};
+use crate::durable::DurableSeq;
+use crate::format_fixed_signed;
+use crate::sparse::{SparseLog, SparseRow};
+use crate::time::{format_iso8601, ISO8601_LEN};
+
+/// Timestamp columns prepended to every row by [`CsvWriter`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Timestamps {
+    /// No timestamp columns
+    #[default]
+    None,
+    /// `uptime_ms` from embassy's monotonic clock
+    Uptime,
+    /// `uptime_ms` followed by `wall_clock` ("YYYY-MM-DDTHH:MM:SS") from the TimeSource.
+    ///
+    /// Uptime never jumps, so rows can always be ordered exactly even if the
+    /// RTC is adjusted mid-session, while the wall clock gives them human meaning.
+    Hybrid,
+}
+
+/// Largest file FAT32 can hold: 4 GiB minus one byte
+pub const FAT32_MAX_FILE_SIZE: u64 = 0xFFFF_FFFF;
+
+/// Longest marker label [`CsvWriter::last_mark`] keeps
+pub const MARK_LABEL_MAX: usize = 32;
+
+/// Byte order mark written by [`CsvWriter::with_bom`]
+const UTF8_BOM: [u8; 3] = [0xEF, 0xBB, 0xBF];
+
+/// Default distance from [`FAT32_MAX_FILE_SIZE`] at which [`CsvWriter`]
+/// reports that the file is approaching the limit
+pub const DEFAULT_SIZE_LIMIT_MARGIN: u64 = 64 * 1024 * 1024;
+
+/// When [`CsvWriter`] treats writes as slow and asks for recovery
+///
+/// A tired card can take hundreds of milliseconds for a single write without
+/// returning an error, and sustained slowness usually precedes failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowWritePolicy {
+    /// A write or flush taking longer than this counts as slow
+    pub threshold: Duration,
+    /// Request recovery after this many slow writes within `window`
+    pub max_slow_writes: u32,
+    /// Time window over which slow writes are counted
+    pub window: Duration,
+}
+
+impl Default for SlowWritePolicy {
+    fn default() -> Self {
+        Self {
+            threshold: Duration::from_millis(250),
+            max_slow_writes: 3,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What a successful [`CsvWriter::flush`] guarantees is on the card
+///
+/// Offsets are logical byte positions in the file, counting any
+/// [`CsvWriter::with_starting_length`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushOutcome {
+    /// Every data byte before this offset is written to the card
+    pub data_durable_up_to: u64,
+    /// The directory entry and FAT reflect the file up to this offset, so a
+    /// reader after power loss sees at least this much
+    pub metadata_durable_up_to: u64,
+    /// How long the flush took
+    pub took: Duration,
+}
+
+/// Slow-write bookkeeping for [`CsvWriter`]
+#[derive(Debug, Clone, Copy, Default)]
+struct SlowWrites {
+    total: u32,
+    in_window: u32,
+    window_start: Option<Instant>,
+    recovery_requested: bool,
+}
+
+/// A single CSV field value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field<'a> {
+    /// Signed integer
+    Int(i64),
+    /// Unsigned integer
+    UInt(u64),
+    /// Text, quoted per RFC 4180 if it contains a comma, quote or newline
+    Str(&'a str),
+    /// Fixed-point number: `value` in units of 10^-`frac_digits` (at most 9),
+    /// so `value` 1250 with `frac_digits` 2 is written as 12.50
+    Fixed {
+        /// Scaled value
+        value: i32,
+        /// Digits after the decimal point
+        frac_digits: u8,
+    },
+}
+
+impl Field<'_> {
+    /// Call `f` with the field formatted as text, before any CSV quoting
+    pub(crate) fn with_bytes<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+        match *self {
+            Field::Int(value) => f(itoa::Buffer::new().format(value).as_bytes()),
+            Field::UInt(value) => f(itoa::Buffer::new().format(value).as_bytes()),
+            Field::Str(value) => f(value.as_bytes()),
+            Field::Fixed { value, frac_digits } => {
+                let mut text = [0u8; 24];
+                let len = format_fixed_signed(&mut text, value, 1, frac_digits);
+                // No '+' on positive numbers, unlike aligned columns
+                let start = usize::from(text[0] == b'+');
+                f(&text[start..len])
+            }
+        }
+    }
+}
+
+impl From<i32> for Field<'_> {
+    fn from(value: i32) -> Self {
+        Field::Int(value.into())
+    }
+}
+
+impl From<i64> for Field<'_> {
+    fn from(value: i64) -> Self {
+        Field::Int(value)
+    }
+}
+
+impl From<u32> for Field<'_> {
+    fn from(value: u32) -> Self {
+        Field::UInt(value.into())
+    }
+}
+
+impl From<u64> for Field<'_> {
+    fn from(value: u64) -> Self {
+        Field::UInt(value)
+    }
+}
+
+impl<'a> From<&'a str> for Field<'a> {
+    fn from(value: &'a str) -> Self {
+        Field::Str(value)
+    }
+}
+
+/// Errors returned by [`CsvWriter`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriterError<E> {
+    /// The underlying file failed
+    Io(E),
+    /// The formatted row does not fit in the writer's line buffer
+    RowTooLong,
+    /// The formatted line is longer than [`CsvWriter::with_max_line_len`]
+    /// allows. Nothing was written.
+    LineTooLong {
+        /// Bytes in the line, including the record separator
+        len: usize,
+        /// The configured maximum
+        max: usize,
+    },
+    /// Writing the row would take the file past [`FAT32_MAX_FILE_SIZE`].
+    /// Nothing was written; start a new file.
+    FileSizeLimit,
+    /// The rows of a [`Transaction`] don't fit in its staging buffer
+    TransactionFull,
+    /// A row of the [`Transaction`] failed, so nothing from it was written
+    TransactionRolledBack,
+}
+
+/// Linear conversion from a raw reading (e.g. an ADC count) to an
+/// engineering value, logged side by side by [`CsvWriter::write_row_scaled`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scale {
+    /// Quantity name, giving the `raw_<name>` column
+    pub name: &'static str,
+    /// Unit, giving the `<name>_<unit>` column, e.g. "mV"
+    pub unit: &'static str,
+    /// Multiplier applied to the raw value
+    pub numerator: i32,
+    /// Divisor applied after `numerator`; 0 is treated as 1
+    pub denominator: i32,
+    /// Added after scaling, in the same units as the result
+    pub offset: i32,
+    /// Decimal places of the result: `apply` returns units of 10^-`frac_digits`
+    pub frac_digits: u8,
+}
+
+impl Scale {
+    /// The engineering value of `raw` in units of 10^-`frac_digits`:
+    /// `raw * numerator / denominator + offset`, saturating at the i32 range
+    pub fn apply(&self, raw: i32) -> i32 {
+        let denominator = if self.denominator == 0 {
+            1
+        } else {
+            i64::from(self.denominator)
+        };
+        let value =
+            i64::from(raw) * i64::from(self.numerator) / denominator + i64::from(self.offset);
+        value.clamp(i64::from(i32::MIN), i64::from(i32::MAX)) as i32
+    }
+}
+
+/// Firmware identification written as a comment line by [`CsvWriter::write_header`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// Firmware version, e.g. `env!("CARGO_PKG_VERSION")`
+    pub firmware: &'static str,
+    /// Build identifier such as a git hash or build date
+    pub build: &'static str,
+}
+
+/// Writes CSV rows to a file (or any [`embedded_io::Write`]).
+///
+/// Each row is formatted into a `LINE`-byte stack buffer and written with a
+/// single call, so a row is never partially written because it was too long.
+///
+/// Only one writer can exist per file: embedded-sdmmc's `VolumeManager`
+/// refuses to open a file that is already open, returning
+/// `Error::FileAlreadyOpen`, so a second task can't get a handle to wrap.
+/// To read a file while it is being written, use [`crate::SnapshotReader`]
+/// on the writer's own handle. The check does not span two `VolumeManager`s
+/// built over the same card, which must never be done.
+pub struct CsvWriter<W, T, const LINE: usize = 128> {
+    out: W,
+    time_source: T,
+    timestamps: Timestamps,
+    quote_all: bool,
+    slow_write_policy: Option<SlowWritePolicy>,
+    slow_writes: SlowWrites,
+    build_info: Option<BuildInfo>,
+    boot_count: Option<u32>,
+    len: u64,
+    bytes_written: u64,
+    rows_written: u64,
+    session_bytes: u64,
+    session_rows: u64,
+    size_limit_margin: u64,
+    size_limit_warned: bool,
+    durable_seq: Option<&'static DurableSeq>,
+    written_seq: Option<u64>,
+    prepend_index: bool,
+    next_index: u64,
+    write_bom: bool,
+    record_separator: &'static [u8],
+    uptime_ms: fn() -> u64,
+    paused_since: Option<Instant>,
+    paused_total: Duration,
+    max_line_len: Option<usize>,
+    last_mark: [u8; MARK_LABEL_MAX],
+    last_mark_len: Option<usize>,
+    checkpoint_every: Option<u32>,
+    checkpoint_crc: Crc32,
+    checkpoint_start: u64,
+    checkpoint_rows: u32,
+    checkpoint_failed: bool,
+}
+
+impl<W: Write, T: TimeSource, const LINE: usize> CsvWriter<W, T, LINE> {
+    /// Create a writer with no timestamp columns
+    pub fn new(out: W, time_source: T) -> Self {
+        Self {
+            out,
+            time_source,
+            timestamps: Timestamps::None,
+            quote_all: false,
+            slow_write_policy: None,
+            slow_writes: SlowWrites::default(),
+            build_info: None,
+            boot_count: None,
+            len: 0,
+            bytes_written: 0,
+            rows_written: 0,
+            session_bytes: 0,
+            session_rows: 0,
+            size_limit_margin: DEFAULT_SIZE_LIMIT_MARGIN,
+            size_limit_warned: false,
+            durable_seq: None,
+            written_seq: None,
+            prepend_index: false,
+            next_index: 0,
+            write_bom: false,
+            record_separator: b"\n",
+            uptime_ms: || Instant::now().as_millis(),
+            paused_since: None,
+            paused_total: Duration::from_ticks(0),
+            max_line_len: None,
+            last_mark: [0; MARK_LABEL_MAX],
+            last_mark_len: None,
+            checkpoint_every: None,
+            checkpoint_crc: Crc32::new(),
+            checkpoint_start: 0,
+            checkpoint_rows: 0,
+            checkpoint_failed: false,
+        }
+    }
+
+    /// Choose which timestamp columns are prepended to every row
+    pub fn with_timestamps(mut self, timestamps: Timestamps) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+
+    /// Take `uptime_ms` column values from `uptime_ms` instead of embassy's
+    /// clock, e.g. a counter so test runs produce byte-identical files
+    pub fn with_uptime_source(mut self, uptime_ms: fn() -> u64) -> Self {
+        self.uptime_ms = uptime_ms;
+        self
+    }
+
+    /// Wrap every field in double quotes, even numbers, for strict parsers.
+    ///
+    /// By default only text fields that need it are quoted.
+    pub fn with_quote_all(mut self, quote_all: bool) -> Self {
+        self.quote_all = quote_all;
+        self
+    }
+
+    /// Start every row with a row number column named `index`, counting from 0
+    /// and restarting with each header, as pandas-style readers expect.
+    ///
+    /// It comes before any timestamp columns.
+    pub fn with_prepend_index(mut self, prepend_index: bool) -> Self {
+        self.prepend_index = prepend_index;
+        self
+    }
+
+    /// End every line with `separator` instead of `\n`, e.g. `b"\0"` or
+    /// `b"\x0c"` for consumers that expect NUL or form-feed delimited
+    /// records, or `b"\r\n"`. Fields are still escaped for CSV, so one that
+    /// contains a custom separator isn't protected.
+    pub fn with_record_separator(mut self, separator: &'static [u8]) -> Self {
+        self.record_separator = separator;
+        self
+    }
+
+    /// Start a new file with a UTF-8 byte order mark, so Excel reads
+    /// non-ASCII text correctly.
+    ///
+    /// Only written if nothing has been written yet; files being appended to
+    /// (see [`CsvWriter::with_starting_length`]) never get one mid-file.
+    pub fn with_bom(mut self, write_bom: bool) -> Self {
+        self.write_bom = write_bom;
+        self
+    }
+
+    /// Time every write and flush, counting those slower than the policy's
+    /// threshold and requesting recovery when too many happen within its window.
+    ///
+    /// Poll [`CsvWriter::take_recovery_request`] and re-initialize the card (or
+    /// lower the SPI speed) when it returns true.
+    pub fn with_slow_write_detection(mut self, policy: SlowWritePolicy) -> Self {
+        self.slow_write_policy = Some(policy);
+        self
+    }
+
+    /// Start new files with a `# fw=<firmware> build=<build>` comment line so
+    /// the data can be traced back to the code that produced it.
+    ///
+    /// Off by default, since not every CSV reader skips comment lines.
+    pub fn with_build_info(mut self, build_info: BuildInfo) -> Self {
+        self.build_info = Some(build_info);
+        self
+    }
+
+    /// Write a `# boot=N` comment line before the header row, so rows can be
+    /// matched to the power cycle that produced them; see
+    /// [`crate::increment_boot_count`]
+    pub fn with_boot_count(mut self, boot_count: u32) -> Self {
+        self.boot_count = Some(boot_count);
+        self
+    }
+
+    /// Post the sequence ID of the last row written with
+    /// [`CsvWriter::write_row_seq`] to `durable_seq` after each successful flush
+    pub fn with_durable_seq(mut self, durable_seq: &'static DurableSeq) -> Self {
+        self.durable_seq = Some(durable_seq);
+        self
+    }
+
+    /// Tell the writer how long the file already is, when appending to an
+    /// existing file, so the FAT32 size limit is tracked correctly
+    pub fn with_starting_length(mut self, len: u64) -> Self {
+        self.len = len;
+        self.checkpoint_start = len;
+        self
+    }
+
+    /// Carry session totals over from the writer for the previous file, e.g.
+    /// its [`CsvWriter::session_bytes_written`] and
+    /// [`CsvWriter::session_rows_written`] when rotating
+    pub fn with_session_totals(mut self, bytes: u64, rows: u64) -> Self {
+        self.session_bytes = bytes;
+        self.session_rows = rows;
+        self
+    }
+
+    /// Report [`CsvWriter::approaching_size_limit`] once the file is within
+    /// `margin` bytes of [`FAT32_MAX_FILE_SIZE`] (default [`DEFAULT_SIZE_LIMIT_MARGIN`])
+    pub fn with_size_limit_margin(mut self, margin: u64) -> Self {
+        self.size_limit_margin = margin;
+        self
+    }
+
+    /// Refuse any line (header, row or comment) longer than `max` bytes
+    /// including its record separator, so readers with a fixed line buffer
+    /// can rely on it. Unlike the `LINE` buffer size, this can be set at
+    /// runtime, e.g. from the reader's configuration.
+    pub fn with_max_line_len(mut self, max: usize) -> Self {
+        self.max_line_len = Some(max);
+        self
+    }
+
+    /// Write a checkpoint line after every `rows` rows (at least 1), so a
+    /// reader can trust a damaged file up to its last good checkpoint; see
+    /// [`CsvWriter::checkpoint`] for the format.
+    ///
+    /// The row that makes a checkpoint due is already in the file when the
+    /// checkpoint is written, so a failed checkpoint doesn't fail the row:
+    /// it stays due, is retried after the next row and on
+    /// [`CsvWriter::flush`], and [`CsvWriter::checkpoint_failed`] reports it.
+    pub fn with_checkpoints(mut self, rows: u32) -> Self {
+        self.checkpoint_every = Some(rows.max(1));
+        self
+    }
+
+    /// Bytes in the file, including any starting length
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// True if nothing has been written to the file
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes this writer has written to its file, as formatted, counting
+    /// comment and header lines but not any starting length
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Data rows this writer has written to its file
+    pub fn rows_written(&self) -> u64 {
+        self.rows_written
+    }
+
+    /// Like [`CsvWriter::bytes_written`], plus the totals carried over with
+    /// [`CsvWriter::with_session_totals`]
+    pub fn session_bytes_written(&self) -> u64 {
+        self.session_bytes
+    }
+
+    /// Like [`CsvWriter::rows_written`], plus the totals carried over with
+    /// [`CsvWriter::with_session_totals`]
+    pub fn session_rows_written(&self) -> u64 {
+        self.session_rows
+    }
+
+    /// True once the file is within the size limit margin of the FAT32
+    /// maximum; rotate to a new file before writes fail with
+    /// [`WriterError::FileSizeLimit`]
+    pub fn approaching_size_limit(&self) -> bool {
+        self.len.saturating_add(self.size_limit_margin) >= FAT32_MAX_FILE_SIZE
+    }
+
+    /// Total number of slow writes and flushes seen
+    pub fn slow_writes(&self) -> u32 {
+        self.slow_writes.total
+    }
+
+    /// True (once) if sustained slow writes mean the card should be recovered
+    pub fn take_recovery_request(&mut self) -> bool {
+        core::mem::take(&mut self.slow_writes.recovery_requested)
+    }
+
+    /// Pause or resume logging. While paused, rows passed to the `write_row`
+    /// methods are dropped; the file stays open and is flushed on pausing,
+    /// so resuming appends to it as before.
+    pub fn set_paused(&mut self, paused: bool) -> Result<(), W::Error> {
+        match (paused, self.paused_since) {
+            (true, None) => {
+                self.flush()?;
+                self.paused_since = Some(Instant::now());
+            }
+            (false, Some(since)) => {
+                self.paused_total += since.elapsed();
+                self.paused_since = None;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// True while [`CsvWriter::set_paused`] has paused logging
+    pub fn is_paused(&self) -> bool {
+        self.paused_since.is_some()
+    }
+
+    /// Total time spent paused, including a pause still in progress
+    pub fn paused_duration(&self) -> Duration {
+        let current = self
+            .paused_since
+            .map_or(Duration::from_ticks(0), |since| since.elapsed());
+        self.paused_total + current
+    }
+
+    /// Write the header row, including the names of the timestamp columns,
+    /// preceded by the build info comment if one was set
+    pub fn write_header(&mut self, columns: &[&str]) -> Result<(), WriterError<W::Error>> {
+        self.write_header_scaled(columns, &[])
+    }
+
+    /// Like [`CsvWriter::write_header`], followed by a `raw_<name>` and a
+    /// `<name>_<unit>` column for each of `scales`
+    pub fn write_header_scaled(
+        &mut self,
+        columns: &[&str],
+        scales: &[Scale],
+    ) -> Result<(), WriterError<W::Error>> {
+        if let Some(info) = self.build_info {
+            let mut comment = Line::<LINE>::new(false);
+            for part in [
+                b"# fw=",
+                info.firmware.as_bytes(),
+                b" build=",
+                info.build.as_bytes(),
+            ] {
+                comment.push(part)?;
+            }
+            self.write_line(&mut comment)?;
+        }
+        if let Some(boot_count) = self.boot_count {
+            let mut comment = Line::<LINE>::new(false);
+            let mut count_buf = itoa::Buffer::new();
+            comment.push(b"# boot=")?;
+            comment.push(count_buf.format(boot_count).as_bytes())?;
+            self.write_line(&mut comment)?;
+        }
+
+        let mut line = Line::<LINE>::new(self.quote_all);
+        if self.prepend_index {
+            line.push_field(b"index")?;
+        }
+        match self.timestamps {
+            Timestamps::None => {}
+            Timestamps::Uptime => line.push_field(b"uptime_ms")?,
+            Timestamps::Hybrid => {
+                line.push_field(b"uptime_ms")?;
+                line.push_field(b"wall_clock")?;
+            }
+        }
+        for column in columns {
+            line.push_field(column.as_bytes())?;
+        }
+        for scale in scales {
+            let mut name = Line::<32>::new(false);
+            name.push(b"raw_")?;
+            name.push(scale.name.as_bytes())?;
+            line.push_field(name.as_bytes())?;
+            let mut name = Line::<32>::new(false);
+            for part in [scale.name.as_bytes(), b"_", scale.unit.as_bytes()] {
+                name.push(part)?;
+            }
+            line.push_field(name.as_bytes())?;
+        }
+        self.write_line(&mut line)?;
+        self.next_index = 0;
+        Ok(())
+    }
+
+    /// Write one row of fields, preceded by any index and timestamp columns;
+    /// does nothing while paused
+    pub fn write_row(&mut self, fields: &[Field<'_>]) -> Result<(), WriterError<W::Error>> {
+        self.write_row_scaled(fields, &[], &[])
+    }
+
+    /// Like [`CsvWriter::write_row`], followed by each of `raw` and its value
+    /// converted by the matching entry of `scales`, to go with
+    /// [`CsvWriter::write_header_scaled`]. Extra entries in the longer of
+    /// `scales` and `raw` are ignored.
+    pub fn write_row_scaled(
+        &mut self,
+        fields: &[Field<'_>],
+        scales: &[Scale],
+        raw: &[i32],
+    ) -> Result<(), WriterError<W::Error>> {
+        if self.is_paused() {
+            return Ok(());
+        }
+        let mut line = self.format_row(self.next_index, fields, scales, raw)?;
+        self.write_line(&mut line)?;
+        self.rows_written += 1;
+        self.session_rows += 1;
+        if self.prepend_index {
+            self.next_index += 1;
+        }
+        self.count_checkpoint_rows(1);
+        Ok(())
+    }
+
+    /// Start a group of rows that reach the file together or not at all;
+    /// see [`Transaction`]. Rows are staged in `staging` until committed.
+    pub fn begin<'w, 'b>(&'w mut self, staging: &'b mut [u8]) -> Transaction<'w, 'b, W, T, LINE> {
+        Transaction {
+            writer: self,
+            staging,
+            len: 0,
+            rows: 0,
+            failed: false,
+        }
+    }
+
+    /// Format a row without writing it
+    fn format_row(
+        &self,
+        index: u64,
+        fields: &[Field<'_>],
+        scales: &[Scale],
+        raw: &[i32],
+    ) -> Result<Line<LINE>, WriterError<W::Error>> {
+        let mut line = Line::<LINE>::new(self.quote_all);
+        if self.prepend_index {
+            line.push_field(itoa::Buffer::new().format(index).as_bytes())?;
+        }
+        if self.timestamps != Timestamps::None {
+            let uptime_ms = (self.uptime_ms)();
+            line.push_field(itoa::Buffer::new().format(uptime_ms).as_bytes())?;
+        }
+        if self.timestamps == Timestamps::Hybrid {
+            let mut wall_clock = [0u8; ISO8601_LEN];
+            let len = format_iso8601(&mut wall_clock, &self.time_source.get_timestamp());
+            line.push_field(&wall_clock[..len])?;
+        }
+        for field in fields {
+            field.with_bytes(|bytes| line.push_field(bytes))?;
+        }
+        for (scale, &raw) in scales.iter().zip(raw) {
+            line.push_field(itoa::Buffer::new().format(raw).as_bytes())?;
+            let mut value = [0u8; 24];
+            let len = format_fixed_signed(&mut value, scale.apply(raw), 1, scale.frac_digits);
+            line.push_field(&value[..len])?;
+        }
+        Ok(line)
+    }
+
+    /// Write `values` as far as `sparse` decides: nothing if no value moved
+    /// past its threshold, the changed values with the rest left empty, or
+    /// a `# keyframe` comment and every value when a keyframe is due. Index
+    /// and timestamp columns are prepended as for [`CsvWriter::write_row`].
+    /// Returns what was written. If the write fails, the next row is a
+    /// keyframe, so readers filling empty fields forward never carry over a
+    /// value that didn't reach the file.
+    pub fn write_sparse_row<const COLS: usize>(
+        &mut self,
+        sparse: &mut SparseLog<COLS>,
+        values: &[i32; COLS],
+    ) -> Result<SparseRow, WriterError<W::Error>> {
+        if self.is_paused() {
+            return Ok(SparseRow::Skip);
+        }
+        let kind = sparse.classify(values, (self.uptime_ms)());
+        if kind == SparseRow::Skip {
+            return Ok(kind);
+        }
+        let mut fields = [Field::Str(""); COLS];
+        for (i, (field, &value)) in fields.iter_mut().zip(values).enumerate() {
+            if sparse.is_changed(i) {
+                *field = Field::Int(value.into());
+            }
+        }
+        let mut write = || {
+            if kind == SparseRow::Keyframe {
+                let mut marker = Line::<LINE>::new(false);
+                marker.push(b"# keyframe")?;
+                self.write_line(&mut marker)?;
+            }
+            self.write_row(&fields)
+        };
+        if let Err(e) = write() {
+            // `sparse` already counts these values as logged; a keyframe
+            // next time puts every column back on record
+            sparse.force_keyframe();
+            return Err(e);
+        }
+        Ok(kind)
+    }
+
+    /// Write an event marker, e.g. for a button press, as a comment line
+    /// that CSV readers skip and a search for `# mark` finds:
+    ///
+    /// ```text
+    /// # mark uptime_ms=15230 wall_clock=2025-03-01T12:00:15 label=trigger A
+    /// ```
+    ///
+    /// `wall_clock` is only present with [`Timestamps::Hybrid`]. Line breaks
+    /// in `label` become spaces. Markers are written even while paused.
+    pub fn mark(&mut self, label: &str) -> Result<(), WriterError<W::Error>> {
+        let mut line = Line::<LINE>::new(false);
+        line.push(b"# mark uptime_ms=")?;
+        line.push(itoa::Buffer::new().format((self.uptime_ms)()).as_bytes())?;
+        if self.timestamps == Timestamps::Hybrid {
+            let mut wall_clock = [0u8; ISO8601_LEN];
+            let len = format_iso8601(&mut wall_clock, &self.time_source.get_timestamp());
+            line.push(b" wall_clock=")?;
+            line.push(&wall_clock[..len])?;
+        }
+        line.push(b" label=")?;
+        for (i, part) in label.split(['\r', '\n']).enumerate() {
+            if i > 0 {
+                line.push(b" ")?;
+            }
+            line.push(part.as_bytes())?;
+        }
+        self.write_line(&mut line)?;
+
+        let len = label.len().min(MARK_LABEL_MAX);
+        self.last_mark[..len].copy_from_slice(&label.as_bytes()[..len]);
+        self.last_mark_len = Some(len);
+        Ok(())
+    }
+
+    /// Write `value`, a field too long for one line, as continuation lines
+    /// for the next row, which should carry [`crate::CONTINUED_FIELD`] in
+    /// its place; see [`crate::FieldJoiner`] for the format and reassembly.
+    ///
+    /// Pieces are sized to fill the `LINE` buffer (or the
+    /// [`CsvWriter::with_max_line_len`] limit). Nothing is written while
+    /// paused, matching the row that follows.
+    pub fn write_continued(&mut self, value: &[u8]) -> Result<(), WriterError<W::Error>> {
+        if self.is_paused() {
+            return Ok(());
+        }
+        // Room for the piece, leaving space for the separator
+        let limit = self.max_line_len.unwrap_or(LINE).min(LINE);
+        let separator_len = self.record_separator.len();
+        if limit < CONTINUATION_PREFIX.len() + 2 + separator_len {
+            return Err(WriterError::RowTooLong);
+        }
+        let limit = limit - separator_len;
+
+        let mut line = Line::<LINE>::new(false);
+        line.push(CONTINUATION_PREFIX)?;
+        for &byte in value {
+            let mut escaped = [0u8; 2];
+            let len = escape_continuation_byte(byte, &mut escaped);
+            if line.as_bytes().len() + len > limit {
+                self.write_line(&mut line)?;
+                line = Line::new(false);
+                line.push(CONTINUATION_PREFIX)?;
+            }
+            line.push(&escaped[..len])?;
+        }
+        self.write_line(&mut line)
+    }
+
+    /// Write a checkpoint line now, covering every byte written since the
+    /// previous one (or since the writer started), as a comment line that
+    /// CSV readers skip:
+    ///
+    /// ```text
+    /// # checkpoint start=1024 rows=100 crc32=1a2b3c4d
+    /// ```
+    ///
+    /// `start` is the file offset the covered bytes begin at, and they run
+    /// up to the first byte of the checkpoint line; `rows` is how many rows
+    /// they hold and `crc32` their CRC-32 (see [`crate::Crc32`]) in lowercase
+    /// hex. To recover a damaged file, check each checkpoint in turn by
+    /// computing the CRC of its range: everything before the last checkpoint
+    /// whose range matches, and whose predecessors all match, is intact.
+    /// Bytes after it are unverified.
+    ///
+    /// A writer appending to an existing file starts covering at
+    /// [`CsvWriter::with_starting_length`], so the earlier file's tail after
+    /// its last checkpoint stays unverified. Written automatically with
+    /// [`CsvWriter::with_checkpoints`]; call it before closing the file to
+    /// cover the last rows too.
+    pub fn checkpoint(&mut self) -> Result<(), WriterError<W::Error>> {
+        let mut crc = [0u8; 8];
+        write_hex(&mut crc, self.checkpoint_crc.finish());
+        let mut line = Line::<LINE>::new(false);
+        line.push(b"# checkpoint start=")?;
+        line.push(itoa::Buffer::new().format(self.checkpoint_start).as_bytes())?;
+        line.push(b" rows=")?;
+        line.push(itoa::Buffer::new().format(self.checkpoint_rows).as_bytes())?;
+        line.push(b" crc32=")?;
+        line.push(&crc)?;
+        self.write_line(&mut line)?;
+        self.checkpoint_crc = Crc32::new();
+        self.checkpoint_start = self.len;
+        self.checkpoint_rows = 0;
+        Ok(())
+    }
+
+    /// True if the last attempt at a due automatic checkpoint failed; see
+    /// [`CsvWriter::with_checkpoints`]
+    pub fn checkpoint_failed(&self) -> bool {
+        self.checkpoint_failed
+    }
+
+    /// Label of the last marker written, cut to [`MARK_LABEL_MAX`] bytes.
+    /// After rotating to a new file, pass it to [`CsvWriter::mark`] on the
+    /// new writer so the file starts with the context it was logged under.
+    pub fn last_mark(&self) -> Option<&str> {
+        let label = &self.last_mark[..self.last_mark_len?];
+        // A cut may have split a character; keep the valid part
+        match core::str::from_utf8(label) {
+            Ok(label) => Some(label),
+            Err(e) => core::str::from_utf8(&label[..e.valid_up_to()]).ok(),
+        }
+    }
+
+    /// Like [`CsvWriter::write_row`], tagging the row with sequence ID `seq`
+    /// for [`CsvWriter::with_durable_seq`]. IDs must increase from row to row.
+    /// Rows dropped while paused are not acknowledged.
+    pub fn write_row_seq(
+        &mut self,
+        seq: u64,
+        fields: &[Field<'_>],
+    ) -> Result<(), WriterError<W::Error>> {
+        if self.is_paused() {
+            return Ok(());
+        }
+        self.write_row(fields)?;
+        self.written_seq = Some(seq);
+        Ok(())
+    }
+
+    /// Flush buffered data so the file's directory entry is updated
+    ///
+    /// On success the highest sequence ID written so far is posted to the
+    /// [`DurableSeq`], if any; a failed flush posts nothing, so a retry that
+    /// succeeds later acknowledges the same rows then.
+    pub fn flush(&mut self) -> Result<FlushOutcome, W::Error> {
+        self.write_due_checkpoint();
+        let start = Instant::now();
+        let result = self.out.flush();
+        let took = start.elapsed();
+        self.check_slow_write(Some(start));
+        result?;
+        // Data durability is what matters to the producer; with a File it
+        // coincides with the directory entry update anyway
+        if let (Some(durable_seq), Some(seq)) = (self.durable_seq, self.written_seq) {
+            durable_seq.post(seq);
+        }
+        // A successful flush leaves both data and the directory entry current
+        Ok(FlushOutcome {
+            data_durable_up_to: self.len,
+            metadata_durable_up_to: self.len,
+            took,
+        })
+    }
+
+    /// Make everything written so far durable before anything written afterwards.
+    ///
+    /// Use between dependent sections, e.g. after the records and before the
+    /// index that points at them, so a crash can't leave the index pointing
+    /// past data that never reached the card. With an embedded-sdmmc `File`
+    /// this flushes and rewrites the directory entry (size and first cluster)
+    /// before returning.
+    pub fn barrier(&mut self) -> Result<FlushOutcome, W::Error> {
+        self.flush()
+    }
+
+    /// Borrow the underlying file
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+
+    /// Mutably borrow the underlying file
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    /// Give back the underlying file
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    /// Count `rows` towards the next automatic checkpoint, writing it if due
+    fn count_checkpoint_rows(&mut self, rows: u32) {
+        if self.checkpoint_every.is_some() {
+            self.checkpoint_rows = self.checkpoint_rows.saturating_add(rows);
+            self.write_due_checkpoint();
+        }
+    }
+
+    /// Write the automatic checkpoint if it is due, leaving it due if the
+    /// write fails; the rows it covers are already written, so the failure
+    /// is only recorded, not returned
+    fn write_due_checkpoint(&mut self) {
+        let Some(every) = self.checkpoint_every else {
+            return;
+        };
+        if self.checkpoint_rows < every {
+            return;
+        }
+        let failed = self.checkpoint().is_err();
+        if failed && !self.checkpoint_failed && crate::verbosity() != crate::Verbosity::Quiet {
+            println!("Checkpoint write failed - retrying after the next row");
+        }
+        self.checkpoint_failed = failed;
+    }
+
+    fn write_line(&mut self, line: &mut Line<LINE>) -> Result<(), WriterError<W::Error>> {
+        self.end_line(line)?;
+        self.write_bytes(line.as_bytes())
+    }
+
+    /// Add the record separator and check the line against the length limit
+    fn end_line(&self, line: &mut Line<LINE>) -> Result<(), WriterError<W::Error>> {
+        line.push(self.record_separator)?;
+        let len = line.as_bytes().len();
+        if let Some(max) = self.max_line_len.filter(|&max| len > max) {
+            return Err(WriterError::LineTooLong { len, max });
+        }
+        Ok(())
+    }
+
+    /// Write whole lines in one call, after a BOM if one is due
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), WriterError<W::Error>> {
+        let len = bytes.len();
+        let bom: &[u8] = if self.write_bom && self.len == 0 {
+            &UTF8_BOM
+        } else {
+            &[]
+        };
+        // Refuse the whole row rather than let the file end in a partial one
+        let new_len = self.len + (bom.len() + len) as u64;
+        if new_len > FAT32_MAX_FILE_SIZE {
+            return Err(WriterError::FileSizeLimit);
+        }
+
+        let start = self.slow_write_policy.map(|_| Instant::now());
+        let result = self
+            .out
+            .write_all(bom)
+            .and_then(|()| self.out.write_all(bytes))
+            .map_err(WriterError::Io);
+        self.check_slow_write(start);
+        result?;
+
+        let written = (bom.len() + len) as u64;
+        self.checkpoint_crc.update(bom);
+        self.checkpoint_crc.update(bytes);
+        self.len = new_len;
+        self.bytes_written += written;
+        self.session_bytes += written;
+        if !self.size_limit_warned && self.approaching_size_limit() {
+            self.size_limit_warned = true;
+            if crate::verbosity() != crate::Verbosity::Quiet {
+                println!(
+                    "Log file is {} bytes, approaching the FAT32 limit - rotate soon",
+                    self.len
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Record how long the operation begun at `start` took, if detection is enabled
+    fn check_slow_write(&mut self, start: Option<Instant>) {
+        let (Some(policy), Some(start)) = (self.slow_write_policy, start) else {
+            return;
+        };
+        let now = Instant::now();
+        let took = now - start;
+        if took <= policy.threshold {
+            return;
+        }
+
+        let slow = &mut self.slow_writes;
+        slow.total += 1;
+        match slow.window_start {
+            Some(window_start) if now - window_start <= policy.window => {}
+            _ => {
+                slow.window_start = Some(now);
+                slow.in_window = 0;
+            }
+        }
+        slow.in_window += 1;
+        if crate::verbosity() == crate::Verbosity::Verbose {
+            println!("Slow write: {} ms", took.as_millis());
+        }
+
+        if slow.in_window >= policy.max_slow_writes {
+            if crate::verbosity() != crate::Verbosity::Quiet {
+                println!(
+                    "{} slow writes within {} ms - card recovery requested",
+                    slow.in_window,
+                    policy.window.as_millis()
+                );
+            }
+            slow.recovery_requested = true;
+            slow.window_start = None;
+            slow.in_window = 0;
+        }
+    }
+}
+
+/// A group of rows that reach the file together or not at all, from
+/// [`CsvWriter::begin`].
+///
+/// Rows are formatted into the staging buffer as they are added, so a row
+/// that fails (too long, buffer full) leaves the file untouched and makes
+/// [`Transaction::commit`] return [`WriterError::TransactionRolledBack`].
+/// Commit writes the whole group in one call and flushes; dropping the
+/// transaction uncommitted discards it. Rows added while the writer is
+/// paused are dropped, as with [`CsvWriter::write_row`].
+///
+/// FAT gives no atomic append, so this is all-or-nothing only up to the
+/// commit itself: if the card fails or power is lost during the commit's
+/// write, part of the group may be on the card. The file then holds more
+/// than [`CsvWriter::len`], which still reports the length before the
+/// group; cut it back to that with [`crate::truncate_file`] after closing
+/// the file, or [`crate::trim_partial_row`] to keep only whole rows.
+pub struct Transaction<'w, 'b, W, T, const LINE: usize> {
+    writer: &'w mut CsvWriter<W, T, LINE>,
+    staging: &'b mut [u8],
+    len: usize,
+    rows: u64,
+    failed: bool,
+}
+
+impl<W: Write, T: TimeSource, const LINE: usize> Transaction<'_, '_, W, T, LINE> {
+    /// Add a row to the group; see [`CsvWriter::write_row`]. An error here
+    /// also fails the commit.
+    pub fn write_row(&mut self, fields: &[Field<'_>]) -> Result<(), WriterError<W::Error>> {
+        let result = self.stage(fields);
+        self.failed |= result.is_err();
+        result
+    }
+
+    /// Rows staged so far
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Write the staged rows in one call and flush
+    pub fn commit(self) -> Result<FlushOutcome, WriterError<W::Error>> {
+        if self.failed {
+            return Err(WriterError::TransactionRolledBack);
+        }
+        let writer = self.writer;
+        writer.write_bytes(&self.staging[..self.len])?;
+        writer.rows_written += self.rows;
+        writer.session_rows += self.rows;
+        if writer.prepend_index {
+            writer.next_index += self.rows;
+        }
+        // The group is in the file now; a failed checkpoint is retried, not
+        // reported as a failed commit
+        writer.count_checkpoint_rows(self.rows as u32);
+        writer.flush().map_err(WriterError::Io)
+    }
+
+    fn stage(&mut self, fields: &[Field<'_>]) -> Result<(), WriterError<W::Error>> {
+        if self.writer.is_paused() {
+            return Ok(());
+        }
+        let index = self.writer.next_index + self.rows;
+        let mut line = self.writer.format_row(index, fields, &[], &[])?;
+        self.writer.end_line(&mut line)?;
+        let bytes = line.as_bytes();
+        self.staging
+            .get_mut(self.len..self.len + bytes.len())
+            .ok_or(WriterError::TransactionFull)?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        self.rows += 1;
+        Ok(())
+    }
+}
+
+/// Fixed-capacity buffer a row is formatted into
+struct Line<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    fields: usize,
+    quote_all: bool,
+}
+
+impl<const N: usize> Line<N> {
+    fn new(quote_all: bool) -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            fields: 0,
+            quote_all,
+        }
+    }
+
+    fn push<E>(&mut self, bytes: &[u8]) -> Result<(), WriterError<E>> {
+        let end = self.len + bytes.len();
+        if end > N {
+            return Err(WriterError::RowTooLong);
+        }
+        self.buf[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    /// Push an escaped field, preceded by a comma unless it is the first one,
+    /// which is quoted if it starts with `#` so the line isn't a comment
+    fn push_field<E>(&mut self, bytes: &[u8]) -> Result<(), WriterError<E>> {
+        let quote = self.quote_all || (self.fields == 0 && quote_as_first_field(bytes));
+        if self.fields > 0 {
+            self.push(b",")?;
+        }
+        self.fields += 1;
+        let written = escape_csv_field(&mut self.buf[self.len..], bytes, quote)
+            .ok_or(WriterError::RowTooLong)?;
+        self.len += written;
+        Ok(())
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
@@ -0,0 +1,89 @@
+//! Cooperative yielding for long-running card operations
+//!
+//! Block I/O through embedded-sdmmc is synchronous, so a loop that copies or
+//! scans thousands of blocks never gives the embassy executor a chance to run
+//! other tasks, even inside an `async fn`. Long operations share a
+//! [`YieldBudget`] and yield after every N blocks processed; the yield
+//! points double as the places they check for cancellation. Copying
+//! ([`crate::copy_file`]), checksumming ([`crate::checksum_file`]), wiping
+//! and truncating ([`crate::wipe_file`], [`crate::truncate_file`]), the
+//! free space checks ([`crate::free_space`]), export, migration and the
+//! benchmarks all take one.
+//!
+//! The example binary measures the trade-off: it runs a 10 ms periodic task
+//! alongside 64 KiB of writes, once with the default budget and once never
+//! yielding, and prints the throughput and the task's worst lateness for
+//! each.
+
+use embedded_sdmmc::Block;
+
+use crate::CancelToken;
+
+/// Tracks blocks processed and yields to the executor once the budget is spent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct YieldBudget {
+    blocks_per_yield: u32,
+    bytes_since_yield: u32,
+    cancel: Option<CancelToken>,
+}
+
+impl YieldBudget {
+    /// Blocks processed between yields by default.
+    ///
+    /// At 2 MHz SPI a 512-byte block takes roughly 2-3 ms, so 8 blocks keeps
+    /// other tasks' latency around 20 ms. Each yield costs one trip through
+    /// the executor, which is negligible next to the block I/O itself.
+    pub const DEFAULT_BLOCKS_PER_YIELD: u32 = 8;
+
+    /// Yield after every `blocks_per_yield` blocks; 0 never yields.
+    ///
+    /// Latency-sensitive applications should lower this; throughput-bound
+    /// ones with no other tasks can raise it or disable yielding entirely.
+    pub const fn new(blocks_per_yield: u32) -> Self {
+        Self {
+            blocks_per_yield,
+            bytes_since_yield: 0,
+            cancel: None,
+        }
+    }
+
+    /// Stop operations using this budget once `cancel` is cancelled
+    pub const fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// True if the budget's [`CancelToken`] has been cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_some_and(|cancel| cancel.is_cancelled())
+    }
+
+    /// Blocks processed between yields
+    pub fn blocks_per_yield(&self) -> u32 {
+        self.blocks_per_yield
+    }
+
+    /// Record `blocks` blocks of work, yielding if the budget is spent
+    pub async fn spend_blocks(&mut self, blocks: u32) {
+        self.spend_bytes(blocks.saturating_mul(Block::LEN_U32))
+            .await
+    }
+
+    /// Record `bytes` bytes of work, yielding if the budget is spent
+    pub async fn spend_bytes(&mut self, bytes: u32) {
+        if self.blocks_per_yield == 0 {
+            return;
+        }
+        self.bytes_since_yield = self.bytes_since_yield.saturating_add(bytes);
+        if self.bytes_since_yield >= self.blocks_per_yield.saturating_mul(Block::LEN_U32) {
+            self.bytes_since_yield = 0;
+            embassy_futures::yield_now().await;
+        }
+    }
+}
+
+impl Default for YieldBudget {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_BLOCKS_PER_YIELD)
+    }
+}